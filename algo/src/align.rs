@@ -0,0 +1,392 @@
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+#[cfg(not(feature = "std"))]
+use num_traits::Float;
+
+use crate::{fingerprint::Fingerprinter, hash::PeakHash, index::Index, Sample};
+
+/// Length of the segments, whose offsets are estimated separately, in seconds.
+const SEGMENT_LENGTH: f64 = 10.0;
+/// Number of votes a segment needs, to take part in the alignment.
+const MIN_SEGMENT_SCORE: usize = 5;
+/// Largest change of the offset between two neighbouring segments, in wavelets.
+const SEGMENT_TOLERANCE: f64 = 2.0;
+/// Number of envelope values per step, when refining an alignment.
+const ENVELOPE_OVERSAMPLING: usize = 8;
+/// Range around the estimated offset, that is searched when refining an alignment, in wavelets.
+const REFINE_RANGE: usize = 2;
+/// Correlation of the envelopes a segment needs, to take part in the refinement.
+const MIN_CORRELATION: f64 = 0.5;
+
+/// The alignment of two recordings of the same event.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Alignment {
+   /// The time in the reference at which the other recording starts, in seconds.
+   /// It is negative, if the other recording starts before the reference.
+   pub offset: f64,
+   /// The relative difference of the clocks of the recordings, such that
+   /// `reference time = offset + (1 + drift) * other time`.
+   /// It is only meaningful for recordings much longer than a segment of ten seconds.
+   pub drift: f64,
+   /// The number of hashes, that agree with the alignment.
+   pub score: usize,
+}
+
+/// The aligner finds the time offset between two recordings of the same event.
+///
+/// # Algorithm
+/// Both recordings are fingerprinted, the reference is put into an index and every hash
+/// of the other recording votes for the offset between the two.
+/// The other recording is divided into segments of a few seconds and the offset of each segment
+/// is estimated separately from the centroid of the votes around its histogram peak,
+/// which is more precise than a single wavelet.
+/// Starting from the strongest segment, neighbouring segments are followed as long as their
+/// offsets change smoothly. A line fitted through them yields the offset and the drift.
+pub struct Aligner {
+   fingerprinter: Fingerprinter,
+}
+
+impl Aligner {
+   pub fn new(fingerprinter: Fingerprinter) -> Self {
+      Self { fingerprinter }
+   }
+
+   /// Returns the alignment of the other recording relative to the reference,
+   /// if they have enough in common.
+   ///
+   /// The alignment of the hashes is refined by cross correlating the onset envelopes
+   /// of the recordings, which resolves a fraction of a step.
+   pub fn align<T: Sample>(&self, reference: &[T], other: &[T]) -> Option<Alignment> {
+      let coarse = self.align_hashes(
+         &self.fingerprinter.fingerprint(reference),
+         &self.fingerprinter.fingerprint(other),
+      )?;
+      Some(self.refine(coarse, reference, other))
+   }
+
+   /// Returns the alignment of two recordings from their hashes.
+   pub fn align_hashes(&self, reference: &[PeakHash], other: &[PeakHash]) -> Option<Alignment> {
+      let mut index = Index::new();
+      index.insert(0, reference);
+
+      // Collect the votes of every segment as (other time, offset)
+      let wavelets_per_second = self.fingerprinter.wavelets_per_second();
+      let segment_length = usize::max(1, (SEGMENT_LENGTH * wavelets_per_second) as usize);
+      let mut segments = BTreeMap::<usize, Vec<(f64, i64)>>::new();
+      for hash in other {
+         for posting in index.lookup(hash.hash) {
+            segments
+               .entry(hash.time as usize / segment_length)
+               .or_default()
+               .push((hash.time as f64, posting.time as i64 - hash.time as i64));
+         }
+      }
+
+      let points = segments
+         .values()
+         .filter_map(|votes| Aligner::segment_offset(votes))
+         .collect::<Vec<_>>();
+      let inliers = Aligner::follow_segments(&points);
+      if inliers.is_empty() {
+         return None;
+      }
+
+      // Fit a line through the offsets of the segments, weighted by their scores
+      let (offset, drift) = fit_line(inliers.iter().map(|p| (p.time, p.offset, p.score as f64)));
+
+      Some(Alignment {
+         offset: offset / wavelets_per_second,
+         drift,
+         score: inliers.iter().map(|p| p.score).sum(),
+      })
+   }
+
+   fn refine<T: Sample>(&self, coarse: Alignment, reference: &[T], other: &[T]) -> Alignment {
+      let step_size = self.fingerprinter.step_size();
+      let hop = usize::max(1, step_size / ENVELOPE_OVERSAMPLING);
+      let hops_per_second = self.fingerprinter.sample_rate() as f64 / hop as f64;
+      let segment_length = (SEGMENT_LENGTH * hops_per_second) as usize;
+      let range = (REFINE_RANGE * step_size / hop) as i64;
+      let reference = envelope(reference, hop);
+      let other = envelope(other, hop);
+
+      // Find the lag of every segment, at which the envelopes correlate best
+      let mut points = Vec::new();
+      for start in (0..other.len()).step_by(usize::max(1, segment_length)) {
+         let segment = &other[start..usize::min(start + segment_length, other.len())];
+         if segment.len() < segment_length / 2 {
+            continue;
+         }
+
+         let centre = start as f64 + segment.len() as f64 / 2.0;
+         let predicted = (coarse.offset * hops_per_second + coarse.drift * centre).round() as i64;
+         let correlations = (predicted - range..=predicted + range)
+            .map(|lag| correlation(segment, &reference, start as i64 + lag))
+            .collect::<Vec<_>>();
+
+         let best = (1..correlations.len() - 1)
+            .filter(|i| correlations[*i].is_finite())
+            .max_by(|a, b| correlations[*a].total_cmp(&correlations[*b]));
+         if let Some(best) = best {
+            if correlations[best] < MIN_CORRELATION {
+               continue;
+            }
+
+            // Interpolate the peak with a parabola through its neighbours
+            let (left, peak, right) = (
+               correlations[best - 1],
+               correlations[best],
+               correlations[best + 1],
+            );
+            let curvature = left - 2.0 * peak + right;
+            let shift = if curvature < 0.0 {
+               0.5 * (left - right) / curvature
+            } else {
+               0.0
+            };
+            let lag = (predicted - range + best as i64) as f64 + shift;
+            points.push((centre, lag, peak));
+         }
+      }
+
+      if points.is_empty() {
+         return coarse;
+      }
+      let (offset, drift) = if points.len() > 1 {
+         fit_line(points.into_iter())
+      } else {
+         (points[0].1 - coarse.drift * points[0].0, coarse.drift)
+      };
+
+      Alignment {
+         offset: offset / hops_per_second,
+         drift,
+         score: coarse.score,
+      }
+   }
+
+   /// Estimates the offset of a segment from the votes around the peak of its histogram.
+   fn segment_offset(votes: &[(f64, i64)]) -> Option<SegmentOffset> {
+      let mut histogram = BTreeMap::<i64, usize>::new();
+      for (_, offset) in votes {
+         *histogram.entry(*offset).or_default() += 1;
+      }
+      let (peak, _) = histogram
+         .iter()
+         .max_by_key(|(offset, count)| (**count, -**offset))?;
+
+      let near_peak = votes
+         .iter()
+         .filter(|(_, offset)| (offset - peak).abs() <= 1)
+         .collect::<Vec<_>>();
+      if near_peak.len() < MIN_SEGMENT_SCORE {
+         return None;
+      }
+
+      let n = near_peak.len() as f64;
+      Some(SegmentOffset {
+         time: near_peak.iter().map(|(time, _)| time).sum::<f64>() / n,
+         offset: near_peak
+            .iter()
+            .map(|(_, offset)| *offset as f64)
+            .sum::<f64>()
+            / n,
+         score: near_peak.len(),
+      })
+   }
+
+   /// Returns the segments, that are connected to the strongest segment by smooth changes of the offset.
+   fn follow_segments(points: &[SegmentOffset]) -> Vec<&SegmentOffset> {
+      let strongest = match (0..points.len()).max_by_key(|i| points[*i].score) {
+         Some(strongest) => strongest,
+         None => return Vec::new(),
+      };
+
+      let mut inliers = vec![&points[strongest]];
+      for direction in [false, true].iter() {
+         let mut last = &points[strongest];
+         let neighbours: Vec<&SegmentOffset> = if *direction {
+            points[strongest + 1..].iter().collect()
+         } else {
+            points[..strongest].iter().rev().collect()
+         };
+
+         for point in neighbours {
+            if (point.offset - last.offset).abs() <= SEGMENT_TOLERANCE {
+               inliers.push(point);
+               last = point;
+            }
+         }
+      }
+      inliers
+   }
+}
+
+/// The offset of a segment at its mean time, both in wavelets.
+struct SegmentOffset {
+   time: f64,
+   offset: f64,
+   score: usize,
+}
+
+/// Fits a line through weighted points `(x, y, weight)` and returns its intercept and slope.
+/// The slope is zero, if the points don't spread along `x`.
+fn fit_line(points: impl Iterator<Item = (f64, f64, f64)> + Clone) -> (f64, f64) {
+   let weight = points.clone().map(|(_, _, w)| w).sum::<f64>();
+   let mean_x = points.clone().map(|(x, _, w)| w * x).sum::<f64>() / weight;
+   let mean_y = points.clone().map(|(_, y, w)| w * y).sum::<f64>() / weight;
+   let covariance = points
+      .clone()
+      .map(|(x, y, w)| w * (x - mean_x) * (y - mean_y))
+      .sum::<f64>();
+   let variance = points
+      .map(|(x, _, w)| w * (x - mean_x) * (x - mean_x))
+      .sum::<f64>();
+
+   let slope = if variance > 0.0 {
+      covariance / variance
+   } else {
+      0.0
+   };
+   (mean_y - slope * mean_x, slope)
+}
+
+/// Computes the onset envelope, i.e. the increase of the log energy from one hop to the next.
+fn envelope<T: Sample>(audio: &[T], hop: usize) -> Vec<f64> {
+   let energies = audio
+      .chunks_exact(hop)
+      .map(|chunk| {
+         let energy = chunk
+            .iter()
+            .map(|sample| sample.to_f64().unwrap_or(0.0).powi(2))
+            .sum::<f64>();
+         (energy / hop as f64 + 1e-10).ln()
+      })
+      .collect::<Vec<_>>();
+
+   energies
+      .windows(2)
+      .map(|pair| f64::max(pair[1] - pair[0], 0.0))
+      .collect()
+}
+
+/// Computes the normalized correlation of a segment with the reference, shifted by `start`.
+/// Only the overlapping part is compared.
+fn correlation(segment: &[f64], reference: &[f64], start: i64) -> f64 {
+   let first = (-start).max(0) as usize;
+   let last = i64::min(segment.len() as i64, reference.len() as i64 - start).max(0) as usize;
+   if last <= first + 1 {
+      return 0.0;
+   }
+
+   let a = &segment[first..last];
+   let b = &reference[(first as i64 + start) as usize..(last as i64 + start) as usize];
+   let n = a.len() as f64;
+   let (mean_a, mean_b) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+
+   let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+   for (x, y) in a.iter().zip(b.iter()) {
+      ab += (x - mean_a) * (y - mean_b);
+      aa += (x - mean_a) * (x - mean_a);
+      bb += (y - mean_b) * (y - mean_b);
+   }
+
+   if aa > 0.0 && bb > 0.0 {
+      ab / (aa * bb).sqrt()
+   } else {
+      0.0
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{config::FingerprintConfig, resample::Resampler, testing};
+
+   fn aligner() -> (Aligner, usize) {
+      let fingerprinter = Fingerprinter::with_config(FingerprintConfig::music()).unwrap();
+      let sample_rate = fingerprinter.sample_rate();
+      (Aligner::new(fingerprinter), sample_rate)
+   }
+
+   #[test]
+   fn finds_the_offset_of_an_excerpt() {
+      let (aligner, sample_rate) = aligner();
+      let reference = testing::melody(sample_rate, 30 * sample_rate, 1);
+      // Not a multiple of the step size, such that the refinement is needed
+      let start = 7 * sample_rate + 100;
+      let other = reference[start..start + 15 * sample_rate]
+         .iter()
+         .zip(testing::noise(15 * sample_rate, 2))
+         .map(|(x, noise)| x + 0.05 * noise)
+         .collect::<Vec<_>>();
+
+      let alignment = aligner.align(&reference, &other).unwrap();
+      // Within a fifth of a wavelet
+      let expected = start as f64 / sample_rate as f64;
+      assert!(
+         (alignment.offset - expected).abs() < 0.005,
+         "{} != {}",
+         alignment.offset,
+         expected
+      );
+      assert!(alignment.drift.abs() < 1e-3, "{}", alignment.drift);
+   }
+
+   #[test]
+   fn finds_the_offset_of_a_recording_starting_early() {
+      let (aligner, sample_rate) = aligner();
+      let audio = testing::melody(sample_rate, 30 * sample_rate, 1);
+      let (reference, other) = (&audio[5 * sample_rate..], &audio[..20 * sample_rate]);
+
+      let alignment = aligner.align(reference, other).unwrap();
+      assert!(
+         (alignment.offset + 5.0).abs() < 0.002,
+         "{}",
+         alignment.offset
+      );
+   }
+
+   #[test]
+   fn finds_the_drift_of_a_resampled_recording() {
+      let (aligner, sample_rate) = aligner();
+      let reference = testing::melody(sample_rate, 70 * sample_rate, 3);
+      // The clock of the other recording runs 0.05 % fast, so it takes more samples per second
+      let mut resampler = Resampler::new(2000, 2001).unwrap();
+      let mut other = resampler.process(&reference[5 * sample_rate..]);
+      other.extend(resampler.flush());
+
+      let alignment = aligner.align(&reference, &other).unwrap();
+      let expected = 2000.0 / 2001.0 - 1.0;
+      assert!(
+         (alignment.drift - expected).abs() < 1e-6,
+         "{} != {}",
+         alignment.drift,
+         expected
+      );
+      assert!(
+         (alignment.offset - 5.0).abs() < 0.005,
+         "{}",
+         alignment.offset
+      );
+   }
+
+   #[test]
+   fn aligns_short_recordings_without_panicking() {
+      // Recordings shorter than the peak picking window used to overflow in debug builds
+      let (aligner, sample_rate) = aligner();
+      let reference = testing::melody(sample_rate, 10 * sample_rate, 1);
+      for len in [0, 1, 256, 1024, sample_rate / 10] {
+         assert_eq!(aligner.align(&reference, &reference[..len]), None);
+         assert_eq!(aligner.align(&reference[..len], &reference), None);
+      }
+   }
+
+   #[test]
+   fn fits_weighted_lines() {
+      let points = [(0.0, 1.0, 1.0), (1.0, 3.0, 2.0), (2.0, 5.0, 1.0)];
+      assert_eq!(fit_line(points.iter().copied()), (1.0, 2.0));
+      assert_eq!(fit_line([(3.0, 4.0, 1.0)].iter().copied()), (4.0, 0.0));
+   }
+}
@@ -0,0 +1,226 @@
+use alloc::{vec, vec::Vec};
+
+use crate::Sample;
+
+/// The way multiple channels are stored in a single buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Layout {
+   /// Samples of the channels alternate, i.e. `L R L R ...`.
+   Interleaved,
+   /// The channels follow each other, i.e. `L L ... R R ...`.
+   Planar,
+}
+
+/// The way multiple channels are turned into the mono streams,
+/// that are fed into the frequencer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Downmix {
+   /// The average of all channels, i.e. `(L + R) / 2` for stereo.
+   Mid,
+   /// Only the first channel.
+   Left,
+   /// Only the second channel.
+   Right,
+   /// Half the difference of the first two channels, i.e. `(L - R) / 2`.
+   Side,
+   /// Every channel is kept and fingerprinted on its own.
+   PerChannel,
+}
+
+impl Downmix {
+   /// Returns the name of the downmix, as used on the command line and in queries.
+   pub fn name(&self) -> &'static str {
+      match self {
+         Downmix::Mid => "mid",
+         Downmix::Left => "left",
+         Downmix::Right => "right",
+         Downmix::Side => "side",
+         Downmix::PerChannel => "per-channel",
+      }
+   }
+
+   /// Returns the downmix with the given name, see [`Downmix::name`].
+   pub fn from_name(name: &str) -> Option<Self> {
+      [
+         Downmix::Mid,
+         Downmix::Left,
+         Downmix::Right,
+         Downmix::Side,
+         Downmix::PerChannel,
+      ]
+      .iter()
+      .copied()
+      .find(|downmix| downmix.name() == name)
+   }
+}
+
+/// The channel mixer turns multichannel audio into one or more mono streams.
+pub struct ChannelMixer {
+   channels: usize,
+   downmix: Downmix,
+}
+
+impl ChannelMixer {
+   pub fn new(channels: usize, downmix: Downmix) -> Result<Self, ()> {
+      if channels == 0 {
+         return Err(());
+      }
+
+      // Right and side need a second channel to work on
+      if channels < 2 && (downmix == Downmix::Right || downmix == Downmix::Side) {
+         return Err(());
+      }
+
+      Ok(Self { channels, downmix })
+   }
+
+   pub fn channels(&self) -> usize {
+      self.channels
+   }
+
+   pub fn downmix(&self) -> Downmix {
+      self.downmix
+   }
+
+   /// The number of mono streams this mixer outputs.
+   pub fn outputs(&self) -> usize {
+      match self.downmix {
+         Downmix::PerChannel => self.channels,
+         _ => 1,
+      }
+   }
+
+   /// Mixes audio, where every channel is given as a separate slice.
+   /// Fails, if the number of planes or their lengths don't match.
+   pub fn mix_planes<T: Sample>(&self, planes: &[&[T]]) -> Result<Vec<Vec<T>>, ()> {
+      if planes.len() != self.channels {
+         return Err(());
+      }
+      let frames = planes[0].len();
+      if planes.iter().any(|plane| plane.len() != frames) {
+         return Err(());
+      }
+
+      Ok(self.mix(frames, |channel, frame| planes[channel][frame]))
+   }
+
+   /// Mixes audio, where all channels are stored in a single buffer.
+   /// Fails, if the buffer doesn't hold a whole number of frames.
+   pub fn mix_buffer<T: Sample>(&self, samples: &[T], layout: Layout) -> Result<Vec<Vec<T>>, ()> {
+      if !samples.len().is_multiple_of(self.channels) {
+         return Err(());
+      }
+      let frames = samples.len() / self.channels;
+
+      Ok(match layout {
+         Layout::Interleaved => self.mix(frames, |channel, frame| {
+            samples[frame * self.channels + channel]
+         }),
+         Layout::Planar => self.mix(frames, |channel, frame| samples[channel * frames + frame]),
+      })
+   }
+
+   fn mix<T: Sample, F: Fn(usize, usize) -> T>(&self, frames: usize, sample: F) -> Vec<Vec<T>> {
+      let stream = |f: &dyn Fn(usize) -> T| (0..frames).map(f).collect::<Vec<_>>();
+
+      match self.downmix {
+         Downmix::Mid => {
+            let scale = T::cast(1.0 / self.channels as f64);
+            vec![stream(&|frame| {
+               (0..self.channels)
+                  .map(|channel| sample(channel, frame))
+                  .sum::<T>()
+                  * scale
+            })]
+         }
+         Downmix::Left => vec![stream(&|frame| sample(0, frame))],
+         Downmix::Right => vec![stream(&|frame| sample(1, frame))],
+         Downmix::Side => vec![stream(&|frame| {
+            T::cast(0.5) * (sample(0, frame) - sample(1, frame))
+         })],
+         Downmix::PerChannel => (0..self.channels)
+            .map(|channel| stream(&|frame| sample(channel, frame)))
+            .collect(),
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const LEFT: [f64; 3] = [1.0, 0.5, -1.0];
+   const RIGHT: [f64; 3] = [0.0, 0.5, 1.0];
+
+   fn mix(downmix: Downmix) -> Vec<Vec<f64>> {
+      ChannelMixer::new(2, downmix)
+         .unwrap()
+         .mix_planes(&[&LEFT, &RIGHT])
+         .unwrap()
+   }
+
+   #[test]
+   fn downmixes_stereo() {
+      assert_eq!(mix(Downmix::Mid), vec![vec![0.5, 0.5, 0.0]]);
+      assert_eq!(mix(Downmix::Left), vec![LEFT.to_vec()]);
+      assert_eq!(mix(Downmix::Right), vec![RIGHT.to_vec()]);
+      assert_eq!(mix(Downmix::Side), vec![vec![0.5, 0.0, -1.0]]);
+      assert_eq!(
+         mix(Downmix::PerChannel),
+         vec![LEFT.to_vec(), RIGHT.to_vec()]
+      );
+   }
+
+   #[test]
+   fn layouts_agree() {
+      let mixer = ChannelMixer::new(2, Downmix::PerChannel).unwrap();
+      let interleaved = [1.0, 0.0, 0.5, 0.5, -1.0, 1.0];
+      let planar = [1.0, 0.5, -1.0, 0.0, 0.5, 1.0];
+
+      let expected = mixer.mix_planes(&[&LEFT, &RIGHT]).unwrap();
+      assert_eq!(
+         mixer.mix_buffer(&interleaved, Layout::Interleaved).unwrap(),
+         expected
+      );
+      assert_eq!(mixer.mix_buffer(&planar, Layout::Planar).unwrap(), expected);
+   }
+
+   #[test]
+   fn rejects_partial_frames() {
+      let mixer = ChannelMixer::new(2, Downmix::Mid).unwrap();
+      assert!(mixer
+         .mix_buffer(&[1.0, 0.0, 0.5], Layout::Interleaved)
+         .is_err());
+      assert!(mixer.mix_buffer(&[1.0, 0.0, 0.5], Layout::Planar).is_err());
+      assert!(mixer.mix_planes(&[&LEFT[..], &RIGHT[..2]]).is_err());
+      assert!(mixer.mix_planes(&[&LEFT[..]]).is_err());
+   }
+
+   #[test]
+   fn finds_downmixes_by_name() {
+      for downmix in [
+         Downmix::Mid,
+         Downmix::Left,
+         Downmix::Right,
+         Downmix::Side,
+         Downmix::PerChannel,
+      ] {
+         assert_eq!(Downmix::from_name(downmix.name()), Some(downmix));
+      }
+      assert_eq!(Downmix::from_name("stereo"), None);
+   }
+
+   #[test]
+   fn rejects_missing_channels() {
+      assert!(ChannelMixer::new(0, Downmix::Mid).is_err());
+      assert!(ChannelMixer::new(1, Downmix::Side).is_err());
+      assert!(ChannelMixer::new(1, Downmix::Right).is_err());
+      assert_eq!(ChannelMixer::new(1, Downmix::Mid).unwrap().outputs(), 1);
+      assert_eq!(
+         ChannelMixer::new(4, Downmix::PerChannel).unwrap().outputs(),
+         4
+      );
+   }
+}
@@ -0,0 +1,308 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::mem::size_of;
+
+use crate::{
+   hash::PeakHash,
+   index::{self, Lookup, Match, Posting, ScaledMatch, SegmentMatch},
+};
+
+/// An index, that stores its posting lists compressed, such that large catalogues fit into memory.
+/// It is queried like an [`Index`](crate::index::Index) and finds the same matches.
+///
+/// # Encoding
+/// The postings of every hash are stored as a list of bytes, grouped by track.
+/// Every group starts with the difference of its track to the track of the previous group
+/// and the number of its postings, followed by the difference of the time of every posting
+/// to the previous one and its span. All numbers are variable length integers with 7 bits per byte.
+/// Since the postings of a group are sorted by time and tracks are usually inserted in ascending
+/// order, the differences are small and a posting takes about five bytes instead of twelve.
+#[derive(Debug, Clone, Default)]
+pub struct CompactIndex {
+   lists: BTreeMap<u32, PostingList>,
+   /// The number of wavelets covered by the hashes of every track.
+   lengths: BTreeMap<u32, u32>,
+   num_postings: usize,
+}
+
+/// The encoded postings of a hash.
+#[derive(Debug, Clone, Default)]
+struct PostingList {
+   data: Vec<u8>,
+   /// The track of the last group, that the next group is encoded relative to.
+   last_track: u32,
+}
+
+impl CompactIndex {
+   pub fn new() -> Self {
+      Self::default()
+   }
+
+   /// Adds the hashes of a track to the index.
+   pub fn insert(&mut self, track: u32, hashes: &[PeakHash]) {
+      let length = self.lengths.entry(track).or_default();
+      for hash in hashes {
+         *length = u32::max(*length, hash.time + hash.span + 1);
+      }
+
+      let mut groups = BTreeMap::<u32, Vec<(u32, u32)>>::new();
+      for hash in hashes {
+         groups
+            .entry(hash.hash)
+            .or_default()
+            .push((hash.time, hash.span));
+      }
+
+      for (hash, mut group) in groups {
+         group.sort_unstable();
+         let list = self.lists.entry(hash).or_default();
+         write_varint(
+            &mut list.data,
+            zigzag(track as i64 - list.last_track as i64),
+         );
+         write_varint(&mut list.data, group.len() as u64);
+
+         let mut last_time = 0;
+         for (time, span) in group.iter() {
+            write_varint(&mut list.data, (time - last_time) as u64);
+            write_varint(&mut list.data, *span as u64);
+            last_time = *time;
+         }
+
+         list.last_track = track;
+         self.num_postings += group.len();
+      }
+   }
+
+   /// Releases the memory reserved for further postings, after all tracks have been inserted.
+   pub fn shrink_to_fit(&mut self) {
+      for list in self.lists.values_mut() {
+         list.data.shrink_to_fit();
+      }
+   }
+
+   /// Returns all occurrences of a hash.
+   pub fn lookup(&self, hash: u32) -> Postings<'_> {
+      Postings {
+         data: self
+            .lists
+            .get(&hash)
+            .map(|list| &list.data[..])
+            .unwrap_or(&[]),
+         track: 0,
+         remaining: 0,
+         time: 0,
+      }
+   }
+
+   /// Returns the number of distinct hashes in the index.
+   pub fn num_hashes(&self) -> usize {
+      self.lists.len()
+   }
+
+   /// Returns the number of postings in the index.
+   pub fn num_postings(&self) -> usize {
+      self.num_postings
+   }
+
+   /// Returns the number of wavelets covered by the hashes of a track.
+   pub fn track_length(&self, track: u32) -> Option<u32> {
+      self.lengths.get(&track).copied()
+   }
+
+   /// Returns the approximate number of bytes used by the posting lists and the track lengths,
+   /// not counting the nodes of the maps.
+   pub fn memory_usage(&self) -> usize {
+      let lists = self
+         .lists
+         .values()
+         .map(|list| size_of::<u32>() + size_of::<PostingList>() + list.data.capacity())
+         .sum::<usize>();
+      lists + self.lengths.len() * 2 * size_of::<u32>()
+   }
+
+   /// Returns the track that matches the query best, if any.
+   pub fn query(&self, hashes: &[PeakHash]) -> Option<Match> {
+      self.candidates(hashes, 1).pop()
+   }
+
+   /// Returns up to `limit` tracks with the highest scores, best first,
+   /// see [`Index::candidates`](crate::index::Index::candidates).
+   pub fn candidates(&self, hashes: &[PeakHash], limit: usize) -> Vec<Match> {
+      index::candidates(hashes, |hash| self.lookup(hash), limit)
+   }
+
+   /// Returns every part of the query, that matches a track at `min_score` or more times,
+   /// see [`Index::query_all`](crate::index::Index::query_all).
+   pub fn query_all(&self, hashes: &[PeakHash], min_score: usize) -> Vec<SegmentMatch> {
+      index::query_all(hashes, |hash| self.lookup(hash), min_score)
+   }
+
+   /// Returns up to `limit` tracks, that may be played at a different speed,
+   /// see [`Index::query_scaled`](crate::index::Index::query_scaled).
+   pub fn query_scaled(&self, hashes: &[PeakHash], limit: usize) -> Vec<ScaledMatch> {
+      index::query_scaled(hashes, |hash| self.lookup(hash), limit)
+   }
+}
+
+impl Lookup for CompactIndex {
+   type Postings<'a> = Postings<'a>;
+
+   fn postings(&self, hash: u32) -> Postings<'_> {
+      self.lookup(hash)
+   }
+
+   fn track_length(&self, track: u32) -> Option<u32> {
+      CompactIndex::track_length(self, track)
+   }
+}
+
+/// An iterator decoding the postings of a hash.
+#[derive(Debug, Clone)]
+pub struct Postings<'a> {
+   data: &'a [u8],
+   track: u32,
+   /// The number of postings left in the current group.
+   remaining: u64,
+   time: u32,
+}
+
+impl<'a> Iterator for Postings<'a> {
+   type Item = Posting;
+
+   fn next(&mut self) -> Option<Posting> {
+      if self.remaining == 0 {
+         if self.data.is_empty() {
+            return None;
+         }
+         let delta = unzigzag(read_varint(&mut self.data));
+         self.track = (self.track as i64 + delta) as u32;
+         self.remaining = read_varint(&mut self.data);
+         self.time = 0;
+      }
+
+      self.time += read_varint(&mut self.data) as u32;
+      let span = read_varint(&mut self.data) as u32;
+      self.remaining -= 1;
+
+      Some(Posting {
+         track: self.track,
+         time: self.time,
+         span,
+      })
+   }
+}
+
+/// Maps signed integers to unsigned ones, such that small magnitudes stay small.
+fn zigzag(value: i64) -> u64 {
+   ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+   (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Appends a variable length integer, 7 bits per byte, least significant first.
+/// The highest bit of a byte is set, if more bytes follow.
+pub(crate) fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+   while value >= 0x80 {
+      data.push(value as u8 | 0x80);
+      value >>= 7;
+   }
+   data.push(value as u8);
+}
+
+/// Reads a variable length integer from the front of the data and advances past it.
+fn read_varint(data: &mut &[u8]) -> u64 {
+   let mut value = 0;
+   let mut shift = 0;
+   while let Some((byte, rest)) = data.split_first() {
+      *data = rest;
+      value |= ((byte & 0x7f) as u64) << shift;
+      if byte & 0x80 == 0 {
+         break;
+      }
+      shift += 7;
+   }
+   value
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{config::FingerprintConfig, fingerprint::Fingerprinter, index::Index, testing};
+
+   const SAMPLE_RATE: usize = 11025;
+
+   #[test]
+   fn round_trips_varints() {
+      let values = [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, u64::MAX];
+      let mut data = Vec::new();
+      for value in values.iter() {
+         write_varint(&mut data, *value);
+      }
+      assert_eq!(&data[..4], &[0x00, 0x01, 0x7f, 0x80]);
+
+      let mut rest = &data[..];
+      for value in values.iter() {
+         assert_eq!(read_varint(&mut rest), *value);
+      }
+      assert!(rest.is_empty());
+      assert_eq!(read_varint(&mut rest), 0);
+   }
+
+   #[test]
+   fn round_trips_zigzag() {
+      for (value, encoded) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (2, 4)].iter() {
+         assert_eq!(zigzag(*value), *encoded);
+      }
+      for value in [i64::MIN, -1000, -1, 0, 1, 1000, i64::MAX].iter() {
+         assert_eq!(unzigzag(zigzag(*value)), *value);
+      }
+   }
+
+   #[test]
+   fn finds_the_same_matches_as_the_index_in_less_memory() {
+      let fingerprinter = Fingerprinter::with_config(FingerprintConfig::music()).unwrap();
+      let tracks = (1..=4)
+         .map(|seed| {
+            fingerprinter.fingerprint(&testing::melody(SAMPLE_RATE, 30 * SAMPLE_RATE, seed))
+         })
+         .collect::<Vec<_>>();
+
+      let mut index = Index::new();
+      let mut compact = CompactIndex::new();
+      // Out of order, such that tracks are also encoded relative to later ones
+      for track in [2, 0, 3, 1].iter() {
+         index.insert(*track, &tracks[*track as usize]);
+         compact.insert(*track, &tracks[*track as usize]);
+      }
+      compact.shrink_to_fit();
+
+      assert_eq!(compact.num_hashes(), index.num_hashes());
+      assert_eq!(
+         compact.num_postings(),
+         tracks.iter().map(Vec::len).sum::<usize>()
+      );
+      for hash in tracks[0].iter().chain(tracks[3].iter()) {
+         let mut postings = compact.lookup(hash.hash).collect::<Vec<_>>();
+         let mut expected = index.lookup(hash.hash).to_vec();
+         postings.sort_unstable_by_key(|p| (p.track, p.time, p.span));
+         expected.sort_unstable_by_key(|p| (p.track, p.time, p.span));
+         assert_eq!(postings, expected);
+      }
+      for track in 0..4 {
+         assert_eq!(compact.track_length(track), index.track_length(track));
+      }
+
+      let clip = &tracks[1][100..400];
+      assert_eq!(compact.candidates(clip, 3), index.candidates(clip, 3));
+      assert_eq!(compact.query_all(clip, 20), index.query_all(clip, 20));
+
+      assert!(
+         2 * compact.memory_usage() < index.memory_usage(),
+         "{} {}",
+         compact.memory_usage(),
+         index.memory_usage()
+      );
+   }
+}
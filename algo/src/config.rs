@@ -0,0 +1,141 @@
+#[cfg(not(feature = "std"))]
+use num_traits::Float;
+
+use crate::{fingerprint::ALGORITHM_ID, hash::PeakHasher, metadata::checksum};
+
+/// All parameters of the fingerprinting, from the frequencer over the peak picking to the hashing.
+/// Hashes are only comparable, if they were computed with the same configuration.
+///
+/// Sizes are given in samples at the sample rate, times in wavelets, i.e. steps.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+   feature = "serde",
+   derive(serde::Serialize, serde::Deserialize),
+   serde(default)
+)]
+pub struct FingerprintConfig {
+   /// The sample rate, that the audio is resampled to.
+   pub sample_rate: usize,
+   /// The number of samples per FFT frame, a power of two.
+   pub block_size: usize,
+   /// The number of samples between two frames, which is the length of a wavelet.
+   pub step_size: usize,
+   /// The number of wavelets before and after a peak, that it has to exceed.
+   pub t_span: usize,
+   /// The number of features each anchor is paired with.
+   pub fan_out: usize,
+   /// The shortest time difference of a pair of features.
+   pub min_dt: usize,
+   /// The longest time difference of a pair of features. Hashes are complete this many
+   /// wavelets after their anchor, so it bounds the latency of live matching.
+   pub max_dt: usize,
+}
+
+impl FingerprintConfig {
+   /// The default for recorded music, about 23 ms per wavelet and pairs of peaks
+   /// up to 1.4 s apart, which is robust against noise and short dropouts.
+   pub fn music() -> Self {
+      Self {
+         sample_rate: 11025,
+         block_size: 1024,
+         step_size: 256,
+         t_span: 10,
+         fan_out: 5,
+         min_dt: 1,
+         max_dt: 60,
+      }
+   }
+
+   /// For speech and telephone audio, which has no content above 4 kHz
+   /// and changes faster than music.
+   pub fn speech() -> Self {
+      Self {
+         sample_rate: 8000,
+         block_size: 512,
+         step_size: 128,
+         t_span: 6,
+         fan_out: 5,
+         min_dt: 1,
+         max_dt: 40,
+      }
+   }
+
+   /// For live matching, where hashes are complete after a quarter of a second
+   /// instead of one and a half, at the cost of fewer and less distinctive hashes.
+   pub fn low_latency() -> Self {
+      Self {
+         sample_rate: 11025,
+         block_size: 512,
+         step_size: 128,
+         t_span: 5,
+         fan_out: 3,
+         min_dt: 1,
+         max_dt: 20,
+      }
+   }
+
+   /// Checks, that the frequencer, the feature finder and the peak hasher accept the parameters.
+   pub fn validate(&self) -> Result<(), ()> {
+      if self.sample_rate == 0
+         || !self.block_size.is_power_of_two()
+         || self.step_size == 0
+         || self.step_size >= self.block_size
+         || self.t_span == 0
+      {
+         return Err(());
+      }
+      PeakHasher::new(self.fan_out, self.min_dt, self.max_dt)?;
+      Ok(())
+   }
+
+   /// Returns an identifier of the configuration and the hashing algorithm. It never changes
+   /// for the same parameters, such that it can be stored and compared across versions.
+   pub fn id(&self) -> u64 {
+      let mut data = [0; 1 + 7 * 4];
+      data[0] = ALGORITHM_ID;
+      for (i, value) in [
+         self.sample_rate,
+         self.block_size,
+         self.step_size,
+         self.t_span,
+         self.fan_out,
+         self.min_dt,
+         self.max_dt,
+      ]
+      .iter()
+      .enumerate()
+      {
+         data[1 + 4 * i..5 + 4 * i].copy_from_slice(&(*value as u32).to_le_bytes());
+      }
+      checksum(&data)
+   }
+
+   /// Returns the configuration for audio at another sample rate, like a live recording that
+   /// isn't resampled. The block and step size are scaled by the power of two closest to the
+   /// ratio of the sample rates, such that frames and wavelets cover about the same time.
+   pub fn scaled_to(&self, sample_rate: usize) -> Self {
+      let shift = (sample_rate as f64 / self.sample_rate as f64)
+         .log2()
+         .round() as i32;
+      let scale = |size: usize| {
+         if shift >= 0 {
+            size << shift
+         } else {
+            usize::max(size >> -shift, 1)
+         }
+      };
+
+      Self {
+         sample_rate,
+         block_size: scale(self.block_size),
+         step_size: scale(self.step_size),
+         ..self.clone()
+      }
+   }
+}
+
+impl Default for FingerprintConfig {
+   fn default() -> Self {
+      Self::music()
+   }
+}
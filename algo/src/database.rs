@@ -0,0 +1,1208 @@
+use std::{
+   collections::{BTreeMap, BTreeSet, HashMap},
+   convert::TryFrom,
+   fs::{self, File},
+   io::{self, Write},
+   path::{Path, PathBuf},
+};
+
+use crate::{
+   compact::{write_varint, CompactIndex},
+   config::FingerprintConfig,
+   fingerprint::Fingerprinter,
+   hash::PeakHash,
+   index::Index,
+   metadata::TrackMetadata,
+   sharded::ShardedIndex,
+   subfingerprint::SubFingerprintIndex,
+   Algorithm,
+};
+
+/// Name of the file listing the segments and the deleted tracks of a database.
+const MANIFEST: &str = "MANIFEST";
+/// First line of the manifest, followed by the version of the format.
+const MANIFEST_HEADER: &str = "fingerprint-database";
+/// Magic bytes at the start of a segment file.
+const SEGMENT_MAGIC: &[u8; 4] = b"FPSG";
+const MANIFEST_VERSION: u32 = 1;
+/// Version of the segment format.
+const SEGMENT_VERSION: u32 = 1;
+/// Extension of segment files.
+const SEGMENT_EXTENSION: &str = "seg";
+/// Extension of files, that are written and not yet renamed to their final name.
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+/// A track stored in a database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredTrack {
+   pub id: u32,
+   /// The identifier of the track given when adding it, like a path or an ISRC.
+   pub key: String,
+   pub hashes: Vec<PeakHash>,
+   pub metadata: TrackMetadata,
+}
+
+/// A match of a query together with the key and the metadata of its track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedMatch<'a, M> {
+   pub key: &'a str,
+   pub metadata: &'a TrackMetadata,
+   /// The match as returned by the index.
+   pub found: M,
+}
+
+/// A fingerprint database on disk, that is updated incrementally.
+///
+/// # Layout
+/// The database is a directory of immutable segment files and a manifest.
+/// Adding tracks writes them into a new segment, without touching the existing ones.
+/// Deleting a track only records a tombstone in the manifest. Compaction merges all segments
+/// into one and drops the deleted tracks.
+///
+/// Every file is written under a temporary name, synced and then renamed, and a change
+/// only takes effect, once the manifest referencing it has been replaced this way.
+/// A crash therefore leaves either the old or the new state, plus maybe some unreferenced
+/// files, which are removed by the next compaction.
+///
+/// The manifest is a text file with one entry per line:
+/// ```text
+/// fingerprint-database 1
+/// parameters <sample rate> <block size> <step size> <t span>
+/// hashing <fan out> <min dt> <max dt>
+/// algorithm <name>
+/// next_track <id>
+/// next_segment <number>
+/// segment <file name>
+/// deleted <id>
+/// ```
+/// Without an `algorithm` line, the constellation algorithm is used.
+/// Databases of the [`SubFingerprint`](Algorithm::SubFingerprint) algorithm store every
+/// sub-fingerprint of a track as a hash, whose time is its position and whose span is zero.
+///
+/// A segment file starts with the magic bytes `FPSG`, the version and the parameters,
+/// including the hashing parameters, as 32 bit little endian integers, and the name of the algorithm,
+/// followed by the number of tracks and every track with its id,
+/// key, hashes, sorted by time, and metadata. The times are stored as differences to the previous hash.
+/// It ends with a checksum of all preceding bytes. The metadata of all tracks is also kept in memory,
+/// so matches can be resolved without reading the segments.
+#[derive(Debug)]
+pub struct Database {
+   path: PathBuf,
+   manifest: Manifest,
+   /// The keys of the tracks, that are not deleted.
+   keys: BTreeMap<u32, String>,
+   /// The ids of the tracks, that are not deleted, by their keys.
+   ids: HashMap<String, u32>,
+   metadata: BTreeMap<u32, TrackMetadata>,
+}
+
+/// A read-only database, that is held in memory, like the copy of a catalogue in a web app.
+/// It is loaded from the single segment written by [`Database::export`].
+/// The index is compact, since the memory of a browser tab is scarce.
+#[derive(Debug)]
+pub struct DatabaseSnapshot {
+   fingerprinter: Fingerprinter,
+   algorithm: Algorithm,
+   keys: BTreeMap<u32, String>,
+   metadata: BTreeMap<u32, TrackMetadata>,
+   index: CompactIndex,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Manifest {
+   fingerprinter: Fingerprinter,
+   algorithm: Algorithm,
+   next_track: u32,
+   next_segment: u64,
+   segments: Vec<String>,
+   deleted: BTreeSet<u32>,
+}
+
+impl Database {
+   /// Creates an empty database in a new directory, storing the parameters of the fingerprinter.
+   pub fn create<P: AsRef<Path>>(path: P, fingerprinter: &Fingerprinter) -> io::Result<Self> {
+      Self::create_with_algorithm(path, fingerprinter, Algorithm::Constellation)
+   }
+
+   /// Creates an empty database for the hashes of another algorithm than the constellation one.
+   pub fn create_with_algorithm<P: AsRef<Path>>(
+      path: P,
+      fingerprinter: &Fingerprinter,
+      algorithm: Algorithm,
+   ) -> io::Result<Self> {
+      let path = path.as_ref().to_path_buf();
+      fs::create_dir(&path)?;
+
+      let manifest = Manifest {
+         fingerprinter: fingerprinter.clone(),
+         algorithm,
+         next_track: 0,
+         next_segment: 0,
+         segments: Vec::new(),
+         deleted: BTreeSet::new(),
+      };
+      write_atomically(&path.join(MANIFEST), manifest.encode().as_bytes())?;
+
+      Ok(Self {
+         path,
+         manifest,
+         keys: BTreeMap::new(),
+         ids: HashMap::new(),
+         metadata: BTreeMap::new(),
+      })
+   }
+
+   /// Opens an existing database. Only the keys and the metadata of the tracks are read,
+   /// their hashes are skipped until an index is loaded.
+   pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+      let path = path.as_ref().to_path_buf();
+      let manifest = Manifest::decode(&fs::read_to_string(path.join(MANIFEST))?)?;
+
+      let mut database = Self {
+         path,
+         manifest,
+         keys: BTreeMap::new(),
+         ids: HashMap::new(),
+         metadata: BTreeMap::new(),
+      };
+      for track in database.read_tracks(false)? {
+         database.ids.insert(track.key.clone(), track.id);
+         database.keys.insert(track.id, track.key);
+         database.metadata.insert(track.id, track.metadata);
+      }
+      Ok(database)
+   }
+
+   /// Returns the fingerprinter, whose parameters the hashes were computed with.
+   pub fn fingerprinter(&self) -> &Fingerprinter {
+      &self.manifest.fingerprinter
+   }
+
+   /// Returns the algorithm, that computed the hashes.
+   pub fn algorithm(&self) -> Algorithm {
+      self.manifest.algorithm
+   }
+
+   /// Returns the number of tracks, that are not deleted.
+   pub fn num_tracks(&self) -> usize {
+      self.keys.len()
+   }
+
+   pub fn num_segments(&self) -> usize {
+      self.manifest.segments.len()
+   }
+
+   /// Returns the id of the track with the given key, if it isn't deleted.
+   pub fn track_id(&self, key: &str) -> Option<u32> {
+      self.ids.get(key).copied()
+   }
+
+   /// Returns the key of a track, if it isn't deleted.
+   pub fn track_key(&self, id: u32) -> Option<&str> {
+      self.keys.get(&id).map(String::as_str)
+   }
+
+   /// Returns the metadata of a track, if it isn't deleted.
+   pub fn metadata(&self, id: u32) -> Option<&TrackMetadata> {
+      self.metadata.get(&id)
+   }
+
+   /// Attaches the key and the metadata of its track to a match, if the track isn't deleted.
+   pub fn resolve<M>(&self, track: u32, found: M) -> Option<ResolvedMatch<'_, M>> {
+      Some(ResolvedMatch {
+         key: self.keys.get(&track)?,
+         metadata: self.metadata.get(&track)?,
+         found,
+      })
+   }
+
+   /// Adds tracks given by their keys, hashes and metadata in a new segment and returns their ids.
+   /// Fails, if a key is already used by another track.
+   pub fn append(
+      &mut self,
+      tracks: &[(&str, &[PeakHash], &TrackMetadata)],
+   ) -> io::Result<Vec<u32>> {
+      let mut keys = BTreeSet::new();
+      for (key, _, _) in tracks {
+         if self.ids.contains_key(*key) || !keys.insert(key) {
+            return Err(io::Error::new(
+               io::ErrorKind::AlreadyExists,
+               format!("duplicate track key {}", key),
+            ));
+         }
+      }
+      if tracks.is_empty() {
+         return Ok(Vec::new());
+      }
+
+      let stored = tracks
+         .iter()
+         .enumerate()
+         .map(|(i, (key, hashes, metadata))| StoredTrack {
+            id: self.manifest.next_track + i as u32,
+            key: String::from(*key),
+            hashes: hashes.to_vec(),
+            metadata: (*metadata).clone(),
+         })
+         .collect::<Vec<_>>();
+
+      let mut manifest = self.manifest.clone();
+      manifest.next_track += tracks.len() as u32;
+      let segment = manifest.add_segment();
+      write_atomically(
+         &self.path.join(&segment),
+         &encode_segment(&manifest.fingerprinter, manifest.algorithm, &stored),
+      )?;
+      self.commit(manifest)?;
+
+      for track in stored.iter() {
+         self.ids.insert(track.key.clone(), track.id);
+         self.keys.insert(track.id, track.key.clone());
+         self.metadata.insert(track.id, track.metadata.clone());
+      }
+      Ok(stored.into_iter().map(|track| track.id).collect())
+   }
+
+   /// Adds the tracks of other databases, like slices of a catalogue fingerprinted on
+   /// different machines, in a new segment. The tracks get new ids, the returned maps
+   /// give the new id of every old id for each of the other databases.
+   ///
+   /// Fails without changing anything, if a database was built with different parameters
+   /// or if a key is used by more than one track.
+   pub fn merge(&mut self, others: &[&Database]) -> io::Result<Vec<BTreeMap<u32, u32>>> {
+      if let Some(other) = others.iter().find(|other| {
+         other.fingerprinter() != self.fingerprinter() || other.algorithm() != self.algorithm()
+      }) {
+         return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+               "{} was built with different parameters",
+               other.path.display()
+            ),
+         ));
+      }
+
+      let mut tracks = Vec::new();
+      for other in others {
+         tracks.push(other.tracks()?);
+      }
+
+      // Report all keys used more than once
+      let mut keys = BTreeSet::new();
+      let mut duplicates = BTreeSet::new();
+      for track in tracks.iter().flatten() {
+         if self.ids.contains_key(&track.key) || !keys.insert(&track.key) {
+            duplicates.insert(track.key.as_str());
+         }
+      }
+      if !duplicates.is_empty() {
+         return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+               "duplicate track keys {}",
+               duplicates.into_iter().collect::<Vec<_>>().join(", ")
+            ),
+         ));
+      }
+
+      let ids = self.append(
+         &tracks
+            .iter()
+            .flatten()
+            .map(|track| (track.key.as_str(), &track.hashes[..], &track.metadata))
+            .collect::<Vec<_>>(),
+      )?;
+
+      let mut ids = ids.into_iter();
+      Ok(tracks
+         .iter()
+         .map(|tracks| {
+            tracks
+               .iter()
+               .map(|track| (track.id, ids.next().unwrap()))
+               .collect()
+         })
+         .collect())
+   }
+
+   /// Marks a track as deleted. Returns false, if there is no such track.
+   pub fn delete(&mut self, id: u32) -> io::Result<bool> {
+      if !self.keys.contains_key(&id) {
+         return Ok(false);
+      }
+
+      let mut manifest = self.manifest.clone();
+      manifest.deleted.insert(id);
+      self.commit(manifest)?;
+
+      if let Some(key) = self.keys.remove(&id) {
+         self.ids.remove(&key);
+      }
+      self.metadata.remove(&id);
+      Ok(true)
+   }
+
+   /// Merges all segments into one, dropping the deleted tracks,
+   /// and removes the files, that are no longer referenced.
+   pub fn compact(&mut self) -> io::Result<()> {
+      let tracks = self.tracks()?;
+
+      let mut manifest = self.manifest.clone();
+      manifest.segments.clear();
+      manifest.deleted.clear();
+      if !tracks.is_empty() {
+         let segment = manifest.add_segment();
+         write_atomically(
+            &self.path.join(&segment),
+            &encode_segment(&manifest.fingerprinter, manifest.algorithm, &tracks),
+         )?;
+      }
+      self.commit(manifest)?;
+
+      // Remove old segments and leftovers of interrupted writes
+      for entry in fs::read_dir(&self.path)? {
+         let path = entry?.path();
+         let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+         let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+         let referenced = self.manifest.segments.iter().any(|segment| segment == name);
+         if (extension == SEGMENT_EXTENSION && !referenced) || extension == TEMPORARY_EXTENSION {
+            fs::remove_file(path)?;
+         }
+      }
+      Ok(())
+   }
+
+   /// Reads all tracks, that are not deleted.
+   pub fn tracks(&self) -> io::Result<Vec<StoredTrack>> {
+      self.read_tracks(true)
+   }
+
+   /// Reads all tracks, that are not deleted, leaving their hashes empty unless `with_hashes`.
+   fn read_tracks(&self, with_hashes: bool) -> io::Result<Vec<StoredTrack>> {
+      let mut tracks = Vec::new();
+      for segment in self.manifest.segments.iter() {
+         let (fingerprinter, algorithm, segment_tracks) =
+            decode_segment(&fs::read(self.path.join(segment))?, with_hashes)?;
+         if fingerprinter != self.manifest.fingerprinter {
+            return Err(invalid_data("segment with different parameters"));
+         }
+         if algorithm != self.manifest.algorithm {
+            return Err(invalid_data("segment of a different algorithm"));
+         }
+         tracks.extend(
+            segment_tracks
+               .into_iter()
+               .filter(|track| !self.manifest.deleted.contains(&track.id)),
+         );
+      }
+      Ok(tracks)
+   }
+
+   /// Reads all tracks, that are not deleted, into an index.
+   pub fn load_index(&self) -> io::Result<Index> {
+      let mut index = Index::new();
+      for track in self.tracks()? {
+         index.insert(track.id, &track.hashes);
+      }
+      Ok(index)
+   }
+
+   /// Reads all tracks, that are not deleted, into a compact index,
+   /// which takes less than half the memory of [`load_index`](Self::load_index).
+   pub fn load_compact_index(&self) -> io::Result<CompactIndex> {
+      let mut index = CompactIndex::new();
+      for track in self.tracks()? {
+         index.insert(track.id, &track.hashes);
+      }
+      index.shrink_to_fit();
+      Ok(index)
+   }
+
+   /// Reads all tracks, that are not deleted, into a sharded index, filling the shards in parallel.
+   /// Fails, if there are no shards.
+   pub fn load_sharded_index(&self, num_shards: usize) -> io::Result<ShardedIndex> {
+      let mut index = ShardedIndex::new(num_shards).map_err(|_| {
+         io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "an index needs at least one shard",
+         )
+      })?;
+      let tracks = self.tracks()?;
+      index.insert_batch(
+         &tracks
+            .iter()
+            .map(|track| (track.id, &track.hashes[..]))
+            .collect::<Vec<_>>(),
+      );
+      index.shrink_to_fit();
+      Ok(index)
+   }
+
+   /// Reads the sub-fingerprints of all tracks, that are not deleted, into an index.
+   /// Fails, if the database holds hashes of another algorithm.
+   pub fn load_sub_fingerprint_index(&self) -> io::Result<SubFingerprintIndex> {
+      if self.manifest.algorithm != Algorithm::SubFingerprint {
+         return Err(invalid_data("not a sub-fingerprint database"));
+      }
+
+      let mut index = SubFingerprintIndex::new();
+      for mut track in self.tracks()? {
+         track.hashes.sort_unstable_by_key(|hash| hash.time);
+         let sub_fingerprints = track
+            .hashes
+            .iter()
+            .map(|hash| hash.hash)
+            .collect::<Vec<_>>();
+         index.insert(track.id, &sub_fingerprints);
+      }
+      Ok(index)
+   }
+
+   /// Serializes all tracks, that are not deleted, into a single segment,
+   /// which can be loaded as a [`DatabaseSnapshot`].
+   /// Only databases of the constellation algorithm can be exported.
+   pub fn export(&self) -> io::Result<Vec<u8>> {
+      if self.manifest.algorithm != Algorithm::Constellation {
+         return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only constellation databases can be exported",
+         ));
+      }
+
+      Ok(encode_segment(
+         &self.manifest.fingerprinter,
+         self.manifest.algorithm,
+         &self.tracks()?,
+      ))
+   }
+
+   /// Makes the manifest take effect.
+   fn commit(&mut self, manifest: Manifest) -> io::Result<()> {
+      write_atomically(&self.path.join(MANIFEST), manifest.encode().as_bytes())?;
+      self.manifest = manifest;
+      Ok(())
+   }
+}
+
+impl DatabaseSnapshot {
+   /// Loads a database serialized by [`Database::export`] and indexes its tracks.
+   /// Fails, if the segment holds hashes of another algorithm than the constellation one.
+   pub fn decode(data: &[u8]) -> io::Result<Self> {
+      let (fingerprinter, algorithm, tracks) = decode_segment(data, true)?;
+      if algorithm != Algorithm::Constellation {
+         return Err(invalid_data("only constellation snapshots can be loaded"));
+      }
+
+      let mut snapshot = Self {
+         fingerprinter,
+         algorithm,
+         keys: BTreeMap::new(),
+         metadata: BTreeMap::new(),
+         index: CompactIndex::new(),
+      };
+      for track in tracks {
+         snapshot.index.insert(track.id, &track.hashes);
+         snapshot.keys.insert(track.id, track.key);
+         snapshot.metadata.insert(track.id, track.metadata);
+      }
+      snapshot.index.shrink_to_fit();
+      Ok(snapshot)
+   }
+
+   /// Returns the fingerprinter, whose parameters the hashes were computed with.
+   pub fn fingerprinter(&self) -> &Fingerprinter {
+      &self.fingerprinter
+   }
+
+   /// Returns the algorithm, that computed the hashes.
+   pub fn algorithm(&self) -> Algorithm {
+      self.algorithm
+   }
+
+   pub fn num_tracks(&self) -> usize {
+      self.keys.len()
+   }
+
+   /// Returns the index of all tracks.
+   pub fn index(&self) -> &CompactIndex {
+      &self.index
+   }
+
+   /// Attaches the key and the metadata of its track to a match.
+   pub fn resolve<M>(&self, track: u32, found: M) -> Option<ResolvedMatch<'_, M>> {
+      Some(ResolvedMatch {
+         key: self.keys.get(&track)?,
+         metadata: self.metadata.get(&track)?,
+         found,
+      })
+   }
+}
+
+impl Manifest {
+   /// Reserves the name of a new segment file.
+   fn add_segment(&mut self) -> String {
+      let name = format!("{:08}.{}", self.next_segment, SEGMENT_EXTENSION);
+      self.next_segment += 1;
+      self.segments.push(name.clone());
+      name
+   }
+
+   fn encode(&self) -> String {
+      let f = self.fingerprinter.config();
+      let mut text = format!(
+         "{} {}\nparameters {} {} {} {}\nhashing {} {} {}\nalgorithm {}\nnext_track {}\nnext_segment {}\n",
+         MANIFEST_HEADER,
+         MANIFEST_VERSION,
+         f.sample_rate,
+         f.block_size,
+         f.step_size,
+         f.t_span,
+         f.fan_out,
+         f.min_dt,
+         f.max_dt,
+         self.algorithm.name(),
+         self.next_track,
+         self.next_segment
+      );
+      for segment in self.segments.iter() {
+         text.push_str(&format!("segment {}\n", segment));
+      }
+      for id in self.deleted.iter() {
+         text.push_str(&format!("deleted {}\n", id));
+      }
+      text
+   }
+
+   fn decode(text: &str) -> io::Result<Self> {
+      let mut lines = text.lines();
+      if lines.next() != Some(&format!("{} {}", MANIFEST_HEADER, MANIFEST_VERSION)) {
+         return Err(invalid_data(
+            "not a fingerprint database of a supported version",
+         ));
+      }
+
+      let (mut parameters, mut hashing) = (None, None);
+      let mut algorithm = Algorithm::Constellation;
+      let (mut next_track, mut next_segment) = (0, 0);
+      let (mut segments, mut deleted) = (Vec::new(), BTreeSet::new());
+      for line in lines {
+         let mut words = line.split(' ');
+         let number = |word: Option<&str>| {
+            word
+               .and_then(|word| word.parse::<u64>().ok())
+               .ok_or_else(|| invalid_data("invalid number in manifest"))
+         };
+
+         match words.next() {
+            Some("parameters") => {
+               let mut parameter = || number(words.next()).map(|value| value as usize);
+               parameters = Some((parameter()?, parameter()?, parameter()?, parameter()?));
+            }
+            Some("hashing") => {
+               let mut parameter = || number(words.next()).map(|value| value as usize);
+               hashing = Some((parameter()?, parameter()?, parameter()?));
+            }
+            Some("algorithm") => {
+               algorithm = words
+                  .next()
+                  .and_then(Algorithm::from_name)
+                  .ok_or_else(|| invalid_data("unknown algorithm in manifest"))?;
+            }
+            Some("next_track") => next_track = number(words.next())? as u32,
+            Some("next_segment") => next_segment = number(words.next())?,
+            Some("segment") => segments.push(String::from(
+               words
+                  .next()
+                  .ok_or_else(|| invalid_data("missing segment name"))?,
+            )),
+            Some("deleted") => {
+               deleted.insert(number(words.next())? as u32);
+            }
+            _ => return Err(invalid_data("invalid line in manifest")),
+         }
+      }
+
+      let (sample_rate, block_size, step_size, t_span) =
+         parameters.ok_or_else(|| invalid_data("missing parameters"))?;
+      let (fan_out, min_dt, max_dt) =
+         hashing.ok_or_else(|| invalid_data("missing hashing parameters"))?;
+      let config = FingerprintConfig {
+         sample_rate,
+         block_size,
+         step_size,
+         t_span,
+         fan_out,
+         min_dt,
+         max_dt,
+      };
+
+      Ok(Self {
+         fingerprinter: Fingerprinter::with_config(config)
+            .map_err(|_| invalid_data("invalid parameters in manifest"))?,
+         algorithm,
+         next_track,
+         next_segment,
+         segments,
+         deleted,
+      })
+   }
+}
+
+fn encode_segment(
+   fingerprinter: &Fingerprinter,
+   algorithm: Algorithm,
+   tracks: &[StoredTrack],
+) -> Vec<u8> {
+   let config = fingerprinter.config();
+   let mut data = SEGMENT_MAGIC.to_vec();
+   for value in [
+      SEGMENT_VERSION as usize,
+      config.sample_rate,
+      config.block_size,
+      config.step_size,
+      config.t_span,
+      config.fan_out,
+      config.min_dt,
+      config.max_dt,
+   ]
+   .iter()
+   {
+      data.extend_from_slice(&(*value as u32).to_le_bytes());
+   }
+   write_varint(&mut data, algorithm.name().len() as u64);
+   data.extend_from_slice(algorithm.name().as_bytes());
+
+   write_varint(&mut data, tracks.len() as u64);
+   for track in tracks {
+      write_varint(&mut data, track.id as u64);
+      write_varint(&mut data, track.key.len() as u64);
+      data.extend_from_slice(track.key.as_bytes());
+
+      let mut hashes = track.hashes.clone();
+      hashes.sort_unstable_by_key(|hash| (hash.time, hash.hash, hash.span));
+      write_varint(&mut data, hashes.len() as u64);
+      let mut last_time = 0;
+      for hash in hashes {
+         write_varint(&mut data, (hash.time - last_time) as u64);
+         write_varint(&mut data, hash.span as u64);
+         data.extend_from_slice(&hash.hash.to_le_bytes());
+         last_time = hash.time;
+      }
+
+      write_metadata(&mut data, &track.metadata);
+   }
+
+   let checksum = checksum(&data);
+   data.extend_from_slice(&checksum.to_le_bytes());
+   data
+}
+
+/// Decodes a segment, skipping the hashes of the tracks unless `with_hashes`.
+fn decode_segment(
+   data: &[u8],
+   with_hashes: bool,
+) -> io::Result<(Fingerprinter, Algorithm, Vec<StoredTrack>)> {
+   if data.len() < SEGMENT_MAGIC.len() + 4 || &data[..SEGMENT_MAGIC.len()] != SEGMENT_MAGIC {
+      return Err(invalid_data("not a segment file"));
+   }
+   let (data, stored_checksum) = data.split_at(data.len() - 4);
+   if checksum(data).to_le_bytes() != stored_checksum {
+      return Err(invalid_data("corrupted segment file"));
+   }
+
+   let mut reader = Reader {
+      data: &data[SEGMENT_MAGIC.len()..],
+   };
+   let version = reader.u32()?;
+   if version != SEGMENT_VERSION {
+      return Err(invalid_data("unsupported segment version"));
+   }
+   let config = FingerprintConfig {
+      sample_rate: reader.u32()? as usize,
+      block_size: reader.u32()? as usize,
+      step_size: reader.u32()? as usize,
+      t_span: reader.u32()? as usize,
+      fan_out: reader.u32()? as usize,
+      min_dt: reader.u32()? as usize,
+      max_dt: reader.u32()? as usize,
+   };
+   let fingerprinter = Fingerprinter::with_config(config)
+      .map_err(|_| invalid_data("invalid parameters in segment"))?;
+   let name_length = reader.varint()? as usize;
+   let algorithm = Algorithm::from_name(&reader.string(name_length)?)
+      .ok_or_else(|| invalid_data("unknown algorithm in segment"))?;
+
+   let mut tracks = Vec::new();
+   for _ in 0..reader.varint()? {
+      let id = reader.varint_u32()?;
+      let key_length = reader.varint()? as usize;
+      let key = reader.string(key_length)?;
+
+      let num_hashes = reader.varint()? as usize;
+      let mut hashes = Vec::with_capacity(if with_hashes {
+         usize::min(num_hashes, reader.data.len())
+      } else {
+         0
+      });
+      let mut time = 0u32;
+      for _ in 0..num_hashes {
+         time = time
+            .checked_add(reader.varint_u32()?)
+            .ok_or_else(|| invalid_data("invalid number in segment file"))?;
+         let span = reader.varint_u32()?;
+         let hash = reader.u32()?;
+         if with_hashes {
+            hashes.push(PeakHash { hash, time, span });
+         }
+      }
+
+      let metadata = reader.metadata()?;
+
+      tracks.push(StoredTrack {
+         id,
+         key,
+         hashes,
+         metadata,
+      });
+   }
+   Ok((fingerprinter, algorithm, tracks))
+}
+
+fn write_metadata(data: &mut Vec<u8>, metadata: &TrackMetadata) {
+   for value in [
+      &metadata.title,
+      &metadata.artist,
+      &metadata.album,
+      &metadata.isrc,
+      &metadata.source_path,
+   ]
+   .iter()
+   {
+      // The length is stored plus one, zero means no value
+      match value {
+         Some(value) => {
+            write_varint(data, value.len() as u64 + 1);
+            data.extend_from_slice(value.as_bytes());
+         }
+         None => write_varint(data, 0),
+      }
+   }
+
+   match metadata.duration {
+      Some(duration) => {
+         data.push(1);
+         data.extend_from_slice(&duration.to_le_bytes());
+      }
+      None => data.push(0),
+   }
+   match metadata.checksum {
+      Some(checksum) => {
+         data.push(1);
+         data.extend_from_slice(&checksum.to_le_bytes());
+      }
+      None => data.push(0),
+   }
+
+   write_varint(data, metadata.tags.len() as u64);
+   for (key, value) in metadata.tags.iter() {
+      for text in [key, value].iter() {
+         write_varint(data, text.len() as u64);
+         data.extend_from_slice(text.as_bytes());
+      }
+   }
+}
+
+/// Reads the values of a segment file, failing if the data ends early.
+struct Reader<'a> {
+   data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+   fn bytes(&mut self, length: usize) -> io::Result<&'a [u8]> {
+      if self.data.len() < length {
+         return Err(invalid_data("truncated segment file"));
+      }
+      let (bytes, rest) = self.data.split_at(length);
+      self.data = rest;
+      Ok(bytes)
+   }
+
+   fn u32(&mut self) -> io::Result<u32> {
+      let mut bytes = [0; 4];
+      bytes.copy_from_slice(self.bytes(4)?);
+      Ok(u32::from_le_bytes(bytes))
+   }
+
+   fn u64(&mut self) -> io::Result<u64> {
+      let mut bytes = [0; 8];
+      bytes.copy_from_slice(self.bytes(8)?);
+      Ok(u64::from_le_bytes(bytes))
+   }
+
+   fn string(&mut self, length: usize) -> io::Result<String> {
+      String::from_utf8(self.bytes(length)?.to_vec()).map_err(|_| invalid_data("invalid text"))
+   }
+
+   fn optional_string(&mut self) -> io::Result<Option<String>> {
+      match self.varint()? as usize {
+         0 => Ok(None),
+         length => self.string(length - 1).map(Some),
+      }
+   }
+
+   /// Reads a value, that is preceded by a byte telling whether it is present.
+   fn optional<T>(
+      &mut self,
+      read: impl FnOnce(&mut Self) -> io::Result<T>,
+   ) -> io::Result<Option<T>> {
+      match self.bytes(1)?[0] {
+         0 => Ok(None),
+         _ => read(self).map(Some),
+      }
+   }
+
+   fn metadata(&mut self) -> io::Result<TrackMetadata> {
+      let title = self.optional_string()?;
+      let artist = self.optional_string()?;
+      let album = self.optional_string()?;
+      let isrc = self.optional_string()?;
+      let source_path = self.optional_string()?;
+      let duration = self.optional(|reader| reader.u64().map(f64::from_bits))?;
+      let checksum = self.optional(Reader::u64)?;
+
+      let mut tags = BTreeMap::new();
+      for _ in 0..self.varint()? {
+         let key_length = self.varint()? as usize;
+         let key = self.string(key_length)?;
+         let value_length = self.varint()? as usize;
+         tags.insert(key, self.string(value_length)?);
+      }
+
+      Ok(TrackMetadata {
+         title,
+         artist,
+         album,
+         isrc,
+         duration,
+         source_path,
+         tags,
+         checksum,
+      })
+   }
+
+   fn varint(&mut self) -> io::Result<u64> {
+      let mut value = 0;
+      for shift in (0..64).step_by(7) {
+         let byte = self.bytes(1)?[0];
+         value |= ((byte & 0x7f) as u64) << shift;
+         if byte & 0x80 == 0 {
+            return Ok(value);
+         }
+      }
+      Err(invalid_data("invalid number in segment file"))
+   }
+
+   /// Reads a variable length number, that has to fit into 32 bits.
+   fn varint_u32(&mut self) -> io::Result<u32> {
+      u32::try_from(self.varint()?).map_err(|_| invalid_data("invalid number in segment file"))
+   }
+}
+
+/// Computes the 32 bit FNV-1a hash of the data.
+fn checksum(data: &[u8]) -> u32 {
+   data.iter().fold(0x811c_9dc5, |hash, byte| {
+      (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+   })
+}
+
+/// Writes a file under a temporary name, syncs it and renames it,
+/// such that it either has its old or its new content after a crash.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+   let temporary = path.with_extension(TEMPORARY_EXTENSION);
+   let mut file = File::create(&temporary)?;
+   file.write_all(data)?;
+   file.sync_all()?;
+   drop(file);
+   fs::rename(&temporary, path)?;
+
+   // Make the rename itself durable, where directories can be synced
+   if let Some(directory) = path.parent() {
+      if let Ok(directory) = File::open(directory) {
+         let _ = directory.sync_all();
+      }
+   }
+   Ok(())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+   io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   /// A directory, that is removed when the test ends.
+   struct TempDir(PathBuf);
+
+   impl TempDir {
+      fn new(name: &str) -> Self {
+         let path =
+            std::env::temp_dir().join(format!("algo-database-{}-{}", name, std::process::id()));
+         let _ = fs::remove_dir_all(&path);
+         fs::create_dir_all(&path).unwrap();
+         Self(path)
+      }
+
+      /// Returns the path of the database within the directory.
+      fn database(&self) -> PathBuf {
+         self.0.join("db")
+      }
+   }
+
+   impl Drop for TempDir {
+      fn drop(&mut self) {
+         let _ = fs::remove_dir_all(&self.0);
+      }
+   }
+
+   fn fingerprinter() -> Fingerprinter {
+      Fingerprinter::with_config(FingerprintConfig::music()).unwrap()
+   }
+
+   /// Returns hashes sorted by time, like segments store them.
+   fn hashes(seed: u32) -> Vec<PeakHash> {
+      (0..100)
+         .map(|i| PeakHash {
+            hash: seed.wrapping_mul(0x9e37_79b1) ^ (i * 7919),
+            time: i * 3,
+            span: i % 7,
+         })
+         .collect()
+   }
+
+   fn metadata(title: &str) -> TrackMetadata {
+      TrackMetadata {
+         title: Some(String::from(title)),
+         duration: Some(12.5),
+         ..TrackMetadata::default()
+      }
+   }
+
+   fn segment_files(path: &Path) -> Vec<String> {
+      let mut names = fs::read_dir(path)
+         .unwrap()
+         .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+         .filter(|name| name != MANIFEST)
+         .collect::<Vec<_>>();
+      names.sort();
+      names
+   }
+
+   #[test]
+   fn reopens_the_tracks_it_wrote() {
+      let dir = TempDir::new("reopen");
+      let mut database = Database::create(dir.database(), &fingerprinter()).unwrap();
+      let (a, b) = (hashes(1), hashes(2));
+      let ids = database
+         .append(&[("a", &a, &metadata("A")), ("b", &b, &metadata("B"))])
+         .unwrap();
+      assert_eq!(ids, [0, 1]);
+      assert!(database.append(&[("a", &a, &metadata("A"))]).is_err());
+
+      let database = Database::open(dir.database()).unwrap();
+      assert_eq!(database.fingerprinter(), &fingerprinter());
+      assert_eq!(database.algorithm(), Algorithm::Constellation);
+      assert_eq!(database.num_tracks(), 2);
+      assert_eq!(database.track_id("b"), Some(1));
+      assert_eq!(database.track_id("c"), None);
+      assert_eq!(database.track_key(0), Some("a"));
+      assert_eq!(database.metadata(1), Some(&metadata("B")));
+
+      let tracks = database.tracks().unwrap();
+      assert_eq!(tracks.len(), 2);
+      assert_eq!((tracks[0].id, &tracks[0].key), (0, &String::from("a")));
+      assert_eq!(tracks[0].hashes, a);
+      assert_eq!(tracks[1].hashes, b);
+      assert_eq!(
+         database
+            .load_index()
+            .unwrap()
+            .query(&b[10..50])
+            .unwrap()
+            .track,
+         1
+      );
+   }
+
+   #[test]
+   fn drops_deleted_tracks_when_compacting() {
+      let dir = TempDir::new("compact");
+      let mut database = Database::create(dir.database(), &fingerprinter()).unwrap();
+      database
+         .append(&[("a", &hashes(1), &metadata("A"))])
+         .unwrap();
+      database
+         .append(&[("b", &hashes(2), &metadata("B"))])
+         .unwrap();
+      assert_eq!(database.num_segments(), 2);
+
+      assert!(database.delete(0).unwrap());
+      assert!(!database.delete(0).unwrap());
+      assert_eq!(database.track_id("a"), None);
+
+      // The tombstone survives reopening
+      let mut database = Database::open(dir.database()).unwrap();
+      assert_eq!(database.num_tracks(), 1);
+      assert_eq!(database.resolve(0, ()), None);
+      assert_eq!(database.tracks().unwrap().len(), 1);
+
+      database.compact().unwrap();
+      assert_eq!(database.num_segments(), 1);
+      assert_eq!(segment_files(&dir.database()).len(), 1);
+      let database = Database::open(dir.database()).unwrap();
+      let tracks = database.tracks().unwrap();
+      assert_eq!(tracks.len(), 1);
+      assert_eq!((tracks[0].id, &tracks[0].hashes), (1, &hashes(2)));
+
+      // The key of the deleted track can be used again, with a new id
+      let mut database = database;
+      assert_eq!(
+         database
+            .append(&[("a", &hashes(3), &metadata("A"))])
+            .unwrap(),
+         [2]
+      );
+   }
+
+   #[test]
+   fn rejects_corrupted_segments() {
+      let dir = TempDir::new("corrupted");
+      let mut database = Database::create(dir.database(), &fingerprinter()).unwrap();
+      database
+         .append(&[("a", &hashes(1), &metadata("A"))])
+         .unwrap();
+
+      let segment = dir.database().join(&segment_files(&dir.database())[0]);
+      let mut data = fs::read(&segment).unwrap();
+      data[40] ^= 1;
+      fs::write(&segment, &data).unwrap();
+      let err = Database::open(dir.database()).unwrap_err();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+      assert_eq!(err.to_string(), "corrupted segment file");
+
+      fs::write(&segment, &data[..data.len() / 2]).unwrap();
+      assert!(Database::open(dir.database()).is_err());
+      fs::write(dir.database().join(MANIFEST), "fingerprint-database 0\n").unwrap();
+      assert!(Database::open(dir.database()).is_err());
+   }
+
+   #[test]
+   fn rejects_numbers_beyond_32_bits() {
+      // A segment of a single track with hashes of the given time differences and spans
+      let segment = |hashes: &[(u64, u64)]| {
+         let empty = encode_segment(&fingerprinter(), Algorithm::Constellation, &[]);
+         // Without the number of tracks and the checksum
+         let mut data = empty[..empty.len() - 5].to_vec();
+         for value in [1, 0, 0, hashes.len() as u64] {
+            write_varint(&mut data, value);
+         }
+         for (dt, span) in hashes {
+            write_varint(&mut data, *dt);
+            write_varint(&mut data, *span);
+            data.extend_from_slice(&0u32.to_le_bytes());
+         }
+         write_metadata(&mut data, &TrackMetadata::default());
+         let checksum = checksum(&data);
+         data.extend_from_slice(&checksum.to_le_bytes());
+         data
+      };
+
+      let max = u32::MAX as u64;
+      let (_, _, tracks) = decode_segment(&segment(&[(max - 1, max), (1, 0)]), true).unwrap();
+      assert_eq!(tracks[0].hashes[1].time, u32::MAX);
+      for hashes in [
+         [(max, 0), (1, 0)],
+         [(max + 1, 0), (0, 0)],
+         [(0, max + 1), (0, 0)],
+      ] {
+         let err = decode_segment(&segment(&hashes), true).unwrap_err();
+         assert_eq!(err.to_string(), "invalid number in segment file");
+      }
+   }
+
+   #[test]
+   fn snapshots_only_hold_constellation_hashes() {
+      let dir = TempDir::new("snapshot");
+      let mut database = Database::create(dir.database(), &fingerprinter()).unwrap();
+      database
+         .append(&[("a", &hashes(1), &metadata("A"))])
+         .unwrap();
+      let snapshot = DatabaseSnapshot::decode(&database.export().unwrap()).unwrap();
+      assert_eq!(snapshot.algorithm(), Algorithm::Constellation);
+      assert_eq!(snapshot.num_tracks(), 1);
+      assert_eq!(snapshot.resolve(0, ()).unwrap().key, "a");
+
+      let tracks = database.tracks().unwrap();
+      let triplets = encode_segment(&fingerprinter(), Algorithm::Triplet, &tracks);
+      let err = DatabaseSnapshot::decode(&triplets).unwrap_err();
+      assert_eq!(
+         err.to_string(),
+         "only constellation snapshots can be loaded"
+      );
+
+      // Nor do segments of another algorithm than the manifest pass
+      let segment = dir.database().join(&segment_files(&dir.database())[0]);
+      fs::write(&segment, &triplets).unwrap();
+      let err = Database::open(dir.database()).unwrap_err();
+      assert_eq!(err.to_string(), "segment of a different algorithm");
+   }
+
+   #[test]
+   fn recovers_from_an_interrupted_write() {
+      let dir = TempDir::new("interrupted");
+      let mut database = Database::create(dir.database(), &fingerprinter()).unwrap();
+      database
+         .append(&[("a", &hashes(1), &metadata("A"))])
+         .unwrap();
+      let manifest = fs::read(dir.database().join(MANIFEST)).unwrap();
+
+      // A crash after the next segment was renamed, while the new manifest was written
+      database
+         .append(&[("b", &hashes(2), &metadata("B"))])
+         .unwrap();
+      fs::write(dir.database().join(MANIFEST), &manifest).unwrap();
+      fs::write(
+         dir.database()
+            .join(MANIFEST)
+            .with_extension(TEMPORARY_EXTENSION),
+         &manifest[..10],
+      )
+      .unwrap();
+      // and a crash while a segment was written
+      fs::write(
+         dir.database()
+            .join(format!("00000009.{}", TEMPORARY_EXTENSION)),
+         b"FPSG",
+      )
+      .unwrap();
+      assert_eq!(segment_files(&dir.database()).len(), 4);
+
+      // The database is in its old state, compaction removes the leftovers
+      let mut database = Database::open(dir.database()).unwrap();
+      assert_eq!(database.num_tracks(), 1);
+      assert_eq!(database.track_id("b"), None);
+      database.compact().unwrap();
+      assert_eq!(segment_files(&dir.database()).len(), 1);
+
+      database
+         .append(&[("b", &hashes(2), &metadata("B"))])
+         .unwrap();
+      let database = Database::open(dir.database()).unwrap();
+      assert_eq!(database.num_tracks(), 2);
+      assert_eq!(database.tracks().unwrap()[1].hashes, hashes(2));
+   }
+}
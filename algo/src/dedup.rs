@@ -0,0 +1,371 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{hash::PeakHash, index::Index};
+
+/// Number of candidates every track is matched against.
+const MAX_CANDIDATES: usize = 16;
+/// Number of hashes, that need to line up for two tracks to overlap.
+const MIN_MATCHES: usize = 20;
+/// Default fraction of the hashes within the overlap, that need to line up for two tracks to be
+/// duplicates. Since many features of quiet bins don't survive a shift of the audio by a fraction
+/// of a step, even identical audio rarely exceeds a similarity of 0.3, while unrelated tracks
+/// stay below 0.01. Remasters land in between, so the default keeps them and lies just above
+/// the unrelated tracks, see [`Deduplicator::set_min_similarity`].
+pub const MIN_SIMILARITY: f64 = 0.015;
+/// Offsets closer than this number of wavelets belong to the same overlap.
+const OFFSET_TOLERANCE: i64 = 2;
+/// Factor by which the votes of an overlap need to exceed the votes of any other offset.
+const MIN_PEAK_RATIO: f64 = 4.0;
+
+/// A part of one track, that also occurs in another track.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Overlap {
+   pub first: u32,
+   pub second: u32,
+   /// The start and the end of the overlap in the first track, in seconds.
+   pub first_range: (f64, f64),
+   /// The start and the end of the overlap in the second track, in seconds.
+   pub second_range: (f64, f64),
+   /// The fraction of the hashes within the overlap, that line up.
+   pub similarity: f64,
+}
+
+/// A group of tracks, that are duplicates of each other, together with their overlaps.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Cluster {
+   pub tracks: Vec<u32>,
+   pub overlaps: Vec<Overlap>,
+}
+
+/// The deduplicator finds duplicates and near duplicates in a collection of tracks,
+/// like re-uploads, remasters and clipped edits.
+///
+/// # Algorithm
+/// All tracks are put into one index and every track is queried against it.
+/// Another track overlaps, if the hashes lining up at its best offset clearly outnumber those
+/// at any other offset, since random collisions pile up at many offsets alike.
+/// The overlap is given by the first and the last of these hashes. The similarity is the
+/// fraction of the hashes of both tracks within the overlap, that line up, which is
+/// higher for re-uploads than for remasters.
+/// Tracks connected by similar overlaps are grouped into clusters.
+#[derive(Debug, Clone)]
+pub struct Deduplicator {
+   index: Index,
+   tracks: BTreeMap<u32, Vec<PeakHash>>,
+   wavelets_per_second: f64,
+   min_similarity: f64,
+}
+
+impl Deduplicator {
+   /// Creates a deduplicator for hashes with the given time resolution,
+   /// see [`Fingerprinter::wavelets_per_second`](crate::fingerprint::Fingerprinter::wavelets_per_second).
+   pub fn new(wavelets_per_second: f64) -> Self {
+      Self {
+         index: Index::new(),
+         tracks: BTreeMap::new(),
+         wavelets_per_second,
+         min_similarity: MIN_SIMILARITY,
+      }
+   }
+
+   /// Sets the fraction of the hashes within an overlap, that need to line up.
+   /// Raising it towards 0.3 only keeps re-uploads, lowering it towards 0.01 risks
+   /// reporting unrelated tracks.
+   pub fn set_min_similarity(&mut self, min_similarity: f64) {
+      self.min_similarity = min_similarity;
+   }
+
+   /// Adds the hashes of a track.
+   pub fn insert(&mut self, track: u32, hashes: Vec<PeakHash>) {
+      self.index.insert(track, &hashes);
+      self.tracks.entry(track).or_default().extend(hashes);
+   }
+
+   /// Cross matches all tracks and returns the overlaps of every pair of tracks.
+   pub fn overlaps(&self) -> Vec<Overlap> {
+      let mut overlaps = Vec::new();
+
+      for (track, hashes) in self.tracks.iter() {
+         for candidate in self.index.candidates(hashes, MAX_CANDIDATES) {
+            // Every pair is found from both sides, only keep one of them
+            if candidate.track <= *track || candidate.score < MIN_MATCHES {
+               continue;
+            }
+
+            // Collect the times of the first track, that vote for each offset
+            let mut votes = BTreeMap::<i64, Vec<i64>>::new();
+            for hash in hashes {
+               for posting in self.index.lookup(hash.hash) {
+                  if posting.track == candidate.track {
+                     votes
+                        .entry(posting.time as i64 - hash.time as i64)
+                        .or_default()
+                        .push(hash.time as i64);
+                  }
+               }
+            }
+
+            // Random collisions pile up at many offsets, a true overlap stands out clearly
+            let background = votes
+               .iter()
+               .filter(|(offset, _)| (*offset - candidate.offset).abs() > OFFSET_TOLERANCE)
+               .map(|(_, times)| times.len())
+               .max()
+               .unwrap_or(0);
+            if (candidate.score as f64) < MIN_PEAK_RATIO * background as f64 {
+               continue;
+            }
+
+            // Find the range of the first track, where the hashes line up
+            let mut times = votes
+               .range(candidate.offset - OFFSET_TOLERANCE..=candidate.offset + OFFSET_TOLERANCE)
+               .flat_map(|(_, times)| times.iter().cloned())
+               .collect::<Vec<_>>();
+            let matches = times.len();
+            times.sort_unstable();
+            let (start, end) = match (times.first(), times.last()) {
+               (Some(start), Some(end)) if start < end => (*start, *end),
+               _ => continue,
+            };
+
+            // Compare to the number of hashes of both tracks in the range
+            let in_range = |hashes: &[PeakHash], offset: i64| {
+               hashes
+                  .iter()
+                  .filter(|hash| (start..=end).contains(&(hash.time as i64 - offset)))
+                  .count()
+            };
+            let total = usize::max(
+               in_range(hashes, 0),
+               in_range(&self.tracks[&candidate.track], candidate.offset),
+            );
+            let similarity = matches as f64 / total as f64;
+            if similarity < self.min_similarity {
+               continue;
+            }
+
+            let seconds = |time: i64| time as f64 / self.wavelets_per_second;
+            overlaps.push(Overlap {
+               first: *track,
+               second: candidate.track,
+               first_range: (seconds(start), seconds(end)),
+               second_range: (
+                  seconds(start + candidate.offset),
+                  seconds(end + candidate.offset),
+               ),
+               similarity,
+            });
+         }
+      }
+
+      overlaps
+   }
+
+   /// Groups the tracks connected by overlaps into clusters, largest first.
+   pub fn clusters(&self) -> Vec<Cluster> {
+      let overlaps = self.overlaps();
+
+      // Union find over the tracks
+      let mut parents = self
+         .tracks
+         .keys()
+         .map(|track| (*track, *track))
+         .collect::<BTreeMap<_, _>>();
+      fn root(parents: &BTreeMap<u32, u32>, mut track: u32) -> u32 {
+         while parents[&track] != track {
+            track = parents[&track];
+         }
+         track
+      }
+      for overlap in overlaps.iter() {
+         let (a, b) = (
+            root(&parents, overlap.first),
+            root(&parents, overlap.second),
+         );
+         parents.insert(u32::max(a, b), u32::min(a, b));
+      }
+
+      let mut clusters = BTreeMap::<u32, Cluster>::new();
+      for overlap in overlaps {
+         let cluster = clusters
+            .entry(root(&parents, overlap.first))
+            .or_insert(Cluster {
+               tracks: Vec::new(),
+               overlaps: Vec::new(),
+            });
+         cluster
+            .tracks
+            .extend([overlap.first, overlap.second].iter());
+         cluster.overlaps.push(overlap);
+      }
+
+      let mut clusters = clusters
+         .into_values()
+         .map(|mut cluster| {
+            cluster.tracks.sort_unstable();
+            cluster.tracks.dedup();
+            cluster
+         })
+         .collect::<Vec<_>>();
+      clusters.sort_by(|a, b| {
+         b.tracks
+            .len()
+            .cmp(&a.tracks.len())
+            .then(a.tracks.cmp(&b.tracks))
+      });
+      clusters
+   }
+}
+
+/// Writes the clusters as a JSON array, naming the tracks with `name`.
+pub fn to_json<F: Fn(u32) -> String>(clusters: &[Cluster], name: F) -> String {
+   let mut json = String::from("[");
+   for (i, cluster) in clusters.iter().enumerate() {
+      if i > 0 {
+         json.push(',');
+      }
+
+      let tracks = cluster
+         .tracks
+         .iter()
+         .map(|track| escape(&name(*track)))
+         .collect::<Vec<_>>();
+      write!(
+         json,
+         "\n  {{\n    \"tracks\": [{}],\n    \"overlaps\": [",
+         tracks.join(", ")
+      )
+      .unwrap();
+
+      for (j, overlap) in cluster.overlaps.iter().enumerate() {
+         if j > 0 {
+            json.push(',');
+         }
+         write!(
+            json,
+            "\n      {{\"first\": {}, \"first_range\": [{:.3}, {:.3}], \"second\": {}, \"second_range\": [{:.3}, {:.3}], \"similarity\": {:.3}}}",
+            escape(&name(overlap.first)),
+            overlap.first_range.0,
+            overlap.first_range.1,
+            escape(&name(overlap.second)),
+            overlap.second_range.0,
+            overlap.second_range.1,
+            overlap.similarity
+         )
+         .unwrap();
+      }
+      json.push_str("\n    ]\n  }");
+   }
+   json.push_str("\n]\n");
+   json
+}
+
+/// Quotes a string for JSON.
+pub(crate) fn escape(value: &str) -> String {
+   let mut escaped = String::from("\"");
+   for c in value.chars() {
+      match c {
+         '"' => escaped.push_str("\\\""),
+         '\\' => escaped.push_str("\\\\"),
+         '\n' => escaped.push_str("\\n"),
+         c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+         c => escaped.push(c),
+      }
+   }
+   escaped.push('"');
+   escaped
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{config::FingerprintConfig, fingerprint::Fingerprinter, testing};
+
+   const SAMPLE_RATE: usize = 11025;
+   /// Largest error of the ranges of an overlap, in seconds.
+   const TIME_TOLERANCE: f64 = 0.5;
+
+   fn assert_close(actual: (f64, f64), expected: (f64, f64)) {
+      assert!(
+         (actual.0 - expected.0).abs() < TIME_TOLERANCE
+            && (actual.1 - expected.1).abs() < TIME_TOLERANCE,
+         "{:?} != {:?}",
+         actual,
+         expected
+      );
+   }
+
+   /// Returns a deduplicator of a track, a clip cut from its middle and an unrelated track.
+   fn deduplicator() -> Deduplicator {
+      let fingerprinter = Fingerprinter::with_config(FingerprintConfig::music()).unwrap();
+      let track = testing::melody(SAMPLE_RATE, 30 * SAMPLE_RATE, 1);
+      let clip = track[10 * SAMPLE_RATE..20 * SAMPLE_RATE].to_vec();
+      let unrelated = testing::melody(SAMPLE_RATE, 30 * SAMPLE_RATE, 2);
+
+      let mut deduplicator = Deduplicator::new(fingerprinter.wavelets_per_second());
+      for (track, audio) in [track, clip, unrelated].iter().enumerate() {
+         deduplicator.insert(track as u32, fingerprinter.fingerprint(audio));
+      }
+      deduplicator
+   }
+
+   #[test]
+   fn flags_a_clip_cut_from_a_track() {
+      let overlaps = deduplicator().overlaps();
+      match &overlaps[..] {
+         [overlap] => {
+            assert_eq!((overlap.first, overlap.second), (0, 1));
+            assert_close(overlap.first_range, (10.0, 20.0));
+            assert_close(overlap.second_range, (0.0, 10.0));
+            assert!(overlap.similarity >= MIN_SIMILARITY, "{:?}", overlap);
+         }
+         overlaps => panic!("{:?}", overlaps),
+      }
+   }
+
+   #[test]
+   fn clusters_the_overlapping_tracks() {
+      let clusters = deduplicator().clusters();
+      assert_eq!(clusters.len(), 1);
+      assert_eq!(clusters[0].tracks, [0, 1]);
+      assert_eq!(clusters[0].overlaps.len(), 1);
+   }
+
+   #[test]
+   fn respects_the_min_similarity() {
+      let mut deduplicator = deduplicator();
+      let similarity = deduplicator.overlaps()[0].similarity;
+
+      deduplicator.set_min_similarity(similarity);
+      assert_eq!(deduplicator.overlaps().len(), 1);
+      deduplicator.set_min_similarity(similarity + 0.01);
+      assert!(deduplicator.overlaps().is_empty());
+      assert!(deduplicator.clusters().is_empty());
+   }
+
+   #[test]
+   fn writes_json() {
+      let cluster = Cluster {
+         tracks: vec![0, 1],
+         overlaps: vec![Overlap {
+            first: 0,
+            second: 1,
+            first_range: (10.0, 20.0),
+            second_range: (0.0, 10.0),
+            similarity: 0.25,
+         }],
+      };
+      let names = ["a.wav", "clip \"b\".wav"];
+      let json = to_json(&[cluster], |track| String::from(names[track as usize]));
+      assert_eq!(
+         json,
+         "[\n  {\n    \"tracks\": [\"a.wav\", \"clip \\\"b\\\".wav\"],\n    \"overlaps\": [\n      \
+          {\"first\": \"a.wav\", \"first_range\": [10.000, 20.000], \"second\": \"clip \\\"b\\\".wav\", \
+          \"second_range\": [0.000, 10.000], \"similarity\": 0.250}\n    ]\n  }\n]\n"
+      );
+      assert_eq!(to_json(&[], |_| String::new()), "[\n]\n");
+   }
+}
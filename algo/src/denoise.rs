@@ -0,0 +1,247 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{Sample, Wavelet};
+
+/// Amount by which the noise estimate is over subtracted.
+const OVER_SUBTRACTION: f64 = 2.0;
+/// Lowest gain that is applied to a bin, avoids musical noise.
+const GAIN_FLOOR: f64 = 0.05;
+/// A wavelet is considered silent, if its energy is within this factor of the quietest wavelet.
+const SILENCE_RATIO: f64 = 2.0;
+/// Rate at which silent wavelets are blended into the noise profile.
+const ADAPTATION_RATE: f64 = 0.05;
+/// Rate at which the quietest energy drifts upwards, such that the silence detection
+/// can follow a noise floor that gets louder over time.
+const MIN_ENERGY_DRIFT: f64 = 1.001;
+/// Wavelets with less energy are digital silence, like muted input or zero padding,
+/// and tell nothing about the noise.
+const SILENCE_ENERGY: f64 = 1e-12;
+
+/// The denoiser attenuates stationary background noise in the wavelets of a frequencer.
+///
+/// It learns a noise profile, i.e. the average amplitude per frequency bin,
+/// from the first `learn_frames` wavelets. Afterwards, the profile is updated
+/// from wavelets that are detected to be silence. Digitally silent wavelets are ignored,
+/// such that muted input neither counts as the noise profile nor stops its adaptation.
+///
+/// # Algorithm
+/// For every bin, the noise power is over subtracted from the power of the bin to get an
+/// estimate of the signal to noise ratio. The bin is then scaled by the Wiener gain
+/// `snr / (1 + snr)`, which is bounded from below by a floor.
+pub struct Denoiser<T = f64> {
+   learn_frames: usize,
+   frames: usize,
+   min_energy: T,
+   noise: Vec<T>,
+}
+
+impl<T: Sample> Denoiser<T> {
+   pub fn new(block_size: usize, learn_frames: usize) -> Self {
+      Self {
+         learn_frames,
+         frames: 0,
+         min_energy: T::infinity(),
+         // We only use half the blocksize
+         noise: vec![T::zero(); block_size / 2],
+      }
+   }
+
+   /// Returns the current noise profile, one amplitude per frequency bin.
+   pub fn noise_profile(&self) -> &[T] {
+      &self.noise
+   }
+
+   /// Returns true, once the initial noise profile has been learned.
+   pub fn is_trained(&self) -> bool {
+      self.frames >= self.learn_frames
+   }
+
+   /// Forgets the learned noise profile.
+   pub fn reset(&mut self) {
+      self.frames = 0;
+      self.min_energy = T::infinity();
+      self.noise.iter_mut().for_each(|noise| *noise = T::zero());
+   }
+
+   /// Updates the noise profile and attenuates the noise in the wavelet.
+   pub fn process(&mut self, wavelet: &mut Wavelet<T>) {
+      self.update(wavelet);
+      self.apply(wavelet);
+   }
+
+   /// Updates the noise profile with a wavelet, without changing the wavelet.
+   pub fn update(&mut self, wavelet: &Wavelet<T>) {
+      assert_eq!(wavelet.bins.len(), self.noise.len());
+
+      let energy = wavelet
+         .bins
+         .iter()
+         .map(|bin| bin.amplitude * bin.amplitude)
+         .sum::<T>();
+      if energy <= T::cast(SILENCE_ENERGY) {
+         return;
+      }
+
+      if !self.is_trained() {
+         // Build up the running mean over the learning frames
+         let n = T::cast(self.frames as f64);
+         for (noise, bin) in self.noise.iter_mut().zip(wavelet.bins.iter()) {
+            *noise = (*noise * n + bin.amplitude) / (n + T::one());
+         }
+         self.frames += 1;
+      } else if energy <= self.min_energy * T::cast(SILENCE_RATIO) {
+         // Silent wavelet, blend it into the profile
+         let rate = T::cast(ADAPTATION_RATE);
+         for (noise, bin) in self.noise.iter_mut().zip(wavelet.bins.iter()) {
+            *noise = *noise + rate * (bin.amplitude - *noise);
+         }
+      }
+
+      // Only audible wavelets get here, so the quietest energy stays above the silence energy
+      self.min_energy = T::min(self.min_energy * T::cast(MIN_ENERGY_DRIFT), energy);
+   }
+
+   /// Attenuates the wavelet using the current noise profile.
+   pub fn apply(&self, wavelet: &mut Wavelet<T>) {
+      assert_eq!(wavelet.bins.len(), self.noise.len());
+
+      for (noise, bin) in self.noise.iter().zip(wavelet.bins.iter_mut()) {
+         let noise_power = *noise * *noise;
+         if noise_power <= T::zero() {
+            continue;
+         }
+
+         let snr = bin.amplitude * bin.amplitude / noise_power - T::cast(OVER_SUBTRACTION);
+         let gain = if snr > T::zero() {
+            snr / (T::one() + snr)
+         } else {
+            T::zero()
+         };
+
+         bin.amplitude = bin.amplitude * T::max(gain, T::cast(GAIN_FLOOR));
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{frequencer::Frequencer, testing, FrequencyBin};
+
+   const BLOCK_SIZE: usize = 256;
+
+   fn wavelet(amplitudes: &[f64]) -> Wavelet {
+      Wavelet {
+         bins: amplitudes
+            .iter()
+            .map(|&amplitude| FrequencyBin {
+               amplitude,
+               frequency: 0.0,
+            })
+            .collect(),
+      }
+   }
+
+   fn flat(amplitude: f64) -> Wavelet {
+      wavelet(&[amplitude; BLOCK_SIZE / 2])
+   }
+
+   #[test]
+   fn silence_is_not_learned_as_noise() {
+      let mut denoiser = Denoiser::new(BLOCK_SIZE, 4);
+      for _ in 0..10 {
+         denoiser.process(&mut flat(0.0));
+      }
+      assert!(!denoiser.is_trained());
+
+      for _ in 0..4 {
+         denoiser.process(&mut flat(1.0));
+      }
+      assert!(denoiser.is_trained());
+      assert!(denoiser.noise_profile().iter().all(|noise| *noise == 1.0));
+
+      // The noise is still attenuated
+      let mut noisy = flat(1.0);
+      denoiser.apply(&mut noisy);
+      assert!(noisy.bins.iter().all(|bin| bin.amplitude <= GAIN_FLOOR));
+   }
+
+   #[test]
+   fn silence_does_not_stop_adaptation() {
+      let mut denoiser = Denoiser::new(BLOCK_SIZE, 4);
+      for _ in 0..4 {
+         denoiser.update(&flat(1.0));
+      }
+      for _ in 0..10 {
+         denoiser.update(&flat(0.0));
+      }
+
+      // A quieter noise floor is still detected as silence and blended into the profile
+      for _ in 0..200 {
+         denoiser.update(&flat(0.8));
+      }
+      let noise = denoiser.noise_profile()[0];
+      assert!(
+         (noise - 0.8).abs() < 0.01,
+         "noise profile stuck at {}",
+         noise
+      );
+   }
+
+   #[test]
+   fn stationary_noise_is_attenuated() {
+      let sample_rate = 8192;
+      let step_size = BLOCK_SIZE / 4;
+      let mut frequencer = Frequencer::new(sample_rate, BLOCK_SIZE, step_size).unwrap();
+      let mut denoiser = Denoiser::new(BLOCK_SIZE, 50);
+
+      // Learn the profile from noise only
+      let noise = testing::noise(sample_rate * 2, 1);
+      for step in noise[..50 * step_size].chunks_exact(step_size) {
+         denoiser.update(&frequencer.feed_audio(step));
+      }
+      assert!(denoiser.is_trained());
+
+      // A tone at bin 32 in the same noise
+      let tone = testing::sine(
+         32.0 * sample_rate as f64 / BLOCK_SIZE as f64,
+         sample_rate,
+         noise.len(),
+      );
+      let (mut noise_before, mut noise_after, mut tone_before, mut tone_after) =
+         (0.0, 0.0, 0.0, 0.0);
+      for (i, step) in noise.chunks_exact(step_size).enumerate().skip(60) {
+         let audio = step
+            .iter()
+            .zip(&tone[i * step_size..])
+            .map(|(noise, tone)| noise * 0.5 + tone)
+            .collect::<Vec<_>>();
+         let mut wavelet = frequencer.feed_audio(&audio);
+         let before = wavelet.clone();
+         denoiser.apply(&mut wavelet);
+
+         for (k, (before, after)) in before.bins.iter().zip(wavelet.bins.iter()).enumerate() {
+            if (30..=34).contains(&k) {
+               tone_before += before.amplitude;
+               tone_after += after.amplitude;
+            } else {
+               noise_before += before.amplitude;
+               noise_after += after.amplitude;
+            }
+         }
+      }
+
+      assert!(
+         noise_after < 0.2 * noise_before,
+         "noise only reduced from {} to {}",
+         noise_before,
+         noise_after
+      );
+      assert!(
+         tone_after > 0.8 * tone_before,
+         "tone reduced from {} to {}",
+         tone_before,
+         tone_after
+      );
+   }
+}
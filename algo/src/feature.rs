@@ -1,4 +1,5 @@
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use num_traits::Zero;
 
 use crate::{FrequencyFeature, Wavelet};
 
@@ -12,22 +13,25 @@ use crate::{FrequencyFeature, Wavelet};
 /// The algorim keeps a slice of 2 * t_span of the spectogram.
 /// For every frequency bin, it keeps a maximum value of the last 2 * t_span wavelets.
 /// Then, it checks for maximas in the time domain at t_span.
-pub struct FeatureFinder {
+///
+/// Besides the floating point samples, it also works on the integer amplitudes
+/// of the [`FixedFrequencer`](crate::fixed::FixedFrequencer).
+pub struct FeatureFinder<T = f64> {
    block_size: usize,
    t_span: usize,
    //f_span: f64,
    time: usize,
-   max_vals: Vec<FrequencyFeature>,
-   val_window: Vec<VecDeque<FrequencyFeature>>,
+   max_vals: Vec<FrequencyFeature<T>>,
+   val_window: Vec<VecDeque<FrequencyFeature<T>>>,
 }
 
-impl FeatureFinder {
+impl<T: Copy + PartialOrd + Zero> FeatureFinder<T> {
    pub fn new(block_size: usize, t_span: usize) -> Self {
       let proto_feature = FrequencyFeature {
          time: 0,
          bin_index: 0,
-         frequency: 0.0,
-         amplitude: 0.0,
+         frequency: T::zero(),
+         amplitude: T::zero(),
       };
 
       let proto_line = VecDeque::from(vec![proto_feature.clone(); 2 * t_span]);
@@ -45,7 +49,7 @@ impl FeatureFinder {
       }
    }
 
-   pub fn process(&mut self, wavelet: Wavelet) -> Vec<FrequencyFeature> {
+   pub fn process(&mut self, wavelet: Wavelet<T>) -> Vec<FrequencyFeature<T>> {
       assert_eq!(wavelet.bins.len(), self.block_size);
       self.time += 1;
 
@@ -79,9 +83,14 @@ impl FeatureFinder {
       }
 
       // Now, we check, if each line has a local maximum at t_span
+      let peak_time = match self.time.checked_sub(self.t_span) {
+         Some(peak_time) => peak_time,
+         // The first wavelets are not t_span old yet
+         None => return vec![],
+      };
       let mut found_features = vec![];
       for val in self.max_vals.iter() {
-         if val.time == self.time - self.t_span {
+         if val.time == peak_time {
             // We have a local maximum in time.
             found_features.push(val.clone());
          }
@@ -90,7 +99,7 @@ impl FeatureFinder {
       found_features
    }
 
-   fn get_max_in_line(line: &VecDeque<FrequencyFeature>) -> &FrequencyFeature {
+   fn get_max_in_line(line: &VecDeque<FrequencyFeature<T>>) -> &FrequencyFeature<T> {
       let mut max = &line[0];
 
       for elem in line.iter() {
@@ -102,3 +111,82 @@ impl FeatureFinder {
       max
    }
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::FrequencyBin;
+
+   const BLOCK_SIZE: usize = 16;
+   const T_SPAN: usize = 3;
+
+   /// A wavelet with a small amplitude everywhere, but the given peaks.
+   fn wavelet(peaks: &[(usize, f64)]) -> Wavelet<f64> {
+      let mut wavelet = Wavelet {
+         bins: (0..BLOCK_SIZE / 2)
+            .map(|k| FrequencyBin {
+               amplitude: 0.1,
+               frequency: k as f64,
+            })
+            .collect(),
+      };
+      for (bin, amplitude) in peaks {
+         wavelet.bins[*bin].amplitude = *amplitude;
+      }
+      wavelet
+   }
+
+   #[test]
+   fn finds_peaks_once_they_are_t_span_old() {
+      let mut finder = FeatureFinder::new(BLOCK_SIZE, T_SPAN);
+      let mut found = Vec::new();
+      for time in 1..=20 {
+         let peaks = match time {
+            5 => vec![(2, 1.0), (6, 2.0)],
+            12 => vec![(2, 3.0)],
+            _ => vec![],
+         };
+         for feature in finder.process(wavelet(&peaks)) {
+            if feature.amplitude > 0.1 {
+               found.push((time, feature.time, feature.bin_index));
+            }
+         }
+      }
+
+      assert_eq!(
+         found,
+         vec![(5 + T_SPAN, 5, 2), (5 + T_SPAN, 5, 6), (12 + T_SPAN, 12, 2)]
+      );
+   }
+
+   #[test]
+   fn finds_nothing_before_t_span_wavelets() {
+      // Subtracting t_span from the time of the first wavelets used to overflow
+      let mut finder = FeatureFinder::new(BLOCK_SIZE, T_SPAN);
+      for _ in 0..T_SPAN - 1 {
+         assert!(finder.process(wavelet(&[(3, 1.0)])).is_empty());
+      }
+   }
+
+   #[test]
+   fn works_on_integer_amplitudes() {
+      let mut finder = FeatureFinder::<u32>::new(BLOCK_SIZE, T_SPAN);
+      let mut found = Vec::new();
+      for time in 1..=10 {
+         let bins = (0..BLOCK_SIZE / 2)
+            .map(|k| FrequencyBin {
+               amplitude: if time == 4 && k == 5 { 100 } else { 1 },
+               frequency: k as u32,
+            })
+            .collect();
+         found.extend(
+            finder
+               .process(Wavelet { bins })
+               .into_iter()
+               .filter(|feature| feature.amplitude == 100),
+         );
+      }
+      assert_eq!(found.len(), 1);
+      assert_eq!((found[0].time, found[0].bin_index), (4, 5));
+   }
+}
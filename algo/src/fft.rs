@@ -0,0 +1,193 @@
+use num_complex::Complex;
+
+use crate::Sample;
+
+#[cfg(feature = "std")]
+use alloc::sync::Arc;
+#[cfg(any(not(feature = "std"), test))]
+use alloc::vec::Vec;
+
+/// The numeric types the FFT backend is able to transform.
+#[cfg(feature = "std")]
+pub trait FftNum: rustfft::FFTnum {}
+
+#[cfg(feature = "std")]
+impl<T: rustfft::FFTnum> FftNum for T {}
+
+/// The numeric types the FFT backend is able to transform.
+#[cfg(not(feature = "std"))]
+pub trait FftNum {}
+
+#[cfg(not(feature = "std"))]
+impl<T> FftNum for T {}
+
+/// A forward FFT of a fixed, power of two length.
+///
+/// With the `std` feature, the transformation is done by rustfft.
+/// Otherwise, an iterative radix-2 FFT is used, which only needs `alloc`.
+pub struct Fft<T> {
+   #[cfg(feature = "std")]
+   fft: Arc<dyn rustfft::FFT<T>>,
+   #[cfg(not(feature = "std"))]
+   fft: Radix2Fft<T>,
+}
+
+impl<T: Sample> Fft<T> {
+   /// Plans the FFT, failing if the length isn't a power of two.
+   pub fn new(len: usize) -> Result<Self, ()> {
+      if !len.is_power_of_two() {
+         return Err(());
+      }
+
+      Ok(Self {
+         #[cfg(feature = "std")]
+         fft: rustfft::FFTplanner::new(false).plan_fft(len),
+         #[cfg(not(feature = "std"))]
+         fft: Radix2Fft::new(len),
+      })
+   }
+
+   /// Transforms `input` into `output`.
+   /// The content of `input` is undefined afterwards.
+   pub fn process(&self, input: &mut [Complex<T>], output: &mut [Complex<T>]) {
+      self.fft.process(input, output);
+   }
+}
+
+/// The iterative radix-2 FFT used without the `std` feature.
+#[cfg(any(not(feature = "std"), test))]
+struct Radix2Fft<T> {
+   len: usize,
+   twiddles: Vec<Complex<T>>,
+}
+
+#[cfg(any(not(feature = "std"), test))]
+impl<T: Sample> Radix2Fft<T> {
+   fn new(len: usize) -> Self {
+      debug_assert!(len.is_power_of_two());
+
+      Self {
+         len,
+         twiddles: (0..len / 2)
+            .map(|k| {
+               let angle = -T::cast(2.0 * core::f64::consts::PI * k as f64 / len as f64);
+               Complex::new(angle.cos(), angle.sin())
+            })
+            .collect(),
+      }
+   }
+
+   fn process(&self, input: &mut [Complex<T>], output: &mut [Complex<T>]) {
+      assert_eq!(input.len(), self.len);
+      assert_eq!(output.len(), self.len);
+
+      // Copy the input in bit reversed order
+      let bits = self.len.trailing_zeros();
+      for (k, x) in input.iter().enumerate() {
+         let index = if bits == 0 {
+            0
+         } else {
+            k.reverse_bits() >> (usize::BITS - bits)
+         };
+         output[index] = *x;
+      }
+
+      // Combine the butterflies, doubling their size in each pass
+      let mut size = 2;
+      while size <= self.len {
+         let half = size / 2;
+         let stride = self.len / size;
+
+         for start in (0..self.len).step_by(size) {
+            for k in 0..half {
+               let twiddle = self.twiddles[k * stride];
+               let even = output[start + k];
+               let odd = output[start + k + half] * twiddle;
+
+               output[start + k] = even + odd;
+               output[start + k + half] = even - odd;
+            }
+         }
+
+         size *= 2;
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing;
+   use num_traits::Zero;
+
+   /// Largest block size accepted by the transport format, see [`crate::transport`].
+   const MAX_LEN: usize = 1 << 16;
+
+   /// Asserts, that both backends compute the same spectrum, relative to its largest value.
+   fn assert_backends_agree(input: &[Complex<f64>]) {
+      let len = input.len();
+      let (mut rustfft_output, mut radix2_output) =
+         (vec![Complex::zero(); len], vec![Complex::zero(); len]);
+      Fft::new(len)
+         .unwrap()
+         .process(&mut input.to_vec(), &mut rustfft_output);
+      Radix2Fft::new(len).process(&mut input.to_vec(), &mut radix2_output);
+
+      let scale = rustfft_output.iter().map(|x| x.norm()).fold(1.0, f64::max);
+      for (k, (a, b)) in rustfft_output.iter().zip(radix2_output.iter()).enumerate() {
+         assert!(
+            (a - b).norm() < 1e-9 * scale,
+            "bin {} of {} differs: {} != {}",
+            k,
+            len,
+            a,
+            b
+         );
+      }
+   }
+
+   #[test]
+   fn radix2_agrees_with_rustfft_on_noise() {
+      // Every block size a fingerprint configuration accepts, i.e. step size < block size
+      let mut len = 2;
+      while len <= MAX_LEN {
+         let noise = testing::noise(2 * len, len as u64);
+         let input = noise
+            .chunks_exact(2)
+            .map(|x| Complex::new(x[0], x[1]))
+            .collect::<Vec<_>>();
+         assert_backends_agree(&input);
+         len *= 2;
+      }
+   }
+
+   #[test]
+   fn radix2_agrees_with_rustfft_on_impulses() {
+      for len in [1, 2, 4, 64, 1024, 4096] {
+         for position in [0, len / 2, len - 1] {
+            let mut input = vec![Complex::zero(); len];
+            input[position] = Complex::new(1.0, 0.0);
+            assert_backends_agree(&input);
+         }
+      }
+   }
+
+   #[test]
+   fn impulse_has_a_flat_spectrum() {
+      let mut input = vec![Complex::zero(); 8];
+      input[0] = Complex::new(1.0, 0.0);
+      let mut output = vec![Complex::zero(); 8];
+      Radix2Fft::new(8).process(&mut input, &mut output);
+      assert!(output
+         .iter()
+         .all(|x| (x - Complex::new(1.0, 0.0)).norm() < 1e-12));
+   }
+
+   #[test]
+   fn rejects_lengths_that_are_no_power_of_two() {
+      for len in [0, 3, 6, 100, 1000, 4095] {
+         assert!(Fft::<f64>::new(len).is_err());
+      }
+      assert!(Fft::<f32>::new(4096).is_ok());
+   }
+}
@@ -0,0 +1,180 @@
+use alloc::vec::Vec;
+
+use crate::{
+   config::FingerprintConfig,
+   feature::FeatureFinder,
+   frequencer::Frequencer,
+   hash::{PeakHash, PeakHasher, TripletHasher},
+   subfingerprint::SubFingerprinter,
+   Sample,
+};
+
+/// The id of the hashing algorithm, which is part of the [`FingerprintConfig::id`].
+/// It has to change with the algorithm, such that hashes of different algorithms are never compared.
+pub const ALGORITHM_ID: u8 = 1;
+
+/// The fingerprinter computes the hashes of a whole recording at once,
+/// running the frequencer, the feature finder and the peak hasher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
+pub struct Fingerprinter {
+   config: FingerprintConfig,
+}
+
+impl Fingerprinter {
+   /// Creates a fingerprinter with the default hashing parameters.
+   pub fn new(
+      sample_rate: usize,
+      block_size: usize,
+      step_size: usize,
+      t_span: usize,
+   ) -> Result<Self, ()> {
+      Self::with_config(FingerprintConfig {
+         sample_rate,
+         block_size,
+         step_size,
+         t_span,
+         ..FingerprintConfig::default()
+      })
+   }
+
+   /// Creates a fingerprinter, failing if the configuration isn't valid.
+   pub fn with_config(config: FingerprintConfig) -> Result<Self, ()> {
+      config.validate()?;
+      Ok(Self { config })
+   }
+
+   pub fn config(&self) -> &FingerprintConfig {
+      &self.config
+   }
+
+   pub fn sample_rate(&self) -> usize {
+      self.config.sample_rate
+   }
+
+   pub fn block_size(&self) -> usize {
+      self.config.block_size
+   }
+
+   pub fn step_size(&self) -> usize {
+      self.config.step_size
+   }
+
+   pub fn t_span(&self) -> usize {
+      self.config.t_span
+   }
+
+   /// Returns the number of wavelets per second, i.e. the unit of the hash times.
+   pub fn wavelets_per_second(&self) -> f64 {
+      self.config.sample_rate as f64 / self.config.step_size as f64
+   }
+
+   /// Creates a stream, that computes the hashes of audio arriving in pieces.
+   pub fn stream<T: Sample>(&self) -> FingerprintStream<T> {
+      let (frequencer, feature_finder, hasher) = self.stages();
+      FingerprintStream {
+         frequencer,
+         feature_finder,
+         hasher,
+         pending: Vec::with_capacity(self.config.step_size),
+      }
+   }
+
+   /// Computes the hashes of a recording. Samples, that don't fill a whole step, are ignored.
+   pub fn fingerprint<T: Sample>(&self, audio: &[T]) -> Vec<PeakHash> {
+      let (mut frequencer, mut feature_finder, mut hasher) = self.stages();
+
+      let mut hashes = Vec::new();
+      for step in audio.chunks_exact(self.config.step_size) {
+         let features = feature_finder.process(frequencer.feed_audio(step));
+         hashes.extend(hasher.process(&features));
+      }
+      hashes.extend(hasher.flush());
+      hashes
+   }
+
+   /// Computes the speed invariant hashes of a recording, see [`TripletHasher`].
+   /// Fails, if the fan out is too small to form triplets.
+   pub fn fingerprint_triplets<T: Sample>(&self, audio: &[T]) -> Result<Vec<PeakHash>, ()> {
+      let config = &self.config;
+      let mut hasher = TripletHasher::new(config.fan_out, config.min_dt, config.max_dt)?;
+      let (mut frequencer, mut feature_finder, _) = self.stages();
+
+      let mut hashes = Vec::new();
+      for step in audio.chunks_exact(config.step_size) {
+         let features = feature_finder.process(frequencer.feed_audio(step));
+         hashes.extend(hasher.process(&features));
+      }
+      hashes.extend(hasher.flush());
+      Ok(hashes)
+   }
+
+   /// Computes the sub-fingerprints of a recording, one for every wavelet but the first,
+   /// see [`SubFingerprinter`]. Fails, if the frames are too short to resolve its bands.
+   pub fn sub_fingerprints<T: Sample>(&self, audio: &[T]) -> Result<Vec<u32>, ()> {
+      let config = &self.config;
+      let mut sub_fingerprinter = SubFingerprinter::new(config.sample_rate, config.block_size)?;
+      let mut frequencer = Frequencer::new(config.sample_rate, config.block_size, config.step_size)
+         .expect("parameters were checked by the constructor");
+
+      Ok(audio
+         .chunks_exact(config.step_size)
+         .filter_map(|step| sub_fingerprinter.process(&frequencer.feed_audio(step)))
+         .collect())
+   }
+
+   fn stages<T: Sample>(&self) -> (Frequencer<T>, FeatureFinder<T>, PeakHasher) {
+      let config = &self.config;
+      (
+         Frequencer::new(config.sample_rate, config.block_size, config.step_size)
+            .expect("parameters were checked by the constructor"),
+         FeatureFinder::new(config.block_size, config.t_span),
+         PeakHasher::new(config.fan_out, config.min_dt, config.max_dt)
+            .expect("parameters were checked by the constructor"),
+      )
+   }
+}
+
+/// Validates the configuration like [`Fingerprinter::with_config`], such that a deserialized
+/// fingerprinter is always valid.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Fingerprinter {
+   fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+      Self::with_config(FingerprintConfig::deserialize(deserializer)?)
+         .map_err(|_| serde::de::Error::custom("invalid fingerprint parameters"))
+   }
+}
+
+/// Computes the hashes of a live recording, see [`Fingerprinter::stream`].
+/// The times of the hashes count the wavelets since the start of the stream.
+pub struct FingerprintStream<T = f64> {
+   frequencer: Frequencer<T>,
+   feature_finder: FeatureFinder<T>,
+   hasher: PeakHasher,
+   /// Audio, that doesn't fill a whole step yet.
+   pending: Vec<T>,
+}
+
+impl<T: Sample> FingerprintStream<T> {
+   /// Feeds the next samples and returns the hashes, that are complete.
+   pub fn feed(&mut self, audio: &[T]) -> Vec<PeakHash> {
+      let step_size = self.frequencer.step_size();
+
+      let mut hashes = Vec::new();
+      for sample in audio {
+         self.pending.push(*sample);
+         if self.pending.len() == step_size {
+            let wavelet = self.frequencer.feed_audio(&self.pending);
+            self.pending.clear();
+            let features = self.feature_finder.process(wavelet);
+            hashes.extend(self.hasher.process(&features));
+         }
+      }
+      hashes
+   }
+
+   /// Ends the stream and returns the remaining hashes.
+   pub fn finish(&mut self) -> Vec<PeakHash> {
+      self.hasher.flush()
+   }
+}
@@ -0,0 +1,339 @@
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use core::iter::FromIterator;
+use num_traits::Float;
+
+use crate::{FrequencyBin, FrequencyFeature, Sample, Wavelet};
+
+/// Number of fractional bits of the Q15 format.
+const Q: u32 = 15;
+/// The value `1.0` in Q15.
+const ONE: f64 = (1 << Q) as f64;
+/// Largest supported frame size.
+/// The FFT grows the values by up to `frame_size`, which needs to fit into the `i32` buffers
+/// alongside the 15 bits of the samples.
+const MAX_FRAME_SIZE: usize = 1 << 15;
+
+/// Integer only counterpart of the [`Frequencer`](crate::frequencer::Frequencer),
+/// meant for devices without a floating point unit.
+///
+/// It takes `i16` samples and outputs wavelets with `u32` bins, where the amplitude
+/// is in Q15, i.e. `amplitude / 2^15` is the amplitude of the floating point path
+/// for samples in `[-1, 1)`, and the frequency is the center frequency of the bin in Hz.
+/// Unlike the floating point path, the frequency is not refined by the phase difference.
+///
+/// The window and twiddle tables are computed once in [`FixedFrequencer::new`],
+/// processing itself only uses integer arithmetic.
+/// The resulting wavelets can be fed into a [`FeatureFinder<u32>`](crate::feature::FeatureFinder).
+pub struct FixedFrequencer {
+   sample_rate: usize,
+   frame_size: usize,
+   step_size: usize,
+   window: Vec<i32>,
+   twiddles: Vec<(i32, i32)>,
+   sample_buf: VecDeque<i16>,
+}
+
+impl FixedFrequencer {
+   pub fn new(sample_rate: usize, frame_size: usize, step_size: usize) -> Result<Self, ()> {
+      if !frame_size.is_power_of_two() || frame_size > MAX_FRAME_SIZE {
+         return Err(());
+      }
+
+      if step_size >= frame_size {
+         return Err(());
+      }
+
+      let pi = core::f64::consts::PI;
+      let window = (0..frame_size)
+         .map(|k| {
+            let window = -0.5 * Float::cos(2.0 * pi * k as f64 / frame_size as f64) + 0.5;
+            Float::round(window * (ONE - 1.0)) as i32
+         })
+         .collect();
+      let twiddles = (0..frame_size / 2)
+         .map(|k| {
+            let angle = -2.0 * pi * k as f64 / frame_size as f64;
+            (
+               Float::round(Float::cos(angle) * (ONE - 1.0)) as i32,
+               Float::round(Float::sin(angle) * (ONE - 1.0)) as i32,
+            )
+         })
+         .collect();
+
+      Ok(Self {
+         sample_rate,
+         frame_size,
+         step_size,
+         window,
+         twiddles,
+         sample_buf: VecDeque::from_iter(core::iter::repeat(0).take(frame_size)),
+      })
+   }
+
+   pub fn sample_rate(&self) -> usize {
+      self.sample_rate
+   }
+
+   pub fn step_size(&self) -> usize {
+      self.step_size
+   }
+
+   pub fn feed_audio(&mut self, audio: &[i16]) -> Wavelet<u32> {
+      // We can only accept slices that are exact step size long
+      assert_eq!(audio.len(), self.step_size);
+
+      // Add the new audio to the end of the buffer
+      self.sample_buf.extend(audio.iter());
+      self.sample_buf.drain(..self.step_size);
+      debug_assert_eq!(self.sample_buf.len(), self.frame_size);
+
+      // Apply the window and copy the frame in bit reversed order
+      let bits = self.frame_size.trailing_zeros();
+      let mut re = vec![0i32; self.frame_size];
+      let mut im = vec![0i32; self.frame_size];
+      for (k, (x, window)) in self.sample_buf.iter().zip(self.window.iter()).enumerate() {
+         let index = if bits == 0 {
+            0
+         } else {
+            k.reverse_bits() >> (usize::BITS - bits)
+         };
+         re[index] = mul_q15(*x as i32, *window);
+      }
+
+      // Radix-2 FFT, doubling the size of the butterflies in each pass
+      let mut size = 2;
+      while size <= self.frame_size {
+         let half = size / 2;
+         let stride = self.frame_size / size;
+
+         for start in (0..self.frame_size).step_by(size) {
+            for k in 0..half {
+               let (cos, sin) = self.twiddles[k * stride];
+               let (even, odd) = (start + k, start + k + half);
+
+               let odd_re = mul_q15(re[odd], cos) - mul_q15(im[odd], sin);
+               let odd_im = mul_q15(re[odd], sin) + mul_q15(im[odd], cos);
+
+               re[odd] = re[even] - odd_re;
+               im[odd] = im[even] - odd_im;
+               re[even] += odd_re;
+               im[even] += odd_im;
+            }
+         }
+
+         size *= 2;
+      }
+
+      // FFT is symetric, therefore we only need lower half
+      let bins = re
+         .iter()
+         .zip(im.iter())
+         .take(self.frame_size / 2)
+         .enumerate()
+         .map(|(k, (re, im))| {
+            let power = (*re as i64 * *re as i64 + *im as i64 * *im as i64) as u64;
+            FrequencyBin {
+               amplitude: isqrt(power) as u32,
+               frequency: (k * self.sample_rate / self.frame_size) as u32,
+            }
+         })
+         .collect();
+
+      Wavelet { bins }
+   }
+}
+
+/// Converts a feature found on the output of the [`FixedFrequencer`]
+/// into the floating point representation.
+pub fn to_float<T: Sample>(feature: &FrequencyFeature<u32>) -> FrequencyFeature<T> {
+   FrequencyFeature {
+      time: feature.time,
+      bin_index: feature.bin_index,
+      frequency: T::cast(feature.frequency as f64),
+      amplitude: T::cast(feature.amplitude as f64 / ONE),
+   }
+}
+
+/// Multiplies a value with a Q15 factor, rounding to nearest.
+fn mul_q15(value: i32, factor: i32) -> i32 {
+   ((value as i64 * factor as i64 + (1 << (Q - 1))) >> Q) as i32
+}
+
+/// Integer square root, rounded down.
+fn isqrt(value: u64) -> u64 {
+   if value < 2 {
+      return value;
+   }
+
+   // Newton iteration, starting above the root
+   let mut x = 1u64 << (64 - value.leading_zeros()).div_ceil(2);
+   loop {
+      let y = (x + value / x) / 2;
+      if y >= x {
+         return x;
+      }
+      x = y;
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{
+      config::FingerprintConfig, feature::FeatureFinder, frequencer::Frequencer, hash::PeakHasher,
+      testing,
+   };
+   use std::collections::BTreeSet;
+
+   const SAMPLE_RATE: usize = 8000;
+   const FRAME_SIZE: usize = 1024;
+   const STEP_SIZE: usize = 256;
+   /// Largest allowed difference of a bin between the fixed and the floating point path,
+   /// relative to the largest bin of the wavelet.
+   const MAX_RELATIVE_ERROR: f64 = 0.001;
+   /// Smallest share of the hashes of the floating point path, that the fixed path has to find.
+   const MIN_HASH_OVERLAP: f64 = 0.95;
+
+   /// A sine sweeping from 100 Hz to 3.5 kHz at half the full scale, over quiet noise.
+   /// Without the noise, most bins would only hold the leakage of the sine, a few steps of Q15
+   /// above zero, whose maxima in time the rounding of the fixed path moves around.
+   fn sweep(len: usize) -> Vec<f64> {
+      let noise = testing::noise(len, 1);
+      let (start, end) = (100.0, 3500.0);
+      let duration = len as f64 / SAMPLE_RATE as f64;
+      (0..len)
+         .map(|i| {
+            let t = i as f64 / SAMPLE_RATE as f64;
+            let phase =
+               2.0 * core::f64::consts::PI * (start * t + (end - start) * t * t / (2.0 * duration));
+            0.5 * phase.sin() + 0.01 * noise[i]
+         })
+         .collect()
+   }
+
+   fn argmax<T: PartialOrd + Copy>(wavelet: &Wavelet<T>) -> usize {
+      (0..wavelet.bins.len()).fold(0, |best, k| {
+         if wavelet.bins[k].amplitude > wavelet.bins[best].amplitude {
+            k
+         } else {
+            best
+         }
+      })
+   }
+
+   #[test]
+   fn agrees_with_the_float_frequencer_on_a_sweep() {
+      let mut fixed = FixedFrequencer::new(SAMPLE_RATE, FRAME_SIZE, STEP_SIZE).unwrap();
+      let mut float = Frequencer::<f64>::new(SAMPLE_RATE, FRAME_SIZE, STEP_SIZE).unwrap();
+      let config = FingerprintConfig::music();
+      let mut fixed_finder = FeatureFinder::<u32>::new(FRAME_SIZE, config.t_span);
+      let mut float_finder = FeatureFinder::<f64>::new(FRAME_SIZE, config.t_span);
+      let hasher = || PeakHasher::new(config.fan_out, config.min_dt, config.max_dt).unwrap();
+      let (mut fixed_hasher, mut float_hasher) = (hasher(), hasher());
+      let (mut fixed_hashes, mut float_hashes) = (BTreeSet::new(), BTreeSet::new());
+
+      let audio = sweep(4 * SAMPLE_RATE);
+      let mut last_peak = 0;
+      for (i, step) in audio.chunks_exact(STEP_SIZE).enumerate() {
+         let samples = step
+            .iter()
+            .map(|x| (x * ONE).round() as i16)
+            .collect::<Vec<_>>();
+         let fixed = fixed.feed_audio(&samples);
+         let float = float.feed_audio(step);
+
+         // The features of the fixed path are hashed like the ones of the floating point path
+         let features = fixed_finder
+            .process(fixed.clone())
+            .iter()
+            .map(to_float::<f64>)
+            .collect::<Vec<_>>();
+         fixed_hashes.extend(fixed_hasher.process(&features));
+         float_hashes.extend(float_hasher.process(&float_finder.process(float.clone())));
+
+         // Skip the frames, that still contain the zeros the buffers start with
+         if i < FRAME_SIZE / STEP_SIZE {
+            continue;
+         }
+
+         let peak = argmax(&float);
+         assert_eq!(argmax(&fixed), peak, "peaks differ in wavelet {}", i);
+         assert!(peak >= last_peak, "the sweep goes upwards");
+         last_peak = peak;
+
+         let scale = float.bins[peak].amplitude;
+         for (k, (fixed, float)) in fixed.bins.iter().zip(float.bins.iter()).enumerate() {
+            let error = (fixed.amplitude as f64 / ONE - float.amplitude).abs() / scale;
+            assert!(
+               error <= MAX_RELATIVE_ERROR,
+               "bin {} of wavelet {} is off by {}",
+               k,
+               i,
+               error
+            );
+         }
+      }
+      assert!(last_peak * SAMPLE_RATE / FRAME_SIZE > 3000);
+
+      fixed_hashes.extend(fixed_hasher.flush());
+      float_hashes.extend(float_hasher.flush());
+      let overlap = fixed_hashes.intersection(&float_hashes).count() as f64;
+      assert!(float_hashes.len() > 100, "{} hashes", float_hashes.len());
+      assert!(
+         overlap / float_hashes.len() as f64 >= MIN_HASH_OVERLAP,
+         "{} of {} hashes overlap",
+         overlap,
+         float_hashes.len()
+      );
+   }
+
+   #[test]
+   fn converts_features_to_float() {
+      let feature = FrequencyFeature {
+         time: 7,
+         bin_index: 42,
+         frequency: 328,
+         amplitude: 3 << (Q - 1),
+      };
+      let converted = to_float::<f64>(&feature);
+      assert_eq!(converted.time, 7);
+      assert_eq!(converted.bin_index, 42);
+      assert_eq!(converted.frequency, 328.0);
+      assert_eq!(converted.amplitude, 1.5);
+   }
+
+   #[test]
+   fn multiplies_and_takes_roots() {
+      assert_eq!(mul_q15(1000, 1 << (Q - 1)), 500);
+      assert_eq!(mul_q15(-1000, 1 << (Q - 1)), -500);
+      assert_eq!(
+         mul_q15(i16::MAX as i32, i16::MAX as i32),
+         i16::MAX as i32 - 1
+      );
+      for value in [
+         0,
+         1,
+         2,
+         3,
+         4,
+         15,
+         16,
+         17,
+         1 << 40,
+         u32::MAX as u64 * u32::MAX as u64,
+      ] {
+         let root = isqrt(value);
+         assert!(root * root <= value);
+         assert!((root + 1)
+            .checked_mul(root + 1)
+            .is_none_or(|square| square > value));
+      }
+   }
+
+   #[test]
+   fn rejects_invalid_sizes() {
+      assert!(FixedFrequencer::new(SAMPLE_RATE, 1000, 250).is_err());
+      assert!(FixedFrequencer::new(SAMPLE_RATE, 1024, 1024).is_err());
+      assert!(FixedFrequencer::new(SAMPLE_RATE, 1 << 16, 1024).is_err());
+   }
+}
@@ -1,23 +1,23 @@
-use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
-use core::{f64::consts::PI, iter::FromIterator};
-use num_complex::Complex64;
-use rustfft::{FFTplanner, FFT};
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use core::iter::FromIterator;
+use num_complex::Complex;
+use num_traits::{Float, Zero};
 
-use crate::{FrequencyBin, Wavelet};
+use crate::{fft::Fft, FrequencyBin, Sample, Wavelet};
 
-pub struct Frequencer {
+pub struct Frequencer<T = f64> {
    sample_rate: usize,
    frame_size: usize,
    step_size: usize,
-   freqs_per_bin: f64,
-   phase_diff_per_frame: f64,
-   oversampling_rate: f64,
-   sample_buf: VecDeque<f64>,
-   phase_buf: Vec<f64>,
-   fft: Arc<dyn FFT<f64>>,
+   freqs_per_bin: T,
+   phase_diff_per_frame: T,
+   oversampling_rate: T,
+   sample_buf: VecDeque<T>,
+   phase_buf: Vec<T>,
+   fft: Fft<T>,
 }
 
-impl Frequencer {
+impl<T: Sample> Frequencer<T> {
    pub fn new(sample_rate: usize, frame_size: usize, step_size: usize) -> Result<Self, ()> {
       if !frame_size.is_power_of_two() {
          return Err(());
@@ -31,12 +31,14 @@ impl Frequencer {
          sample_rate,
          frame_size,
          step_size,
-         freqs_per_bin: sample_rate as f64 / frame_size as f64,
-         phase_diff_per_frame: 2.0 * PI * step_size as f64 / frame_size as f64,
-         oversampling_rate: frame_size as f64 / step_size as f64,
-         sample_buf: VecDeque::from_iter(core::iter::repeat(0.0).take(frame_size)),
-         phase_buf: vec![0.0; frame_size],
-         fft: FFTplanner::new(false).plan_fft(frame_size),
+         freqs_per_bin: T::cast(sample_rate as f64 / frame_size as f64),
+         phase_diff_per_frame: T::cast(
+            2.0 * core::f64::consts::PI * step_size as f64 / frame_size as f64,
+         ),
+         oversampling_rate: T::cast(frame_size as f64 / step_size as f64),
+         sample_buf: VecDeque::from_iter(core::iter::repeat(T::zero()).take(frame_size)),
+         phase_buf: vec![T::zero(); frame_size],
+         fft: Fft::new(frame_size)?,
       })
    }
 
@@ -48,7 +50,7 @@ impl Frequencer {
       self.step_size
    }
 
-   pub fn feed_audio(&mut self, audio: &[f64]) -> Wavelet {
+   pub fn feed_audio(&mut self, audio: &[T]) -> Wavelet<T> {
       // We can only accept slices that are exact step size long
       assert_eq!(audio.len(), self.step_size);
 
@@ -57,29 +59,28 @@ impl Frequencer {
       self.sample_buf.drain(..self.step_size());
       debug_assert_eq!(self.sample_buf.len(), self.frame_size);
 
+      let pi = T::PI();
+      let two = T::cast(2.0);
+      let half = T::cast(0.5);
+      let frame_size = T::cast(self.frame_size as f64);
+
       let mut frame = self
          .sample_buf
          .iter()
          // apply windowing
          .enumerate()
          .map(|(k, x)| {
-            let window = -0.5 * f64::cos(2.0 * PI * k as f64 / self.frame_size as f64) + 0.5;
-            window * x
+            let window = -half * T::cos(two * pi * T::cast(k as f64) / frame_size) + half;
+            window * *x
          })
          // map to complex numbers
-         .map(|x| Complex64::new(x, 0.0))
+         .map(|x| Complex::new(x, T::zero()))
          .collect::<Vec<_>>();
 
       // do the actual transformation
-      //let fft1 = crate::fft::fft(&frame).unwrap();
-      use rustfft::num_traits::Zero;
-      let mut fft = vec![Complex64::zero(); self.frame_size];
+      let mut fft = vec![Complex::zero(); self.frame_size];
       self.fft.process(&mut frame, &mut fft);
 
-      // for i in 0..self.frame_size {
-      //    assert_eq!(fft1[i], fft[i]);
-      // }
-
       // transform
       let bins = fft
          .iter()
@@ -95,29 +96,29 @@ impl Frequencer {
             self.phase_buf[k] = phase_diff;
 
             // calculate difference to expected phase
-            phase_diff -= k as f64 * self.phase_diff_per_frame;
+            phase_diff = phase_diff - T::cast(k as f64) * self.phase_diff_per_frame;
 
-            let n = (f64::abs(phase_diff) / PI) as usize;
+            let n = T::trunc(Float::abs(phase_diff) / pi);
 
             // map back onto rad
-            if phase_diff > 0.0 {
-               phase_diff -= n as f64 * PI;
-               if phase_diff > PI {
-                  phase_diff = PI;
+            if phase_diff > T::zero() {
+               phase_diff = phase_diff - n * pi;
+               if phase_diff > pi {
+                  phase_diff = pi;
                }
             } else {
-               phase_diff += n as f64 * PI;
-               if phase_diff < -PI {
-                  phase_diff = -PI;
+               phase_diff = phase_diff + n * pi;
+               if phase_diff < -pi {
+                  phase_diff = -pi;
                }
             }
-            assert!(phase_diff <= PI && phase_diff >= -PI);
+            assert!(phase_diff <= pi && phase_diff >= -pi);
 
             // compute frequency deviation
-            let freq_dev = self.oversampling_rate * phase_diff / (2.0 * PI);
+            let freq_dev = self.oversampling_rate * phase_diff / (two * pi);
 
             // compute frequency
-            let freq = (k as f64 + freq_dev) * self.freqs_per_bin;
+            let freq = (T::cast(k as f64) + freq_dev) * self.freqs_per_bin;
 
             FrequencyBin {
                amplitude: amp,
@@ -129,3 +130,46 @@ impl Frequencer {
       Wavelet { bins }
    }
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing;
+
+   #[test]
+   fn finds_the_frequency_of_a_sine() {
+      let mut frequencer = Frequencer::<f64>::new(8000, 1024, 256).unwrap();
+      let audio = testing::sine(1000.0, 8000, 4096);
+
+      let wavelet = audio
+         .chunks_exact(256)
+         .map(|step| frequencer.feed_audio(step))
+         .last()
+         .unwrap();
+      assert_eq!(wavelet.bins.len(), 512);
+      // 1000 Hz is exactly bin 128
+      assert!((wavelet.base_freq() - 1000.0).abs() < 1.0);
+   }
+
+   #[test]
+   fn f32_agrees_with_f64() {
+      let mut frequencer64 = Frequencer::<f64>::new(8000, 512, 128).unwrap();
+      let mut frequencer32 = Frequencer::<f32>::new(8000, 512, 128).unwrap();
+      let audio = testing::noise(128 * 20, 7);
+
+      for step in audio.chunks_exact(128) {
+         let step32 = step.iter().map(|&x| x as f32).collect::<Vec<_>>();
+         let wavelet64 = frequencer64.feed_audio(step);
+         let wavelet32 = frequencer32.feed_audio(&step32);
+
+         let peak = wavelet64
+            .bins
+            .iter()
+            .map(|bin| bin.amplitude)
+            .fold(0.0, f64::max);
+         for (bin64, bin32) in wavelet64.bins.iter().zip(wavelet32.bins.iter()) {
+            assert!((bin64.amplitude - bin32.amplitude as f64).abs() < 1e-4 * peak);
+         }
+      }
+   }
+}
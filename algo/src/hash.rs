@@ -0,0 +1,379 @@
+use alloc::{collections::VecDeque, vec::Vec};
+
+#[cfg(not(feature = "std"))]
+use num_traits::Float;
+
+use crate::{FrequencyFeature, Sample};
+
+/// Number of bits used for the bin index of each feature in the hash.
+const BIN_BITS: u32 = 11;
+/// Number of bits used for the time difference in the hash.
+const DT_BITS: u32 = 10;
+/// Number of bits used for each frequency ratio in a triplet hash.
+const RATIO_BITS: u32 = 11;
+/// Number of steps per octave, that frequency ratios are quantized to.
+const RATIO_STEPS_PER_OCTAVE: f64 = 16.0;
+/// Number of bits used for the time ratio in a triplet hash.
+const TIME_RATIO_BITS: u32 = 10;
+/// Number of steps, that time ratios are quantized to.
+/// The time differences are whole wavelets, so finer steps would split the ratios
+/// of a query played at another speed from the ones of the track.
+const TIME_RATIO_STEPS: f64 = 16.0;
+/// How many times stronger than the median feature of its wavelet a feature has to be,
+/// to take part in a triplet hash.
+const MIN_PROMINENCE: f64 = 20.0;
+
+/// The hash of a group of features, located at the time of the first feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeakHash {
+   pub hash: u32,
+   pub time: u32,
+   /// The time between the first and the last feature of the hash, in wavelets.
+   /// Comparing the spans of a query and a track reveals a change in speed.
+   pub span: u32,
+}
+
+/// The peak hasher combines the features of the feature finder into hashes.
+///
+/// # Algorithm
+/// Every feature is used as an anchor and paired with up to `fan_out` of the features
+/// following it within a target zone of `min_dt` to `max_dt` wavelets.
+/// A hash consists of the bin index of the anchor, the bin index of the other feature
+/// and the time difference between them. Since the features arrive in order of time,
+/// the hashes of an anchor are emitted as soon as its target zone has passed.
+pub struct PeakHasher {
+   zone: TargetZone,
+}
+
+impl PeakHasher {
+   pub fn new(fan_out: usize, min_dt: usize, max_dt: usize) -> Result<Self, ()> {
+      Ok(Self {
+         zone: TargetZone::new(fan_out, min_dt, max_dt)?,
+      })
+   }
+
+   /// Takes the features found in one step of the feature finder and
+   /// returns the hashes that are complete.
+   pub fn process<T>(&mut self, features: &[FrequencyFeature<T>]) -> Vec<PeakHash> {
+      self.zone.push(features);
+
+      let mut hashes = Vec::new();
+      while let Some((anchor, targets)) = self.zone.next_anchor(false) {
+         PeakHasher::hash_anchor(anchor, &targets, &mut hashes);
+      }
+      hashes
+   }
+
+   /// Returns the hashes of all remaining features, for example at the end of a file.
+   pub fn flush(&mut self) -> Vec<PeakHash> {
+      let mut hashes = Vec::new();
+      while let Some((anchor, targets)) = self.zone.next_anchor(true) {
+         PeakHasher::hash_anchor(anchor, &targets, &mut hashes);
+      }
+      hashes
+   }
+
+   fn hash_anchor((time, bin): Peak, targets: &[Peak], hashes: &mut Vec<PeakHash>) {
+      for (target_time, target_bin) in targets {
+         hashes.push(PeakHash {
+            hash: pair_hash(bin, *target_bin, target_time - time),
+            time: time as u32,
+            span: (target_time - time) as u32,
+         });
+      }
+   }
+}
+
+/// The triplet hasher combines the features of the feature finder into hashes,
+/// that are invariant to changes in speed, i.e. when the audio is played faster or slower.
+///
+/// # Algorithm
+/// Only the strongest feature of every wavelet is kept, if it stands out from the other
+/// features of the wavelet, such that the targets of an anchor lie at different times and
+/// are salient enough to be found again at another speed. Every such feature is used as an
+/// anchor and combined with every two of the up to `fan_out` features in its target zone.
+/// Changing the speed scales the frequencies and the time differences of all three features
+/// by the same factor. Therefore, the hash consists of the ratios of the frequencies of the
+/// two targets to the anchor, quantized on a logarithmic scale, and the coarsely quantized
+/// ratio of the time differences of the two targets to the anchor.
+/// The span of the hash is the time difference of the last target, which allows to
+/// estimate the speed factor when matching, see [`Index::query_scaled`](crate::index::Index::query_scaled).
+pub struct TripletHasher {
+   zone: TargetZone,
+}
+
+impl TripletHasher {
+   pub fn new(fan_out: usize, min_dt: usize, max_dt: usize) -> Result<Self, ()> {
+      if fan_out < 2 {
+         return Err(());
+      }
+
+      Ok(Self {
+         zone: TargetZone::new(fan_out, min_dt, max_dt)?,
+      })
+   }
+
+   /// Takes the features found in one step of the feature finder and
+   /// returns the hashes that are complete.
+   pub fn process<T: Sample>(&mut self, features: &[FrequencyFeature<T>]) -> Vec<PeakHash> {
+      let mut prominent = Vec::new();
+      let mut start = 0;
+      while start < features.len() {
+         let time = features[start].time;
+         let len = features[start..]
+            .iter()
+            .take_while(|feature| feature.time == time)
+            .count();
+
+         // Compared as f64, whose total order doesn't panic on NaN amplitudes
+         let mut amplitudes = features[start..start + len]
+            .iter()
+            .map(|feature| feature.amplitude.to_f64().unwrap_or(f64::NAN))
+            .collect::<Vec<_>>();
+         amplitudes.sort_by(f64::total_cmp);
+         let median = amplitudes[len / 2];
+         let strongest =
+            features[start..start + len]
+               .iter()
+               .fold(&features[start], |best, feature| {
+                  if feature.amplitude > best.amplitude {
+                     feature
+                  } else {
+                     best
+                  }
+               });
+         if strongest.amplitude >= T::cast(MIN_PROMINENCE * median) {
+            prominent.push(strongest);
+         }
+         start += len;
+      }
+      self.zone.push(prominent);
+
+      let mut hashes = Vec::new();
+      while let Some((anchor, targets)) = self.zone.next_anchor(false) {
+         TripletHasher::hash_anchor(anchor, &targets, &mut hashes);
+      }
+      hashes
+   }
+
+   /// Returns the hashes of all remaining features, for example at the end of a file.
+   pub fn flush(&mut self) -> Vec<PeakHash> {
+      let mut hashes = Vec::new();
+      while let Some((anchor, targets)) = self.zone.next_anchor(true) {
+         TripletHasher::hash_anchor(anchor, &targets, &mut hashes);
+      }
+      hashes
+   }
+
+   fn hash_anchor((time, bin): Peak, targets: &[Peak], hashes: &mut Vec<PeakHash>) {
+      // The DC bin has no meaningful ratio
+      if bin == 0 {
+         return;
+      }
+
+      for (i, (first_time, first_bin)) in targets.iter().enumerate() {
+         for (second_time, second_bin) in targets[i + 1..].iter() {
+            if first_time == second_time || *first_bin == 0 || *second_bin == 0 {
+               continue;
+            }
+
+            hashes.push(PeakHash {
+               hash: triplet_hash(
+                  *first_bin as f64 / bin as f64,
+                  *second_bin as f64 / bin as f64,
+                  (first_time - time) as f64 / (second_time - time) as f64,
+               ),
+               time: time as u32,
+               span: (second_time - time) as u32,
+            });
+         }
+      }
+   }
+}
+
+/// Computes the hash of two bin indices and their time difference.
+pub fn pair_hash(anchor_bin: usize, target_bin: usize, dt: usize) -> u32 {
+   let bin_mask = (1 << BIN_BITS) - 1;
+   let dt_mask = (1 << DT_BITS) - 1;
+
+   ((anchor_bin as u32 & bin_mask) << (BIN_BITS + DT_BITS))
+      | ((target_bin as u32 & bin_mask) << DT_BITS)
+      | (dt as u32 & dt_mask)
+}
+
+/// Computes the hash of two frequency ratios and a time ratio in `(0, 1)`.
+pub fn triplet_hash(first_ratio: f64, second_ratio: f64, time_ratio: f64) -> u32 {
+   let quantize_ratio = |ratio: f64| {
+      let max = (1 << RATIO_BITS) - 1;
+      let step = (ratio.log2() * RATIO_STEPS_PER_OCTAVE).round() as i64 + (max + 1) / 2;
+      step.clamp(0, max) as u32
+   };
+   let time_max = (1 << TIME_RATIO_BITS) - 1;
+   let time_step = ((time_ratio * TIME_RATIO_STEPS).round() as u32).min(time_max);
+
+   (quantize_ratio(first_ratio) << (RATIO_BITS + TIME_RATIO_BITS))
+      | (quantize_ratio(second_ratio) << TIME_RATIO_BITS)
+      | time_step
+}
+
+/// The time and the bin index of a feature.
+type Peak = (usize, usize);
+
+/// Keeps track of the features, that are waiting for their target zone to pass.
+struct TargetZone {
+   fan_out: usize,
+   min_dt: usize,
+   max_dt: usize,
+   latest: usize,
+   pending: VecDeque<Peak>,
+}
+
+impl TargetZone {
+   fn new(fan_out: usize, min_dt: usize, max_dt: usize) -> Result<Self, ()> {
+      if fan_out == 0 || min_dt == 0 || min_dt > max_dt || max_dt >= 1 << DT_BITS {
+         return Err(());
+      }
+
+      Ok(Self {
+         fan_out,
+         min_dt,
+         max_dt,
+         latest: 0,
+         pending: VecDeque::new(),
+      })
+   }
+
+   fn push<'a, T: 'a>(&mut self, features: impl IntoIterator<Item = &'a FrequencyFeature<T>>) {
+      for feature in features {
+         debug_assert!(feature.time >= self.latest);
+         self.latest = feature.time;
+         self.pending.push_back((feature.time, feature.bin_index));
+      }
+   }
+
+   /// Removes the first pending feature and returns it together with its targets,
+   /// if its target zone has passed or `flush` is set.
+   fn next_anchor(&mut self, flush: bool) -> Option<(Peak, Vec<Peak>)> {
+      let (time, _) = *self.pending.front()?;
+      if !flush && time + self.max_dt > self.latest {
+         return None;
+      }
+
+      let anchor = self.pending.pop_front().unwrap();
+      let targets = self
+         .pending
+         .iter()
+         .skip_while(|(target_time, _)| *target_time < time + self.min_dt)
+         .take_while(|(target_time, _)| *target_time <= time + self.max_dt)
+         .take(self.fan_out)
+         .cloned()
+         .collect();
+
+      Some((anchor, targets))
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn feature(time: usize, bin_index: usize, amplitude: f64) -> FrequencyFeature {
+      FrequencyFeature {
+         time,
+         bin_index,
+         frequency: 0.0,
+         amplitude,
+      }
+   }
+
+   #[test]
+   fn pairs_anchors_with_the_features_in_their_target_zone() {
+      let mut hasher = PeakHasher::new(2, 2, 4).unwrap();
+      let mut hashes = Vec::new();
+      for time in 0..8 {
+         hashes.extend(hasher.process(&[feature(time, 10 + time, 1.0)]));
+      }
+      hashes.extend(hasher.flush());
+
+      let first = hashes
+         .iter()
+         .filter(|hash| hash.time == 0)
+         .collect::<Vec<_>>();
+      assert_eq!(first.len(), 2);
+      assert_eq!(first[0].hash, pair_hash(10, 12, 2));
+      assert_eq!(first[0].span, 2);
+      assert_eq!(first[1].hash, pair_hash(10, 13, 3));
+      // Anchors near the end only have the targets, that are left
+      assert_eq!(hashes.iter().filter(|hash| hash.time == 6).count(), 0);
+      assert_eq!(hashes.iter().filter(|hash| hash.time == 5).count(), 1);
+   }
+
+   #[test]
+   fn triplet_hashes_do_not_change_with_the_speed() {
+      // Playing 5% faster raises all frequencies and shortens all time differences alike
+      let hash = |scale: f64| {
+         let anchor = 100.0 * scale;
+         triplet_hash(
+            (180.0 * scale).round() / anchor,
+            (140.0 * scale).round() / anchor,
+            (10.0 / scale).round() / (40.0 / scale).round(),
+         )
+      };
+      assert_eq!(hash(1.0), hash(1.05));
+      assert_eq!(hash(1.0), hash(0.96));
+      assert_ne!(triplet_hash(1.8, 1.4, 0.4), triplet_hash(1.4, 1.8, 0.4));
+      assert_ne!(triplet_hash(1.8, 1.4, 0.4), triplet_hash(1.8, 1.4, 0.8));
+   }
+
+   #[test]
+   fn triplets_use_the_prominent_feature_of_every_wavelet() {
+      let mut hasher = TripletHasher::new(3, 1, 10).unwrap();
+      let mut hashes = Vec::new();
+      for time in 0..20 {
+         // A quiet background, that only stands out in every other wavelet
+         let peak = if time % 2 == 0 { 100.0 } else { 1.5 };
+         let features = [
+            feature(time, 5, 1.0),
+            feature(time, 20 + time, peak),
+            feature(time, 7, 1.0),
+         ];
+         hashes.extend(hasher.process(&features));
+      }
+      hashes.extend(hasher.flush());
+
+      assert!(!hashes.is_empty());
+      assert!(hashes
+         .iter()
+         .all(|hash| hash.time % 2 == 0 && hash.span % 2 == 0));
+   }
+
+   #[test]
+   fn skips_wavelets_with_nan_amplitudes() {
+      let mut hasher = TripletHasher::new(2, 1, 10).unwrap();
+      let mut hashes = Vec::new();
+      for time in 0..10 {
+         let noise = if time == 4 { f64::NAN } else { 1.0 };
+         let features = [
+            feature(time, 5, noise),
+            feature(time, 20 + time, 100.0),
+            feature(time, 7, 1.0),
+         ];
+         hashes.extend(hasher.process(&features));
+      }
+      hashes.extend(hasher.flush());
+
+      assert!(!hashes.is_empty());
+      assert!(hashes.iter().all(|hash| hash.time != 4));
+   }
+
+   #[test]
+   fn rejects_invalid_target_zones() {
+      assert!(PeakHasher::new(0, 1, 10).is_err());
+      assert!(PeakHasher::new(5, 0, 10).is_err());
+      assert!(PeakHasher::new(5, 10, 1).is_err());
+      assert!(PeakHasher::new(5, 1, 1 << DT_BITS).is_err());
+      assert!(TripletHasher::new(1, 1, 10).is_err());
+      assert!(TripletHasher::new(2, 1, 10).is_ok());
+   }
+}
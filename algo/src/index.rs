@@ -0,0 +1,695 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{iter::Copied, mem::size_of, slice};
+
+#[cfg(not(feature = "std"))]
+use num_traits::Float;
+
+use crate::hash::PeakHash;
+
+/// Relative resolution of the speed histogram of a scaled query.
+const SPEED_RESOLUTION: f64 = 0.02;
+/// Number of the most common bins of the speed histogram, that are refined in a scaled query.
+/// Random collisions favour similar spans, so the bin of the true speed may come second.
+const SPEED_CANDIDATES: usize = 3;
+/// Number of speeds tried within each bin of the speed histogram.
+const SPEED_REFINE_STEPS: i64 = 4;
+/// Offsets closer than this number of wavelets vote for the same alignment in a scaled query.
+const OFFSET_TOLERANCE: f64 = 2.0;
+/// Shortest span of a hash, that votes for the speed of a scaled query.
+/// Shorter spans are too coarse to tell small changes in speed apart.
+const MIN_SPEED_SPAN: u32 = 8;
+/// Offsets closer than this number of wavelets belong to the same segment in a multi-match query.
+const SEGMENT_TOLERANCE: i64 = 1;
+/// Longest gap between two matching hashes of the same segment, in wavelets.
+const MAX_SEGMENT_GAP: u32 = 32;
+/// Number of matching hashes without long gaps between them, that are not considered random collisions.
+const MIN_RUN_LENGTH: usize = 3;
+/// Number of offsets on either side of a segment, whose votes give the rate of random collisions.
+const BACKGROUND_OFFSETS: i64 = 16;
+/// Factor by which the query times of a segment need to be denser than random collisions.
+/// A true match votes for almost every query time, random collisions of melodic audio for
+/// up to half of them.
+const MIN_DENSITY_RATIO: f64 = 1.5;
+
+/// An occurrence of a hash in a track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Posting {
+   pub track: u32,
+   pub time: u32,
+   pub span: u32,
+}
+
+/// A track found by a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Match {
+   pub track: u32,
+   /// The time of the query in the track, i.e. `track time - query time`, in wavelets.
+   pub offset: i64,
+   /// The number of query hashes, that line up at this offset.
+   pub score: usize,
+}
+
+/// A part of the query, that matches a track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SegmentMatch {
+   pub track: u32,
+   /// The time of the segment in the track, i.e. `track time - query time`, in wavelets.
+   pub offset: i64,
+   /// The number of query times with hashes, that line up within the segment.
+   pub score: usize,
+   /// The time in the query at which the segment starts, in wavelets.
+   pub start: u32,
+   /// The time in the query at which the segment ends, in wavelets.
+   pub end: u32,
+}
+
+/// A track found by a query, that may be played at a different speed.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScaledMatch {
+   pub track: u32,
+   /// The time in the track at which the query starts, in wavelets.
+   pub offset: f64,
+   /// The speed of the query relative to the track, i.e. `1.05` if it is played 5% faster.
+   pub speed: f64,
+   /// The number of query hashes, that line up at this offset and speed.
+   pub score: usize,
+}
+
+/// The lookups of an index, that scoring and streaming identification need.
+/// Implemented by every index, that holds its postings in memory.
+pub trait Lookup {
+   /// The iterator over the postings of a hash.
+   type Postings<'a>: Iterator<Item = Posting>
+   where
+      Self: 'a;
+
+   /// Returns all occurrences of a hash.
+   fn postings(&self, hash: u32) -> Self::Postings<'_>;
+
+   /// Returns the number of wavelets covered by the hashes of a track.
+   fn track_length(&self, track: u32) -> Option<u32>;
+
+   /// Returns up to `limit` tracks with the highest scores, best first,
+   /// see [`Index::candidates`].
+   fn candidates(&self, hashes: &[PeakHash], limit: usize) -> Vec<Match> {
+      candidates(hashes, |hash| self.postings(hash), limit)
+   }
+}
+
+/// The index maps hashes to the tracks and times they occur at.
+///
+/// # Algorithm
+/// A query looks up all its hashes and votes for the offset between the time
+/// in the track and the time in the query of every posting.
+/// The hashes of a matching track line up at the same offset, whereas random
+/// collisions spread over all offsets. The score of a track is the highest number
+/// of votes for a single offset.
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+   postings: BTreeMap<u32, Vec<Posting>>,
+   /// The number of wavelets covered by the hashes of every track.
+   lengths: BTreeMap<u32, u32>,
+}
+
+impl Index {
+   pub fn new() -> Self {
+      Self::default()
+   }
+
+   /// Adds the hashes of a track to the index.
+   pub fn insert(&mut self, track: u32, hashes: &[PeakHash]) {
+      let length = self.lengths.entry(track).or_default();
+      for hash in hashes {
+         *length = u32::max(*length, hash.time + hash.span + 1);
+      }
+
+      for hash in hashes {
+         self.postings.entry(hash.hash).or_default().push(Posting {
+            track,
+            time: hash.time,
+            span: hash.span,
+         });
+      }
+   }
+
+   /// Returns all occurrences of a hash.
+   pub fn lookup(&self, hash: u32) -> &[Posting] {
+      self.postings.get(&hash).map(|p| &p[..]).unwrap_or(&[])
+   }
+
+   /// Returns the number of distinct hashes in the index.
+   pub fn num_hashes(&self) -> usize {
+      self.postings.len()
+   }
+
+   /// Returns the number of wavelets covered by the hashes of a track.
+   pub fn track_length(&self, track: u32) -> Option<u32> {
+      self.lengths.get(&track).copied()
+   }
+
+   /// Returns the approximate number of bytes used by the postings and the track lengths,
+   /// not counting the nodes of the maps.
+   pub fn memory_usage(&self) -> usize {
+      let postings = self
+         .postings
+         .values()
+         .map(|postings| {
+            size_of::<u32>()
+               + size_of::<Vec<Posting>>()
+               + postings.capacity() * size_of::<Posting>()
+         })
+         .sum::<usize>();
+      postings + self.lengths.len() * 2 * size_of::<u32>()
+   }
+
+   /// Returns the track that matches the query best, if any.
+   pub fn query(&self, hashes: &[PeakHash]) -> Option<Match> {
+      self.candidates(hashes, 1).pop()
+   }
+
+   /// Returns up to `limit` tracks with the highest scores, best first.
+   /// Every track is reported at its best offset only.
+   pub fn candidates(&self, hashes: &[PeakHash], limit: usize) -> Vec<Match> {
+      candidates(hashes, |hash| self.lookup(hash).iter().copied(), limit)
+   }
+
+   /// Returns every part of the query, that matches a track at `min_score` or more times,
+   /// ordered by their start in the query. This finds all tracks of a mix or a medley.
+   ///
+   /// Every peak of the offset histograms of the tracks is a candidate segment, so a track
+   /// played twice yields two segments. Its range is given by the query times voting for it,
+   /// leaving out isolated times, which line up by chance, and the ends, where the times are
+   /// not clearly denser than the votes for the offsets nearby. The segments are then accepted
+   /// best first. A segment is dropped, if most of its times, or all but fewer than `min_score`,
+   /// lie within better segments. This keeps crossfades between two tracks, but drops
+   /// other versions of a track, like remasters.
+   pub fn query_all(&self, hashes: &[PeakHash], min_score: usize) -> Vec<SegmentMatch> {
+      query_all(hashes, |hash| self.lookup(hash).iter().copied(), min_score)
+   }
+
+   /// Returns up to `limit` tracks with the highest scores, best first,
+   /// allowing the query to be played at a different speed than the track.
+   ///
+   /// This is meant for speed invariant hashes, see [`TripletHasher`](crate::hash::TripletHasher).
+   /// Every posting votes for the speed given by the ratio of its span to the span of the query hash.
+   /// The most common speed of a track is then refined by finding the speed and offset,
+   /// at which the most postings line up, and fitting a line through them.
+   pub fn query_scaled(&self, hashes: &[PeakHash], limit: usize) -> Vec<ScaledMatch> {
+      query_scaled(hashes, |hash| self.lookup(hash).iter().copied(), limit)
+   }
+}
+
+impl Lookup for Index {
+   type Postings<'a> = Copied<slice::Iter<'a, Posting>>;
+
+   fn postings(&self, hash: u32) -> Self::Postings<'_> {
+      self.lookup(hash).iter().copied()
+   }
+
+   fn track_length(&self, track: u32) -> Option<u32> {
+      Index::track_length(self, track)
+   }
+
+   fn candidates(&self, hashes: &[PeakHash], limit: usize) -> Vec<Match> {
+      Index::candidates(self, hashes, limit)
+   }
+}
+
+/// Implements [`Index::candidates`] for any source of postings.
+pub(crate) fn candidates<I: Iterator<Item = Posting>>(
+   hashes: &[PeakHash],
+   lookup: impl Fn(u32) -> I,
+   limit: usize,
+) -> Vec<Match> {
+   best_offsets(histogram(hashes, lookup), limit)
+}
+
+/// Counts the votes for every offset of every track.
+pub(crate) fn histogram<I: Iterator<Item = Posting>>(
+   hashes: &[PeakHash],
+   lookup: impl Fn(u32) -> I,
+) -> BTreeMap<(u32, i64), usize> {
+   let mut histogram = BTreeMap::<(u32, i64), usize>::new();
+   for hash in hashes {
+      for posting in lookup(hash.hash) {
+         let offset = posting.time as i64 - hash.time as i64;
+         *histogram.entry((posting.track, offset)).or_default() += 1;
+      }
+   }
+   histogram
+}
+
+/// Returns up to `limit` tracks with the highest votes in the offset histogram, best first.
+pub(crate) fn best_offsets(histogram: BTreeMap<(u32, i64), usize>, limit: usize) -> Vec<Match> {
+   // Keep the best offset of every track
+   let mut best = BTreeMap::<u32, Match>::new();
+   for ((track, offset), score) in histogram {
+      let entry = best.entry(track).or_insert(Match {
+         track,
+         offset,
+         score: 0,
+      });
+      if score > entry.score {
+         entry.offset = offset;
+         entry.score = score;
+      }
+   }
+
+   let mut matches = best.into_values().collect::<Vec<_>>();
+   matches.sort_by(|a, b| b.score.cmp(&a.score).then(a.track.cmp(&b.track)));
+   matches.truncate(limit);
+   matches
+}
+
+/// Implements [`Index::query_all`] for any source of postings.
+pub(crate) fn query_all<I: Iterator<Item = Posting>>(
+   hashes: &[PeakHash],
+   lookup: impl Fn(u32) -> I,
+   min_score: usize,
+) -> Vec<SegmentMatch> {
+   segments(votes(hashes, lookup), min_score)
+}
+
+/// The query times voting for every offset of every track.
+pub(crate) type Votes = BTreeMap<u32, BTreeMap<i64, Vec<u32>>>;
+
+/// Collects the query times voting for every offset of every track.
+pub(crate) fn votes<I: Iterator<Item = Posting>>(
+   hashes: &[PeakHash],
+   lookup: impl Fn(u32) -> I,
+) -> Votes {
+   let mut votes = Votes::new();
+   for hash in hashes {
+      for posting in lookup(hash.hash) {
+         votes
+            .entry(posting.track)
+            .or_default()
+            .entry(posting.time as i64 - hash.time as i64)
+            .or_default()
+            .push(hash.time);
+      }
+   }
+   votes
+}
+
+/// Finds the segments of the query, that match a track at `min_score` or more times, in the votes.
+pub(crate) fn segments(votes: Votes, min_score: usize) -> Vec<SegmentMatch> {
+   let min_score = usize::max(1, min_score);
+   let query_len = votes
+      .values()
+      .flat_map(|offsets| offsets.values().flatten())
+      .max()
+      .map_or(1, |time| time + 1);
+
+   // Find the peaks of every track
+   let mut candidates = Vec::new();
+   for (track, offsets) in votes {
+      let mut peaks = offsets
+         .iter()
+         .map(|(offset, times)| (*offset, times.len()))
+         .collect::<Vec<_>>();
+      peaks.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+      let mut taken = Vec::<i64>::new();
+      for (offset, count) in peaks {
+         // The remaining peaks can't reach the score anymore
+         if count * ((2 * SEGMENT_TOLERANCE + 1) as usize) < min_score {
+            break;
+         }
+         if taken
+            .iter()
+            .any(|other| (offset - other).abs() <= 2 * SEGMENT_TOLERANCE)
+         {
+            continue;
+         }
+         taken.push(offset);
+
+         let mut times = offsets
+            .range(offset - SEGMENT_TOLERANCE..=offset + SEGMENT_TOLERANCE)
+            .flat_map(|(_, times)| times.iter().cloned())
+            .collect::<Vec<_>>();
+         times.sort_unstable();
+         times.dedup();
+
+         // Random collisions vote for the offsets nearby as often as for the peak
+         let window = 2 * SEGMENT_TOLERANCE + 1;
+         let mut background = (offset - window - BACKGROUND_OFFSETS..=offset - window)
+            .chain(offset + window..=offset + window + BACKGROUND_OFFSETS)
+            .map(|offset| {
+               offsets.get(&offset).map_or(0, |times| {
+                  let mut times = times.clone();
+                  times.sort_unstable();
+                  times.dedup();
+                  times.len()
+               })
+            })
+            .collect::<Vec<_>>();
+         background.sort_unstable();
+         let chance =
+            (background[background.len() / 2] * window as usize) as f64 / query_len as f64;
+
+         let times = densest_range(&remove_isolated(&times), MIN_DENSITY_RATIO * chance).to_vec();
+         if times.len() >= min_score {
+            candidates.push((track, offset, times));
+         }
+      }
+   }
+   candidates.sort_by(|a, b| b.2.len().cmp(&a.2.len()).then(a.0.cmp(&b.0)));
+
+   // Accept the segments best first, unless better segments explain most of their times
+   let mut accepted = Vec::<SegmentMatch>::new();
+   for (track, offset, times) in candidates {
+      let uncovered = times
+         .iter()
+         .filter(|time| {
+            !accepted
+               .iter()
+               .any(|segment| (segment.start..=segment.end).contains(time))
+         })
+         .count();
+      if uncovered < min_score || 2 * uncovered < times.len() {
+         continue;
+      }
+
+      accepted.push(SegmentMatch {
+         track,
+         offset,
+         score: times.len(),
+         start: times[0],
+         end: times[times.len() - 1],
+      });
+   }
+
+   accepted.sort_by(|a, b| a.start.cmp(&b.start).then(b.score.cmp(&a.score)));
+   accepted
+}
+
+/// Implements [`Index::query_scaled`] for any source of postings.
+pub(crate) fn query_scaled<I: Iterator<Item = Posting>>(
+   hashes: &[PeakHash],
+   lookup: impl Fn(u32) -> I,
+   limit: usize,
+) -> Vec<ScaledMatch> {
+   // Collect the votes of every track
+   let mut votes = BTreeMap::<u32, Vec<Vote>>::new();
+   for hash in hashes.iter().filter(|hash| hash.span > 0) {
+      for posting in lookup(hash.hash) {
+         votes.entry(posting.track).or_default().push(Vote {
+            query_time: hash.time as f64,
+            track_time: posting.time as f64,
+            speed: posting.span as f64 / hash.span as f64,
+            precise: hash.span >= MIN_SPEED_SPAN,
+         });
+      }
+   }
+
+   let mut matches = votes
+      .into_iter()
+      .filter_map(|(track, votes)| align_scaled(track, &votes))
+      .collect::<Vec<_>>();
+   matches.sort_by(|a, b| b.score.cmp(&a.score).then(a.track.cmp(&b.track)));
+   matches.truncate(limit);
+   matches
+}
+
+fn align_scaled(track: u32, votes: &[Vote]) -> Option<ScaledMatch> {
+   // Find the most common speed, including the neighbouring bins
+   let speed_bin = |speed: f64| (speed.ln() / SPEED_RESOLUTION).round() as i64;
+   // Prefer the votes of long spans, unless there are none
+   let precise = votes.iter().any(|vote| vote.precise);
+   let mut speeds = BTreeMap::<i64, usize>::new();
+   for vote in votes.iter().filter(|vote| vote.precise || !precise) {
+      *speeds.entry(speed_bin(vote.speed)).or_default() += 1;
+   }
+   let mut coarse = speeds
+      .keys()
+      .map(|bin| {
+         let votes = (*bin - 1..=*bin + 1)
+            .filter_map(|b| speeds.get(&b))
+            .sum::<usize>();
+         (votes, *bin)
+      })
+      .collect::<Vec<_>>();
+   coarse.sort_by(|a, b| b.cmp(a));
+   coarse.truncate(SPEED_CANDIDATES);
+
+   // Try finer speeds around them and find the offset most votes agree on
+   let mut best = (0, 1.0, 0.0);
+   for (_, coarse) in coarse {
+      for step in -SPEED_REFINE_STEPS..=SPEED_REFINE_STEPS {
+         let speed =
+            ((coarse as f64 + step as f64 / SPEED_REFINE_STEPS as f64) * SPEED_RESOLUTION).exp();
+
+         let mut offsets = BTreeMap::<i64, usize>::new();
+         for vote in votes {
+            let offset = vote.track_time - speed * vote.query_time;
+            *offsets
+               .entry((offset / OFFSET_TOLERANCE).round() as i64)
+               .or_default() += 1;
+         }
+
+         for (bin, count) in offsets.iter() {
+            if *count > best.0 {
+               best = (*count, speed, *bin as f64 * OFFSET_TOLERANCE);
+            }
+         }
+      }
+   }
+   if best.0 == 0 {
+      return None;
+   }
+
+   // Fit a line through the votes, that agree with the best alignment
+   let (_, speed, offset) = best;
+   let inliers = votes
+      .iter()
+      .filter(|vote| (vote.track_time - speed * vote.query_time - offset).abs() <= OFFSET_TOLERANCE)
+      .collect::<Vec<_>>();
+
+   let n = inliers.len() as f64;
+   let mean_query = inliers.iter().map(|vote| vote.query_time).sum::<f64>() / n;
+   let mean_track = inliers.iter().map(|vote| vote.track_time).sum::<f64>() / n;
+   let covariance = inliers
+      .iter()
+      .map(|vote| (vote.query_time - mean_query) * (vote.track_time - mean_track))
+      .sum::<f64>();
+   let variance = inliers
+      .iter()
+      .map(|vote| (vote.query_time - mean_query) * (vote.query_time - mean_query))
+      .sum::<f64>();
+
+   let speed = if variance > 0.0 {
+      covariance / variance
+   } else {
+      speed
+   };
+   Some(ScaledMatch {
+      track,
+      offset: mean_track - speed * mean_query,
+      speed,
+      score: inliers.len(),
+   })
+}
+
+/// Returns the sorted times, that belong to runs of at least [`MIN_RUN_LENGTH`] times
+/// without gaps longer than [`MAX_SEGMENT_GAP`].
+fn remove_isolated(times: &[u32]) -> Vec<u32> {
+   let mut kept = Vec::new();
+   let mut start = 0;
+   for i in 1..=times.len() {
+      if i == times.len() || times[i] - times[i - 1] > MAX_SEGMENT_GAP {
+         if i - start >= MIN_RUN_LENGTH {
+            kept.extend_from_slice(&times[start..i]);
+         }
+         start = i;
+      }
+   }
+   kept
+}
+
+/// Returns the range of the sorted times, in which they are densest compared to `density`
+/// times per wavelet, i.e. the range maximizing the number of times minus `density` times its length.
+fn densest_range(times: &[u32], density: f64) -> &[u32] {
+   let density = f64::min(density, 1.0);
+   let (mut best, mut best_score) = (0..0, 0.0);
+   let (mut start, mut score) = (0, 0.0);
+   for (i, time) in times.iter().enumerate() {
+      let extended = match i {
+         0 => f64::NEG_INFINITY,
+         _ => score + 1.0 - density * (time - times[i - 1]) as f64,
+      };
+      if extended > 1.0 - density {
+         score = extended;
+      } else {
+         start = i;
+         score = 1.0 - density;
+      }
+      if score > best_score {
+         best = start..i + 1;
+         best_score = score;
+      }
+   }
+   &times[best]
+}
+
+/// A posting of a scaled query, voting for the alignment of the query and the track.
+struct Vote {
+   query_time: f64,
+   track_time: f64,
+   speed: f64,
+   /// Whether the span of the hash is long enough to vote for the speed.
+   precise: bool,
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{
+      config::FingerprintConfig, fingerprint::Fingerprinter, resample::Resampler, testing,
+   };
+
+   const SAMPLE_RATE: usize = 11025;
+
+   fn fingerprinter() -> Fingerprinter {
+      Fingerprinter::with_config(FingerprintConfig::music()).unwrap()
+   }
+
+   /// Returns the time in wavelets of a time in seconds.
+   fn wavelets(seconds: f64) -> f64 {
+      seconds * fingerprinter().wavelets_per_second()
+   }
+
+   #[test]
+   fn finds_a_clip_at_its_offset() {
+      let fingerprinter = fingerprinter();
+      let tracks = [
+         testing::melody(SAMPLE_RATE, 30 * SAMPLE_RATE, 1),
+         testing::melody(SAMPLE_RATE, 30 * SAMPLE_RATE, 2),
+      ];
+      let mut index = Index::new();
+      for (track, audio) in tracks.iter().enumerate() {
+         index.insert(track as u32, &fingerprinter.fingerprint(audio));
+      }
+
+      // The offset is a whole number of wavelets, such that the hashes line up exactly
+      let start = 256 * 300;
+      let clip = fingerprinter.fingerprint(&tracks[1][start..start + 10 * SAMPLE_RATE]);
+      let candidates = index.candidates(&clip, 2);
+      assert_eq!(candidates[0].track, 1);
+      assert_eq!(candidates[0].offset, 300);
+      assert!(candidates.len() < 2 || candidates[1].score * 10 < candidates[0].score);
+      assert_eq!(index.query(&clip), Some(candidates[0].clone()));
+   }
+
+   #[test]
+   fn finds_both_tracks_of_a_concatenation() {
+      let fingerprinter = fingerprinter();
+      let tracks = (1..=3)
+         .map(|seed| testing::melody(SAMPLE_RATE, 30 * SAMPLE_RATE, seed))
+         .collect::<Vec<_>>();
+      let mut index = Index::new();
+      for (track, audio) in tracks.iter().enumerate() {
+         index.insert(track as u32, &fingerprinter.fingerprint(audio));
+      }
+
+      // 10 s from the middle of the third track, followed by 12 s from the start of the first
+      let mut query = tracks[2][10 * SAMPLE_RATE..20 * SAMPLE_RATE].to_vec();
+      query.extend_from_slice(&tracks[0][..12 * SAMPLE_RATE]);
+      let segments = index.query_all(&fingerprinter.fingerprint(&query), 20);
+
+      let assert_close = |actual: f64, expected: f64| {
+         assert!(
+            (actual - expected).abs() < wavelets(0.5),
+            "{} != {}, {:?}",
+            actual,
+            expected,
+            segments
+         )
+      };
+      match &segments[..] {
+         [first, second] => {
+            assert_eq!((first.track, second.track), (2, 0));
+            assert_close(first.start as f64, 0.0);
+            assert_close(first.end as f64, wavelets(10.0));
+            assert_close(first.offset as f64, wavelets(10.0));
+            assert_close(second.start as f64, wavelets(10.0));
+            assert_close(second.end as f64, wavelets(22.0));
+            assert_close(second.offset as f64, -wavelets(10.0));
+         }
+         segments => panic!("{:?}", segments),
+      }
+   }
+
+   #[test]
+   fn finds_a_faster_clip_and_its_speed() {
+      let fingerprinter = fingerprinter();
+      let tracks = [
+         testing::melody(SAMPLE_RATE, 40 * SAMPLE_RATE, 1),
+         testing::melody(SAMPLE_RATE, 40 * SAMPLE_RATE, 2),
+      ];
+      let mut index = Index::new();
+      for (track, audio) in tracks.iter().enumerate() {
+         index.insert(
+            track as u32,
+            &fingerprinter.fingerprint_triplets(audio).unwrap(),
+         );
+      }
+
+      // Playing 21 samples in the time of 20 is 5% faster
+      for (speed, out_rate) in [
+         (1.05, SAMPLE_RATE * 20 / 21),
+         (0.96, SAMPLE_RATE * 25 / 24),
+         (1.0, SAMPLE_RATE),
+      ] {
+         let clip = &tracks[0][10 * SAMPLE_RATE..25 * SAMPLE_RATE];
+         let clip = match out_rate {
+            SAMPLE_RATE => clip.to_vec(),
+            _ => Resampler::new(SAMPLE_RATE, out_rate).unwrap().process(clip),
+         };
+
+         let matches = index.query_scaled(&fingerprinter.fingerprint_triplets(&clip).unwrap(), 2);
+         let found = &matches[0];
+         assert_eq!(found.track, 0);
+         assert!(
+            (found.speed - speed).abs() < 0.005,
+            "{} != {}",
+            found.speed,
+            speed
+         );
+         assert!(
+            (found.offset - wavelets(10.0)).abs() < 2.0,
+            "{} != {}",
+            found.offset,
+            wavelets(10.0)
+         );
+         assert!(matches.len() < 2 || matches[1].score * 5 < found.score);
+      }
+   }
+
+   #[test]
+   fn reports_the_length_and_the_hashes_of_tracks() {
+      let mut index = Index::new();
+      let hashes = [
+         PeakHash {
+            hash: 7,
+            time: 3,
+            span: 2,
+         },
+         PeakHash {
+            hash: 7,
+            time: 10,
+            span: 5,
+         },
+      ];
+      index.insert(4, &hashes);
+      assert_eq!(index.track_length(4), Some(16));
+      assert_eq!(index.track_length(5), None);
+      assert_eq!(index.num_hashes(), 1);
+      assert_eq!(index.lookup(7).len(), 2);
+      assert!(index.lookup(8).is_empty());
+   }
+}
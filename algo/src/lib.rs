@@ -1,35 +1,134 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!("either the `std` or the `libm` feature needs to be enabled");
+
 extern crate alloc;
 
+pub mod align;
+pub mod channel;
+pub mod chromaprint;
+pub mod compact;
+pub mod config;
+#[cfg(feature = "std")]
+pub mod database;
+pub mod dedup;
+pub mod denoise;
 pub mod feature;
+pub mod fft;
+pub mod fingerprint;
+pub mod fixed;
 pub mod frequencer;
+pub mod hash;
+pub mod index;
+pub mod metadata;
+pub mod monitor;
+pub mod resample;
+pub mod score;
+pub mod session;
+#[cfg(feature = "std")]
+pub mod sharded;
+pub mod subfingerprint;
+#[cfg(test)]
+mod testing;
+pub mod transport;
+
+use alloc::vec::Vec;
+use core::{fmt::Debug, iter::Sum};
+use num_traits::{Float, FloatConst};
+
+/// The floating point type the algorithms operate on.
+///
+/// It is implemented for `f64`, which is the default everywhere,
+/// and `f32`, which halves the memory and can use faster FFTs.
+pub trait Sample: fft::FftNum + Float + FloatConst + Default + Sum + Debug + 'static {
+    /// Converts a constant into the sample type.
+    fn cast(value: f64) -> Self;
+}
+
+impl Sample for f32 {
+    fn cast(value: f64) -> Self {
+        value as f32
+    }
+}
+
+impl Sample for f64 {
+    fn cast(value: f64) -> Self {
+        value
+    }
+}
+
+/// The fingerprinting algorithms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Algorithm {
+    /// Hashes of pairs of peaks found by the [`FeatureFinder`](feature::FeatureFinder),
+    /// computed by the [`PeakHasher`](hash::PeakHasher) and matched by the [`Index`](index::Index).
+    #[default]
+    Constellation,
+    /// Binary sub-fingerprints of band energy differences, computed by the
+    /// [`SubFingerprinter`](subfingerprint::SubFingerprinter) and matched by the
+    /// [`SubFingerprintIndex`](subfingerprint::SubFingerprintIndex).
+    SubFingerprint,
+    /// Speed invariant hashes of triplets of peaks found by the
+    /// [`FeatureFinder`](feature::FeatureFinder), computed by the
+    /// [`TripletHasher`](hash::TripletHasher) and matched by
+    /// [`Index::query_scaled`](index::Index::query_scaled).
+    Triplet,
+}
+
+impl Algorithm {
+    /// Returns the name of the algorithm, as used in database manifests and on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Constellation => "constellation",
+            Algorithm::SubFingerprint => "subfingerprint",
+            Algorithm::Triplet => "triplet",
+        }
+    }
+
+    /// Returns the algorithm with the given name, see [`Algorithm::name`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Algorithm::Constellation,
+            Algorithm::SubFingerprint,
+            Algorithm::Triplet,
+        ]
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.name() == name)
+    }
+}
 
 #[derive(Debug, Clone)]
-pub struct FrequencyBin {
-    pub amplitude: f64,
-    pub frequency: f64,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrequencyBin<T = f64> {
+    pub amplitude: T,
+    pub frequency: T,
 }
 
 #[derive(Debug, Clone)]
-pub struct Wavelet {
-    pub bins: Vec<FrequencyBin>,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Wavelet<T = f64> {
+    pub bins: Vec<FrequencyBin<T>>,
 }
 
-impl Wavelet {
+impl<T: Sample> Wavelet<T> {
     pub fn empty(frames: usize) -> Self {
         Wavelet {
             bins: (0..frames)
                 .map(|_| FrequencyBin {
-                    amplitude: 0.0,
-                    frequency: 0.0,
+                    amplitude: T::zero(),
+                    frequency: T::zero(),
                 })
                 .collect::<Vec<_>>(),
         }
     }
 
     // TODO: Make this fancy with iterators
-    pub fn base_freq(&self) -> f64 {
-        let mut max_freq = 0.0;
-        let mut max_amp = 0.0;
+    pub fn base_freq(&self) -> T {
+        let mut max_freq = T::zero();
+        let mut max_amp = T::zero();
         for bin in &self.bins {
             if bin.amplitude > max_amp {
                 max_amp = bin.amplitude;
@@ -40,22 +139,22 @@ impl Wavelet {
         //let max = self.bins.iter().max();
     }
 
-    pub fn pitch_shift(&mut self, pitch_shift: f64) {
+    pub fn pitch_shift(&mut self, pitch_shift: T) {
         let bins = &self.bins;
 
         // Create empty bins
         let mut new_bins = core::iter::repeat(FrequencyBin {
-            amplitude: 0.0,
-            frequency: 0.0,
+            amplitude: T::zero(),
+            frequency: T::zero(),
         })
         .take(bins.len())
         .collect::<Vec<_>>();
 
         for k in 0..bins.len() {
-            let index = ((k as f64) * pitch_shift) as usize;
+            let index = (T::cast(k as f64) * pitch_shift).to_usize().unwrap_or(usize::MAX);
 
             if index < new_bins.len() {
-                new_bins[index].amplitude += bins[k].amplitude;
+                new_bins[index].amplitude = new_bins[index].amplitude + bins[k].amplitude;
                 new_bins[index].frequency = bins[k].frequency * pitch_shift
             }
         }
@@ -65,9 +164,91 @@ impl Wavelet {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct FrequencyFeature {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrequencyFeature<T = f64> {
     pub time: usize,
     pub bin_index: usize,
-    pub frequency: f64,
-    pub amplitude: f64,
+    pub frequency: T,
+    pub amplitude: T,
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::{Downmix, Layout},
+        config::FingerprintConfig,
+        fingerprint::Fingerprinter,
+        score::Calibration,
+    };
+    use alloc::vec;
+    use serde::{de::DeserializeOwned, Serialize};
+
+    /// Serializes the value to JSON and back, checking that nothing is lost on the way.
+    fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+        let json = serde_json::to_string(value).unwrap();
+        let decoded = serde_json::from_str::<T>(&json).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        decoded
+    }
+
+    #[test]
+    fn round_trips_the_signal_types() {
+        let bin = FrequencyBin {
+            amplitude: 0.25f32,
+            frequency: 440.0,
+        };
+        let decoded = round_trip(&bin);
+        assert_eq!((decoded.amplitude, decoded.frequency), (0.25, 440.0));
+
+        let wavelet = Wavelet {
+            bins: vec![
+                bin,
+                FrequencyBin {
+                    amplitude: 1.5,
+                    frequency: 880.5,
+                },
+            ],
+        };
+        let decoded = round_trip(&wavelet);
+        assert_eq!(decoded.bins.len(), 2);
+        assert_eq!((decoded.bins[1].amplitude, decoded.bins[1].frequency), (1.5, 880.5));
+
+        let feature = FrequencyFeature {
+            time: 7,
+            bin_index: 42,
+            frequency: 328.125,
+            amplitude: 0.1,
+        };
+        assert_eq!(round_trip(&feature), feature);
+    }
+
+    #[test]
+    fn round_trips_the_configurations() {
+        for config in [FingerprintConfig::music(), FingerprintConfig::speech()] {
+            assert_eq!(round_trip(&config), config);
+            let fingerprinter = Fingerprinter::with_config(config).unwrap();
+            assert_eq!(round_trip(&fingerprinter), fingerprinter);
+        }
+        assert_eq!(round_trip(&Calibration::default()), Calibration::default());
+        for algorithm in [Algorithm::Constellation, Algorithm::SubFingerprint, Algorithm::Triplet] {
+            assert_eq!(round_trip(&algorithm), algorithm);
+        }
+        assert_eq!(round_trip(&Downmix::Side), Downmix::Side);
+        assert_eq!(round_trip(&Layout::Planar), Layout::Planar);
+    }
+
+    #[test]
+    fn fills_in_and_validates_configurations() {
+        let config = serde_json::from_str::<FingerprintConfig>(r#"{"fan_out": 3}"#).unwrap();
+        assert_eq!(
+            config,
+            FingerprintConfig {
+                fan_out: 3,
+                ..FingerprintConfig::default()
+            }
+        );
+        assert!(serde_json::from_str::<Fingerprinter>(r#"{"block_size": 2048}"#).is_ok());
+        assert!(serde_json::from_str::<Fingerprinter>(r#"{"block_size": 1000}"#).is_err());
+    }
 }
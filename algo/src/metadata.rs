@@ -0,0 +1,27 @@
+use alloc::{collections::BTreeMap, string::String};
+
+/// Human readable information about a track, stored with its hashes in a database.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrackMetadata {
+   pub title: Option<String>,
+   pub artist: Option<String>,
+   pub album: Option<String>,
+   /// The International Standard Recording Code.
+   pub isrc: Option<String>,
+   /// The length of the track, in seconds.
+   pub duration: Option<f64>,
+   /// The file the track was fingerprinted from.
+   pub source_path: Option<String>,
+   /// Any other information as key value pairs.
+   pub tags: BTreeMap<String, String>,
+   /// The checksum of the content of the source file, see [`checksum`].
+   pub checksum: Option<u64>,
+}
+
+/// Computes the 64 bit FNV-1a hash of the data, which detects changed source files.
+pub fn checksum(data: &[u8]) -> u64 {
+   data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+      (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+   })
+}
@@ -0,0 +1,433 @@
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+use core::fmt::Write;
+#[cfg(not(feature = "std"))]
+use num_traits::Float;
+
+use crate::{
+   dedup::escape,
+   feature::FeatureFinder,
+   frequencer::Frequencer,
+   hash::{PeakHash, PeakHasher},
+   index::Lookup,
+   score::{self, Calibration},
+   Sample,
+};
+
+/// Length of the window of the stream, that is matched against the index, in seconds.
+const WINDOW: f64 = 8.0;
+/// Time between two matches of the window, in seconds.
+const HOP: f64 = 2.0;
+/// Time without a match, after which a segment is closed, in seconds.
+const MAX_GAP: f64 = 10.0;
+/// Number of tracks matched in every window, such that overlapping tracks are found.
+const MAX_TRACKS: usize = 4;
+/// Offsets closer than this number of wavelets belong to the same segment.
+const OFFSET_TOLERANCE: i64 = 2;
+/// A play, that misses less than this at the start or the end of the track, is complete, in seconds.
+const PARTIAL_TOLERANCE: f64 = 1.0;
+
+/// A catalogued track, that played in the stream.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Detection {
+   pub track: u32,
+   /// The time in the stream at which the track started playing, in seconds.
+   pub start: f64,
+   /// The time in the stream at which the track stopped playing, in seconds.
+   pub end: f64,
+   /// The time in the track at which it started playing, in seconds.
+   pub track_start: f64,
+   /// Whether only a part of the track was played, i.e. it was cut at the start or the end.
+   pub partial: bool,
+   /// The number of hashes, that matched over the whole segment.
+   pub score: usize,
+}
+
+/// The monitor finds the catalogued tracks played in a long stream, like a radio recording.
+///
+/// # Algorithm
+/// The stream runs through the frequencer, the feature finder and the hasher.
+/// In regular intervals, the hashes of a sliding window are matched against the index,
+/// keeping every track accepted by the calibration, so overlapping tracks are found as well.
+/// A match continues a segment of the same track at the same offset, otherwise it opens a new one.
+/// The start and the end of a segment are given by the first and the last matching hash,
+/// so partial plays are cut precisely. Segments, that haven't been continued for a while,
+/// are closed and reported as detections.
+pub struct Monitor<T = f64> {
+   frequencer: Frequencer<T>,
+   feature_finder: FeatureFinder<T>,
+   hasher: PeakHasher,
+   /// Audio, that doesn't fill a whole step yet.
+   pending: Vec<T>,
+   window: VecDeque<PeakHash>,
+   window_length: usize,
+   hop: usize,
+   max_gap: usize,
+   calibration: Calibration,
+   /// The number of wavelets processed so far.
+   time: usize,
+   segments: Vec<Segment>,
+}
+
+/// A segment, that is still open.
+struct Segment {
+   track: u32,
+   offset: i64,
+   first: usize,
+   last: usize,
+   /// The time of the latest hash counted in the score.
+   counted: usize,
+   score: usize,
+}
+
+impl<T: Sample> Monitor<T> {
+   pub fn new(
+      sample_rate: usize,
+      block_size: usize,
+      step_size: usize,
+      t_span: usize,
+      hasher: PeakHasher,
+   ) -> Result<Self, ()> {
+      let wavelets_per_second = sample_rate as f64 / step_size as f64;
+
+      Ok(Self {
+         frequencer: Frequencer::new(sample_rate, block_size, step_size)?,
+         feature_finder: FeatureFinder::new(block_size, t_span),
+         hasher,
+         pending: Vec::with_capacity(step_size),
+         window: VecDeque::new(),
+         window_length: (WINDOW * wavelets_per_second) as usize,
+         hop: usize::max(1, (HOP * wavelets_per_second) as usize),
+         max_gap: (MAX_GAP * wavelets_per_second) as usize,
+         calibration: Calibration::default(),
+         time: 0,
+         segments: Vec::new(),
+      })
+   }
+
+   /// Sets the calibration, that decides whether the match of a window is accepted.
+   pub fn set_calibration(&mut self, calibration: Calibration) {
+      self.calibration = calibration;
+   }
+
+   /// Feeds the next samples of the stream and returns the detections, that ended.
+   pub fn feed_audio<L: Lookup>(&mut self, index: &L, audio: &[T]) -> Vec<Detection> {
+      let step_size = self.frequencer.step_size();
+      let mut detections = Vec::new();
+
+      for sample in audio {
+         self.pending.push(*sample);
+         if self.pending.len() < step_size {
+            continue;
+         }
+
+         let wavelet = self.frequencer.feed_audio(&self.pending);
+         self.pending.clear();
+         let features = self.feature_finder.process(wavelet);
+         let hashes = self.hasher.process(&features);
+         self.push_hashes(hashes);
+
+         self.time += 1;
+         if self.time.is_multiple_of(self.hop) {
+            self.match_window(index);
+            detections.extend(self.close_segments(index, false));
+         }
+      }
+
+      detections
+   }
+
+   /// Ends the stream and returns the remaining detections.
+   pub fn finish<L: Lookup>(&mut self, index: &L) -> Vec<Detection> {
+      let hashes = self.hasher.flush();
+      self.push_hashes(hashes);
+      self.match_window(index);
+      self.close_segments(index, true)
+   }
+
+   fn push_hashes(&mut self, hashes: Vec<PeakHash>) {
+      self.window.extend(hashes);
+      while let Some(hash) = self.window.front() {
+         if hash.time as usize + self.window_length >= self.time {
+            break;
+         }
+         self.window.pop_front();
+      }
+   }
+
+   fn match_window<L: Lookup>(&mut self, index: &L) {
+      let window = self.window.iter().cloned().collect::<Vec<_>>();
+
+      let candidates = index.candidates(&window, MAX_TRACKS);
+      for m in score::score(index, &window, &candidates) {
+         if !self.calibration.accept(&m) {
+            continue;
+         }
+
+         // Find the hashes, that line up at the offset
+         let matching = window
+            .iter()
+            .filter(|hash| {
+               index.postings(hash.hash).any(|posting| {
+                  posting.track == m.track
+                     && (posting.time as i64 - hash.time as i64 - m.offset).abs()
+                        <= OFFSET_TOLERANCE
+               })
+            })
+            .collect::<Vec<_>>();
+         if matching.is_empty() {
+            continue;
+         }
+
+         // Some hashes line up by chance, trim as many from both ends
+         let trim = (m.expected * (2 * OFFSET_TOLERANCE + 1) as f64).ceil() as usize;
+         let trim = usize::min(trim, (matching.len() - 1) / 2);
+         let mut starts = matching
+            .iter()
+            .map(|hash| hash.time as usize)
+            .collect::<Vec<_>>();
+         let mut ends = matching
+            .iter()
+            .map(|hash| (hash.time + hash.span) as usize)
+            .collect::<Vec<_>>();
+         starts.sort_unstable();
+         ends.sort_unstable();
+         let (first, last) = (starts[trim], ends[ends.len() - 1 - trim]);
+
+         // Continue a segment of the same play or open a new one
+         let segment = self.segments.iter_mut().find(|segment| {
+            segment.track == m.track && (segment.offset - m.offset).abs() <= OFFSET_TOLERANCE
+         });
+         match segment {
+            Some(segment) => {
+               // Overlapping windows see the same hashes again, only count the new ones
+               let counted = segment.counted;
+               segment.score += matching
+                  .iter()
+                  .filter(|hash| hash.time as usize > counted)
+                  .count();
+               segment.counted = matching
+                  .iter()
+                  .map(|hash| hash.time as usize)
+                  .fold(counted, usize::max);
+               segment.first = usize::min(segment.first, first);
+               segment.last = usize::max(segment.last, last);
+            }
+            None => self.segments.push(Segment {
+               track: m.track,
+               offset: m.offset,
+               first,
+               last,
+               counted: matching
+                  .iter()
+                  .map(|hash| hash.time as usize)
+                  .fold(first, usize::max),
+               score: matching.len(),
+            }),
+         }
+      }
+   }
+
+   fn close_segments<L: Lookup>(&mut self, index: &L, all: bool) -> Vec<Detection> {
+      let seconds = self.frequencer.step_size() as f64 / self.frequencer.sample_rate() as f64;
+      let (time, max_gap) = (self.time, self.max_gap);
+
+      let mut detections = Vec::new();
+      self.segments.retain(|segment| {
+         if !all && segment.last + max_gap > time {
+            return true;
+         }
+
+         let track_start = (segment.first as i64 + segment.offset) as f64 * seconds;
+         let track_end = (segment.last as i64 + segment.offset) as f64 * seconds;
+         let track_length = index.track_length(segment.track).unwrap_or(0) as f64 * seconds;
+         detections.push(Detection {
+            track: segment.track,
+            start: segment.first as f64 * seconds,
+            end: segment.last as f64 * seconds,
+            track_start,
+            partial: track_start > PARTIAL_TOLERANCE
+               || track_end + PARTIAL_TOLERANCE < track_length,
+            score: segment.score,
+         });
+         false
+      });
+
+      detections.sort_by(|a, b| a.start.total_cmp(&b.start));
+      detections
+   }
+}
+
+/// Writes the detections as a JSON array, naming the tracks with `name`.
+pub fn to_json<F: Fn(u32) -> String>(detections: &[Detection], name: F) -> String {
+   let mut json = String::from("[");
+   for (i, detection) in detections.iter().enumerate() {
+      if i > 0 {
+         json.push(',');
+      }
+      write!(
+         json,
+         "\n  {{\"track\": {}, \"start\": {:.3}, \"end\": {:.3}, \"track_start\": {:.3}, \"partial\": {}, \"score\": {}}}",
+         escape(&name(detection.track)),
+         detection.start,
+         detection.end,
+         detection.track_start,
+         detection.partial,
+         detection.score
+      )
+      .unwrap();
+   }
+   json.push_str("\n]\n");
+   json
+}
+
+/// Writes the detections as CSV with a header line, naming the tracks with `name`.
+pub fn to_csv<F: Fn(u32) -> String>(detections: &[Detection], name: F) -> String {
+   let mut csv = String::from("track,start,end,track_start,partial,score\n");
+   for detection in detections {
+      writeln!(
+         csv,
+         "{},{:.3},{:.3},{:.3},{},{}",
+         csv_field(&name(detection.track)),
+         detection.start,
+         detection.end,
+         detection.track_start,
+         detection.partial,
+         detection.score
+      )
+      .unwrap();
+   }
+   csv
+}
+
+/// Quotes a CSV field, if it contains separators, quotes or line breaks.
+fn csv_field(value: &str) -> String {
+   if value.contains([',', '"', '\n', '\r']) {
+      format!("\"{}\"", value.replace('"', "\"\""))
+   } else {
+      String::from(value)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{config::FingerprintConfig, fingerprint::Fingerprinter, index::Index, testing};
+
+   const SAMPLE_RATE: usize = 11025;
+   /// Largest error of the times of a detection, in seconds.
+   const TIME_TOLERANCE: f64 = 0.5;
+
+   fn monitor() -> Monitor {
+      let config = FingerprintConfig::music();
+      let hasher = PeakHasher::new(config.fan_out, config.min_dt, config.max_dt).unwrap();
+      Monitor::new(
+         config.sample_rate,
+         config.block_size,
+         config.step_size,
+         config.t_span,
+         hasher,
+      )
+      .unwrap()
+   }
+
+   fn seconds(seconds: f64) -> usize {
+      (seconds * SAMPLE_RATE as f64) as usize
+   }
+
+   fn assert_close(actual: f64, expected: f64) {
+      assert!(
+         (actual - expected).abs() < TIME_TOLERANCE,
+         "{} != {}",
+         actual,
+         expected
+      );
+   }
+
+   /// Plays the whole first track and the middle of the second track between unknown audio,
+   /// and returns the detections.
+   fn detections(chunk: usize) -> Vec<Detection> {
+      let fingerprinter = Fingerprinter::with_config(FingerprintConfig::music()).unwrap();
+      let tracks = (1..=3)
+         .map(|seed| testing::melody(SAMPLE_RATE, seconds(20.0), seed))
+         .collect::<Vec<_>>();
+      let mut index = Index::new();
+      for (track, audio) in tracks.iter().enumerate() {
+         index.insert(track as u32, &fingerprinter.fingerprint(audio));
+      }
+
+      let mut stream = testing::melody(SAMPLE_RATE, seconds(4.0), 10);
+      stream.extend_from_slice(&tracks[0]);
+      stream.extend(testing::melody(SAMPLE_RATE, seconds(4.0), 11));
+      stream.extend_from_slice(&tracks[1][seconds(5.0)..seconds(15.0)]);
+      stream.extend(testing::melody(SAMPLE_RATE, seconds(4.0), 12));
+
+      let mut monitor = monitor();
+      let mut detections = Vec::new();
+      for piece in stream.chunks(chunk) {
+         detections.extend(monitor.feed_audio(&index, piece));
+      }
+      detections.extend(monitor.finish(&index));
+      detections
+   }
+
+   #[test]
+   fn merges_the_windows_of_a_play_into_one_detection() {
+      let detections = detections(seconds(1.0));
+      let full = detections
+         .iter()
+         .filter(|detection| detection.track == 0)
+         .collect::<Vec<_>>();
+      assert_eq!(full.len(), 1, "{:?}", detections);
+      assert_close(full[0].start, 4.0);
+      assert_close(full[0].end, 24.0);
+      assert_close(full[0].track_start, 0.0);
+      assert!(!full[0].partial);
+      assert!(detections.iter().all(|detection| detection.track != 2));
+   }
+
+   #[test]
+   fn cuts_partial_plays() {
+      let detections = detections(seconds(1.0));
+      let partial = detections
+         .iter()
+         .filter(|detection| detection.track == 1)
+         .collect::<Vec<_>>();
+      assert_eq!(partial.len(), 1, "{:?}", detections);
+      assert_close(partial[0].start, 28.0);
+      assert_close(partial[0].end, 38.0);
+      assert_close(partial[0].track_start, 5.0);
+      assert!(partial[0].partial);
+   }
+
+   #[test]
+   fn does_not_depend_on_how_the_stream_is_split() {
+      let whole = detections(usize::MAX);
+      let pieces = detections(1000);
+      assert_eq!(whole, pieces);
+   }
+
+   #[test]
+   fn writes_json_and_csv() {
+      let detections = [Detection {
+         track: 3,
+         start: 1.5,
+         end: 12.25,
+         track_start: 0.0,
+         partial: false,
+         score: 42,
+      }];
+      let name = |track: u32| format!("track \"{}\", remastered", track);
+      assert_eq!(
+         to_json(&detections, name),
+         "[\n  {\"track\": \"track \\\"3\\\", remastered\", \"start\": 1.500, \"end\": 12.250, \
+          \"track_start\": 0.000, \"partial\": false, \"score\": 42}\n]\n"
+      );
+      assert_eq!(
+         to_csv(&detections, name),
+         "track,start,end,track_start,partial,score\n\
+          \"track \"\"3\"\", remastered\",1.500,12.250,0.000,false,42\n"
+      );
+      assert_eq!(to_json(&[], name), "[\n]\n");
+   }
+}
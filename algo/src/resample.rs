@@ -0,0 +1,168 @@
+use alloc::{vec, vec::Vec};
+use core::f64::consts::PI;
+#[cfg(not(feature = "std"))]
+use num_traits::Float;
+
+/// Number of zero crossings of the sinc kernel on each side, at the full bandwidth.
+const FILTER_LENGTH: usize = 16;
+/// Number of precomputed fractional positions of the kernel.
+const PHASES: usize = 256;
+/// Cutoff frequency relative to the lower of the two nyquist frequencies.
+const CUTOFF: f64 = 0.8;
+/// Shape parameter of the kaiser window.
+const KAISER_BETA: f64 = 9.0;
+
+/// The resampler converts a stream of audio from one sample rate to another.
+///
+/// # Algorithm
+/// Every output sample is interpolated from the surrounding input samples with a
+/// kaiser windowed sinc kernel, which also acts as an anti aliasing lowpass when downsampling.
+/// The kernel is precomputed for a fixed number of phases between two input samples,
+/// the nearest phase is used for every output sample.
+pub struct Resampler {
+   in_rate: usize,
+   out_rate: usize,
+   taps: usize,
+   kernel: Vec<Vec<f64>>,
+   /// Input samples, starting at absolute input position `start`.
+   buffer: Vec<f64>,
+   start: i64,
+   consumed: u64,
+   produced: u64,
+}
+
+impl Resampler {
+   pub fn new(in_rate: usize, out_rate: usize) -> Result<Self, ()> {
+      if in_rate == 0 || out_rate == 0 {
+         return Err(());
+      }
+
+      let factor = CUTOFF * f64::min(1.0, out_rate as f64 / in_rate as f64);
+      let taps = 2 * (FILTER_LENGTH as f64 / factor / 2.0).ceil() as usize;
+      let half = taps as f64 / 2.0;
+
+      let kernel = (0..PHASES)
+         .map(|phase| {
+            let frac = phase as f64 / PHASES as f64;
+            let mut taps = (0..taps)
+               .map(|tap| {
+                  let distance = tap as f64 - half + 1.0 - frac;
+                  factor * sinc(factor * distance) * kaiser(distance / half)
+               })
+               .collect::<Vec<_>>();
+
+            // Normalize to unit gain
+            let sum = taps.iter().sum::<f64>();
+            taps.iter_mut().for_each(|tap| *tap /= sum);
+            taps
+         })
+         .collect();
+
+      Ok(Self {
+         in_rate,
+         out_rate,
+         taps,
+         kernel,
+         // Samples before the start of the stream are silence
+         buffer: vec![0.0; taps / 2 - 1],
+         start: -(taps as i64 / 2 - 1),
+         consumed: 0,
+         produced: 0,
+      })
+   }
+
+   pub fn in_rate(&self) -> usize {
+      self.in_rate
+   }
+
+   pub fn out_rate(&self) -> usize {
+      self.out_rate
+   }
+
+   /// Feeds input samples and returns all output samples, that can be computed so far.
+   pub fn process(&mut self, audio: &[f64]) -> Vec<f64> {
+      self.buffer.extend_from_slice(audio);
+      self.consumed += audio.len() as u64;
+      self.drain(u64::MAX)
+   }
+
+   /// Returns the remaining output samples at the end of the stream.
+   pub fn flush(&mut self) -> Vec<f64> {
+      // Every input sample results in `out_rate / in_rate` output samples
+      let total = (self.consumed * self.out_rate as u64).div_ceil(self.in_rate as u64);
+
+      self.buffer.extend(core::iter::repeat(0.0).take(self.taps));
+      self.drain(total)
+   }
+
+   /// Computes output samples up to `limit`, as long as the input covers their kernel.
+   fn drain(&mut self, limit: u64) -> Vec<f64> {
+      let mut output = Vec::new();
+      let end = self.start + self.buffer.len() as i64;
+
+      while self.produced < limit {
+         // The position of the output sample in the input
+         let position = self.produced * self.in_rate as u64;
+         let index = (position / self.out_rate as u64) as i64;
+         let phase = (position % self.out_rate as u64) as usize * PHASES / self.out_rate;
+
+         let first = index - (self.taps as i64 / 2 - 1);
+         if first + self.taps as i64 > end {
+            break;
+         }
+
+         let offset = (first - self.start) as usize;
+         let sample = self.buffer[offset..offset + self.taps]
+            .iter()
+            .zip(self.kernel[phase].iter())
+            .map(|(x, k)| x * k)
+            .sum::<f64>();
+
+         output.push(sample);
+         self.produced += 1;
+      }
+
+      // Drop the samples, that are not needed anymore
+      let next = (self.produced * self.in_rate as u64 / self.out_rate as u64) as i64;
+      let first = next - (self.taps as i64 / 2 - 1);
+      if first > self.start {
+         let count = usize::min((first - self.start) as usize, self.buffer.len());
+         self.buffer.drain(..count);
+         self.start += count as i64;
+      }
+
+      output
+   }
+}
+
+fn sinc(x: f64) -> f64 {
+   if x == 0.0 {
+      1.0
+   } else {
+      (PI * x).sin() / (PI * x)
+   }
+}
+
+/// The kaiser window at `t` in `[-1, 1]`.
+fn kaiser(t: f64) -> f64 {
+   if t.abs() > 1.0 {
+      return 0.0;
+   }
+
+   bessel_i0(KAISER_BETA * (1.0 - t * t).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+/// Modified bessel function of the first kind and order zero.
+fn bessel_i0(x: f64) -> f64 {
+   let mut sum = 1.0;
+   let mut term = 1.0;
+   let mut k = 1.0;
+
+   while term > 1e-12 * sum {
+      term *= (x / (2.0 * k)) * (x / (2.0 * k));
+      sum += term;
+      k += 1.0;
+   }
+
+   sum
+}
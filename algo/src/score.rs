@@ -0,0 +1,452 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+
+#[cfg(not(feature = "std"))]
+use num_traits::Float;
+
+use crate::{
+   hash::PeakHash,
+   index::{Lookup, Match},
+};
+
+/// Number of newton iterations when fitting a calibration.
+const FIT_ITERATIONS: usize = 50;
+/// Strength of the regularization of the calibration weights,
+/// which keeps them finite if the evaluation separates perfectly.
+const FIT_REGULARIZATION: f64 = 1e-3;
+/// Default weights of the calibration, see [`Calibration::default`].
+const DEFAULT_WEIGHTS: [f64; 3] = [-3.9, 61.8, 0.0];
+/// Default probability threshold, see [`Calibration::default`].
+const DEFAULT_THRESHOLD: f64 = 0.7;
+
+/// A match together with the measures, that make it comparable across queries and tracks.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchScore {
+   pub track: u32,
+   pub offset: i64,
+   /// The raw number of query hashes, that line up at the offset.
+   pub score: usize,
+   /// The fraction of query hashes, that line up at the offset.
+   pub coverage: f64,
+   /// The number of votes expected at any offset by chance,
+   /// given how often the query hashes occur in the track.
+   pub expected: f64,
+   /// How far the score exceeds the chance votes, in standard deviations.
+   pub significance: f64,
+}
+
+/// Computes the normalized scores of the matches of a query.
+///
+/// # Algorithm
+/// Random collisions of a query hash with a track spread evenly over all possible offsets.
+/// Summing the occurrences of all query hashes in the track and dividing them by the number
+/// of offsets yields the expected votes of a random offset. Since these votes are roughly
+/// poisson distributed, the significance is the excess of the score over the expected votes,
+/// divided by their standard deviation. Common hashes thereby contribute less than rare ones.
+pub fn score<L: Lookup>(index: &L, hashes: &[PeakHash], matches: &[Match]) -> Vec<MatchScore> {
+   if hashes.is_empty() {
+      return Vec::new();
+   }
+
+   let first = hashes.iter().map(|hash| hash.time).min().unwrap_or(0);
+   let last = hashes.iter().map(|hash| hash.time).max().unwrap_or(0);
+   let query_length = (last - first + 1) as f64;
+
+   // Count the occurrences of the query hashes in the matched tracks
+   let mut occurrences = BTreeMap::<u32, usize>::new();
+   for m in matches {
+      occurrences.insert(m.track, 0);
+   }
+   for hash in hashes {
+      for posting in index.postings(hash.hash) {
+         if let Some(count) = occurrences.get_mut(&posting.track) {
+            *count += 1;
+         }
+      }
+   }
+
+   matches
+      .iter()
+      .map(|m| {
+         let track_length = index.track_length(m.track).unwrap_or(0) as f64;
+         let expected = occurrences[&m.track] as f64 / (track_length + query_length);
+         MatchScore {
+            track: m.track,
+            offset: m.offset,
+            score: m.score,
+            coverage: m.score as f64 / hashes.len() as f64,
+            expected,
+            // Regularized, such that a single vote in a track without collisions is no evidence
+            significance: (m.score as f64 - expected) / (expected + 1.0).sqrt(),
+         }
+      })
+      .collect()
+}
+
+/// The calibration turns a match score into the probability of a true match.
+///
+/// # Algorithm
+/// The probability is a logistic function of a weighted sum of the coverage and the
+/// significance of the match. The weights and the probability threshold are fitted
+/// on an [`Evaluation`] with known ground truth. The weights of the measures are never
+/// negative, so the probability grows with both of them.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Calibration {
+   /// The weights of the constant term, the coverage and the significance.
+   pub weights: [f64; 3],
+   /// The probability, that a match has to exceed to be accepted.
+   pub threshold: f64,
+}
+
+impl Default for Calibration {
+   /// A conservative calibration, fitted on synthetic queries of a few seconds, see the tests.
+   /// The significance gets no weight, because steady chords make wrong matches look
+   /// significant, and the threshold lies above the fitted one.
+   /// Real catalogues should be calibrated with an [`Evaluation`] of their own.
+   fn default() -> Self {
+      Self {
+         weights: DEFAULT_WEIGHTS,
+         threshold: DEFAULT_THRESHOLD,
+      }
+   }
+}
+
+impl Calibration {
+   /// Returns the probability, that the match is a true match.
+   pub fn probability(&self, score: &MatchScore) -> f64 {
+      sigmoid(dot(&self.weights, &features(score)))
+   }
+
+   /// Returns whether the match is accepted as a true match.
+   pub fn accept(&self, score: &MatchScore) -> bool {
+      self.probability(score) > self.threshold
+   }
+}
+
+/// The results of an evaluation run, i.e. the scores of queries with known ground truth.
+#[derive(Debug, Clone, Default)]
+pub struct Evaluation {
+   samples: Vec<([f64; 3], bool)>,
+}
+
+impl Evaluation {
+   pub fn new() -> Self {
+      Self::default()
+   }
+
+   /// Adds the score of a match, which is `correct` if it found the right track at the right offset.
+   pub fn add(&mut self, score: &MatchScore, correct: bool) {
+      self.samples.push((features(score), correct));
+   }
+
+   /// Returns the number of matches in the evaluation.
+   pub fn len(&self) -> usize {
+      self.samples.len()
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.samples.is_empty()
+   }
+
+   /// Fits a calibration, that accepts at most `max_false_positive_rate` of the incorrect matches.
+   /// Fails, if the evaluation doesn't contain both correct and incorrect matches.
+   pub fn calibrate(&self, max_false_positive_rate: f64) -> Result<Calibration, ()> {
+      let positives = self.samples.iter().filter(|(_, correct)| *correct).count();
+      if positives == 0 || positives == self.samples.len() {
+         return Err(());
+      }
+
+      // Measures, that fit a negative weight, are dropped and the rest is fitted again,
+      // such that more evidence never lowers the probability
+      let mut active = [true; 3];
+      let weights = loop {
+         let weights = self.fit(&active).ok_or(())?;
+         match (1..3).find(|i| active[*i] && weights[*i] < 0.0) {
+            Some(i) => active[i] = false,
+            None => break weights,
+         }
+      };
+
+      // Choose the threshold, such that the allowed number of incorrect matches lies above it
+      let mut negatives = self
+         .samples
+         .iter()
+         .filter(|(_, correct)| !correct)
+         .map(|(x, _)| sigmoid(dot(&weights, x)))
+         .filter(|probability| !probability.is_nan())
+         .collect::<Vec<_>>();
+      negatives.sort_by(|a, b| b.total_cmp(a));
+      let allowed = (max_false_positive_rate.max(0.0) * negatives.len() as f64) as usize;
+      let threshold = negatives.get(allowed).copied().unwrap_or(0.0);
+
+      Ok(Calibration { weights, threshold })
+   }
+
+   /// Fits the logistic regression with newton's method,
+   /// keeping the weights of the inactive features at zero.
+   fn fit(&self, active: &[bool; 3]) -> Option<[f64; 3]> {
+      let mut weights = [0.0; 3];
+      for _ in 0..FIT_ITERATIONS {
+         let mut gradient = [0.0; 3];
+         let mut hessian = [[0.0; 3]; 3];
+         for i in 0..3 {
+            gradient[i] = FIT_REGULARIZATION * weights[i];
+            hessian[i][i] = FIT_REGULARIZATION;
+         }
+
+         for (x, correct) in self.samples.iter() {
+            let p = sigmoid(dot(&weights, x));
+            let error = p - if *correct { 1.0 } else { 0.0 };
+            for i in 0..3 {
+               gradient[i] += error * x[i];
+               for j in 0..3 {
+                  hessian[i][j] += p * (1.0 - p) * x[i] * x[j];
+               }
+            }
+         }
+
+         // Inactive features don't move
+         for i in (0..3).filter(|i| !active[*i]) {
+            gradient[i] = 0.0;
+            for j in 0..3 {
+               hessian[i][j] = 0.0;
+               hessian[j][i] = 0.0;
+            }
+            hessian[i][i] = 1.0;
+         }
+
+         let step = solve(hessian, gradient)?;
+         for i in 0..3 {
+            weights[i] -= step[i];
+         }
+      }
+      Some(weights)
+   }
+
+   /// Returns the true positive rate and the false positive rate of a calibration on the evaluation.
+   pub fn rates(&self, calibration: &Calibration) -> (f64, f64) {
+      let mut accepted = [0usize; 2];
+      let mut total = [0usize; 2];
+      for (x, correct) in self.samples.iter() {
+         let class = *correct as usize;
+         total[class] += 1;
+         if sigmoid(dot(&calibration.weights, x)) > calibration.threshold {
+            accepted[class] += 1;
+         }
+      }
+
+      let rate = |class: usize| accepted[class] as f64 / usize::max(total[class], 1) as f64;
+      (rate(1), rate(0))
+   }
+}
+
+fn features(score: &MatchScore) -> [f64; 3] {
+   [1.0, score.coverage, score.significance]
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+   a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+}
+
+fn sigmoid(x: f64) -> f64 {
+   1.0 / (1.0 + (-x).exp())
+}
+
+/// Solves the linear system `a * x = b` by gaussian elimination with partial pivoting.
+fn solve(mut a: [[f64; 3]; 3], mut b: [f64; 3]) -> Option<[f64; 3]> {
+   for col in 0..3 {
+      let pivot = (col..3).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+      if a[pivot][col].is_nan() || a[pivot][col].abs() < 1e-12 {
+         return None;
+      }
+      a.swap(col, pivot);
+      b.swap(col, pivot);
+
+      let pivot_row = a[col];
+      for row in col + 1..3 {
+         let factor = a[row][col] / pivot_row[col];
+         for (value, pivot) in a[row][col..].iter_mut().zip(pivot_row[col..].iter()) {
+            *value -= factor * pivot;
+         }
+         b[row] -= factor * b[col];
+      }
+   }
+
+   let mut x = [0.0; 3];
+   for row in (0..3).rev() {
+      let sum = (row + 1..3).map(|k| a[row][k] * x[k]).sum::<f64>();
+      x[row] = (b[row] - sum) / a[row][row];
+   }
+   Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{config::FingerprintConfig, fingerprint::Fingerprinter, index::Index, testing};
+
+   const SAMPLE_RATE: usize = 11025;
+
+   /// Creates reproducible audio from the sample rate, the length and a seed.
+   type Generator = fn(usize, usize, u64) -> Vec<f64>;
+
+   /// Scores synthetic queries of a few seconds against a catalogue of melodies and chords.
+   /// Every query is a clip of a track with a little noise, or unknown audio of the same kind.
+   /// A match is correct, if it finds the track of the clip at the offset of the clip.
+   fn synthetic_evaluation() -> Evaluation {
+      let fingerprinter = Fingerprinter::with_config(FingerprintConfig::music()).unwrap();
+      let step_size = fingerprinter.step_size();
+      let generators: [Generator; 2] = [testing::melody, testing::chords];
+      let tracks = (0..6)
+         .map(|seed| generators[seed % 2](SAMPLE_RATE, 30 * SAMPLE_RATE, seed as u64))
+         .collect::<Vec<_>>();
+      let mut index = Index::new();
+      for (track, audio) in tracks.iter().enumerate() {
+         index.insert(track as u32, &fingerprinter.fingerprint(audio));
+      }
+
+      let mut evaluation = Evaluation::new();
+      let mut seed = 100;
+      for (track, audio) in tracks.iter().enumerate() {
+         for &seconds in &[2, 4, 8] {
+            for &noise in &[0.0, 0.01, 0.02, 0.05] {
+               seed += 1;
+               let len = seconds * SAMPLE_RATE;
+               let start = 40 * (seed as usize % 12);
+               let clip = audio[start * step_size..][..len].to_vec();
+               let unknown = generators[track % 2](SAMPLE_RATE, len, seed);
+
+               for (mut query, truth) in [(clip, Some(start as i64)), (unknown, None)] {
+                  for (x, n) in query.iter_mut().zip(testing::noise(len, seed)) {
+                     *x += noise * n;
+                  }
+
+                  let hashes = fingerprinter.fingerprint(&query);
+                  let candidates = index.candidates(&hashes, 3);
+                  for m in score(&index, &hashes, &candidates) {
+                     let correct = m.track == track as u32
+                        && truth.is_some_and(|offset| (m.offset - offset).abs() <= 1);
+                     evaluation.add(&m, correct);
+                  }
+               }
+            }
+         }
+      }
+      evaluation
+   }
+
+   fn match_score(coverage: f64, significance: f64) -> MatchScore {
+      MatchScore {
+         track: 0,
+         offset: 0,
+         score: 0,
+         coverage,
+         expected: 0.0,
+         significance,
+      }
+   }
+
+   #[test]
+   fn fits_the_default_calibration_on_synthetic_queries() {
+      let evaluation = synthetic_evaluation();
+      let fitted = evaluation.calibrate(0.0).unwrap();
+      for (fitted, default) in fitted.weights.iter().zip(DEFAULT_WEIGHTS.iter()) {
+         assert!(
+            (fitted - default).abs() <= 0.01 * default.abs(),
+            "{:?}",
+            fitted
+         );
+      }
+      assert!(fitted.threshold < DEFAULT_THRESHOLD);
+
+      let (true_positives, false_positives) = evaluation.rates(&Calibration::default());
+      assert_eq!(false_positives, 0.0);
+      assert!(true_positives > 0.6, "{}", true_positives);
+   }
+
+   #[test]
+   fn probability_grows_with_the_evidence() {
+      let calibrations = [
+         Calibration::default(),
+         Calibration {
+            weights: [-15.4, 1.9, 0.2],
+            threshold: 0.5,
+         },
+      ];
+      for calibration in calibrations.iter() {
+         for significance in [0.0, 10.0, 100.0, 1000.0] {
+            let probabilities = (0..=20)
+               .map(|i| calibration.probability(&match_score(i as f64 / 20.0, significance)))
+               .collect::<Vec<_>>();
+            assert!(probabilities.windows(2).all(|p| p[0] <= p[1]));
+            assert!(probabilities.iter().all(|p| (0.0..=1.0).contains(p)));
+         }
+         for coverage in [0.0, 0.05, 0.5] {
+            let probabilities = [0.0, 10.0, 100.0, 1000.0]
+               .iter()
+               .map(|significance| calibration.probability(&match_score(coverage, *significance)))
+               .collect::<Vec<_>>();
+            assert!(probabilities.windows(2).all(|p| p[0] <= p[1]));
+         }
+      }
+   }
+
+   #[test]
+   fn accepts_matches_above_the_threshold() {
+      let default = Calibration::default();
+      for i in 0..=100 {
+         let score = match_score(i as f64 / 1000.0, 50.0);
+         assert_eq!(
+            default.accept(&score),
+            default.probability(&score) > DEFAULT_THRESHOLD
+         );
+      }
+      assert!(!default.accept(&match_score(0.01, 50.0)));
+      assert!(default.accept(&match_score(0.5, 50.0)));
+
+      // Raising the threshold never accepts more
+      let score = match_score(0.08, 50.0);
+      let probability = default.probability(&score);
+      let stricter = Calibration {
+         threshold: probability,
+         ..default.clone()
+      };
+      assert!(default.accept(&score));
+      assert!(!stricter.accept(&score));
+   }
+
+   #[test]
+   fn calibrates_the_false_positive_rate() {
+      // Correct matches cover more of the query, but some incorrect ones cover as much
+      let mut evaluation = Evaluation::new();
+      for i in 0..100 {
+         let coverage = i as f64 / 100.0;
+         evaluation.add(&match_score(coverage, 10.0), false);
+         evaluation.add(&match_score(coverage + 0.5, 10.0), true);
+      }
+
+      for max_false_positive_rate in [0.0, 0.1, 0.3] {
+         let calibration = evaluation.calibrate(max_false_positive_rate).unwrap();
+         let (true_positives, false_positives) = evaluation.rates(&calibration);
+         assert!(false_positives <= max_false_positive_rate);
+         assert!(true_positives >= 0.5 + false_positives - 1e-9);
+         assert!(calibration.weights[1] > 0.0);
+         assert!(calibration.weights[2] >= 0.0);
+      }
+
+      let mut one_sided = Evaluation::new();
+      one_sided.add(&match_score(0.5, 10.0), true);
+      assert!(one_sided.calibrate(0.0).is_err());
+      assert!(Evaluation::new().calibrate(0.0).is_err());
+   }
+
+   #[test]
+   fn fails_to_calibrate_on_nan_scores() {
+      let mut evaluation = Evaluation::new();
+      evaluation.add(&match_score(0.2, 10.0), false);
+      evaluation.add(&match_score(0.8, 10.0), true);
+      evaluation.add(&match_score(0.5, f64::NAN), false);
+      assert!(evaluation.calibrate(0.1).is_err());
+   }
+}
@@ -0,0 +1,319 @@
+use alloc::vec::Vec;
+
+use crate::{
+   hash::{PeakHash, PeakHasher},
+   index::{Lookup, Match},
+   FrequencyFeature,
+};
+
+/// Time between two scorings of the query against the index, in seconds.
+const SCORE_INTERVAL: f64 = 0.5;
+/// Default score, at which a track is reported as a candidate.
+const CANDIDATE_SCORE: usize = 8;
+/// Default score, at which a track is reported as a confident match.
+const CONFIDENT_SCORE: usize = 30;
+/// Factor by which a confident match has to outscore the runner up.
+const CONFIDENT_MARGIN: usize = 2;
+
+/// An event of a query session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SessionEvent {
+   /// A new best track, that is not certain yet.
+   Candidate(Match),
+   /// A track that matches with high confidence. The session is finished.
+   Confident(Match),
+   /// No track was confidently matched before the timeout. The session is finished.
+   NoMatch,
+}
+
+/// The query session is a single identification attempt on a live stream.
+///
+/// # Algorithm
+/// The session hashes the features of every wavelet and scores the hashes collected so far
+/// against the index in regular intervals. Whenever the best track changes and reaches the
+/// candidate score, it is reported as a candidate. Once the best track reaches the confident
+/// score and outscores the runner up by a wide margin, the session terminates early.
+/// If that doesn't happen before the timeout, the session terminates without a match.
+///
+/// The offsets of the matches are the times in the track at the start of the session.
+pub struct QuerySession {
+   hasher: PeakHasher,
+   hashes: Vec<PeakHash>,
+   score_interval: usize,
+   timeout: usize,
+   candidate_score: usize,
+   confident_score: usize,
+   /// The time of the first feature of the session.
+   start: Option<usize>,
+   /// The number of wavelets processed in the session.
+   elapsed: usize,
+   candidate: Option<Match>,
+   finished: bool,
+}
+
+impl QuerySession {
+   /// Creates a session, that gives up after `timeout` seconds.
+   /// `wavelets_per_second` is the rate at which the frequencer produces wavelets,
+   /// i.e. the sample rate divided by the step size.
+   pub fn new(hasher: PeakHasher, wavelets_per_second: f64, timeout: f64) -> Result<Self, ()> {
+      if wavelets_per_second <= 0.0 || timeout <= 0.0 {
+         return Err(());
+      }
+
+      Ok(Self {
+         hasher,
+         hashes: Vec::new(),
+         score_interval: usize::max(1, (SCORE_INTERVAL * wavelets_per_second) as usize),
+         timeout: (timeout * wavelets_per_second) as usize,
+         candidate_score: CANDIDATE_SCORE,
+         confident_score: CONFIDENT_SCORE,
+         start: None,
+         elapsed: 0,
+         candidate: None,
+         finished: false,
+      })
+   }
+
+   /// Sets the scores, at which a track is reported as a candidate and as a confident match.
+   pub fn set_thresholds(&mut self, candidate_score: usize, confident_score: usize) {
+      self.candidate_score = candidate_score;
+      self.confident_score = usize::max(candidate_score, confident_score);
+   }
+
+   /// Returns the current candidate, if any.
+   pub fn candidate(&self) -> Option<&Match> {
+      self.candidate.as_ref()
+   }
+
+   /// Returns whether the session has terminated, either with or without a match.
+   pub fn is_finished(&self) -> bool {
+      self.finished
+   }
+
+   /// Starts a new identification attempt at the current position of the stream.
+   pub fn reset(&mut self) {
+      self.hashes.clear();
+      self.start = None;
+      self.elapsed = 0;
+      self.candidate = None;
+      self.finished = false;
+   }
+
+   /// Takes the features found in one step of the feature finder, i.e. it has to be called
+   /// for every wavelet, even without features. Returns an event, if something happened.
+   pub fn process<L: Lookup, T>(
+      &mut self,
+      index: &L,
+      features: &[FrequencyFeature<T>],
+   ) -> Option<SessionEvent> {
+      // Keep hashing after the session has finished, such that a reset starts right away
+      let hashes = self.hasher.process(features);
+      if self.finished {
+         return None;
+      }
+
+      if let (None, Some(feature)) = (self.start, features.first()) {
+         self.start = Some(feature.time);
+      }
+      if let Some(start) = self.start {
+         // Anchors from before a reset don't belong to this session
+         self.hashes.extend(
+            hashes
+               .into_iter()
+               .filter(|hash| hash.time as usize >= start),
+         );
+      }
+
+      self.elapsed += 1;
+      let event = if self.elapsed.is_multiple_of(self.score_interval) {
+         self.score(index)
+      } else {
+         None
+      };
+
+      if event.is_none() && self.elapsed >= self.timeout {
+         self.finished = true;
+         return Some(SessionEvent::NoMatch);
+      }
+      event
+   }
+
+   fn score<L: Lookup>(&mut self, index: &L) -> Option<SessionEvent> {
+      let mut candidates = index.candidates(&self.hashes, 2).into_iter();
+      let mut best = candidates.next()?;
+      let runner_up = candidates.next().map(|m| m.score).unwrap_or(0);
+
+      // Report the offset relative to the start of the session
+      best.offset += self.start.unwrap_or(0) as i64;
+
+      if best.score >= self.confident_score && best.score >= CONFIDENT_MARGIN * runner_up {
+         self.finished = true;
+         self.candidate = Some(best.clone());
+         return Some(SessionEvent::Confident(best));
+      }
+
+      if best.score < self.candidate_score {
+         return None;
+      }
+      let is_new = self.candidate.as_ref().map(|c| c.track) != Some(best.track);
+      self.candidate = Some(best.clone());
+      if is_new {
+         Some(SessionEvent::Candidate(best))
+      } else {
+         None
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{
+      config::FingerprintConfig, feature::FeatureFinder, fingerprint::Fingerprinter,
+      frequencer::Frequencer, index::Index, testing,
+   };
+
+   const TIMEOUT: f64 = 5.0;
+
+   fn fingerprinter() -> Fingerprinter {
+      Fingerprinter::with_config(FingerprintConfig::music()).unwrap()
+   }
+
+   /// Returns an index of two melodies and the melodies.
+   fn index() -> (Index, Vec<Vec<f64>>) {
+      let fingerprinter = fingerprinter();
+      let tracks = (1..=2)
+         .map(|seed| {
+            testing::melody(
+               fingerprinter.sample_rate(),
+               30 * fingerprinter.sample_rate(),
+               seed,
+            )
+         })
+         .collect::<Vec<_>>();
+      let mut index = Index::new();
+      for (track, audio) in tracks.iter().enumerate() {
+         index.insert(track as u32, &fingerprinter.fingerprint(audio));
+      }
+      (index, tracks)
+   }
+
+   fn session() -> QuerySession {
+      let config = FingerprintConfig::music();
+      let hasher = PeakHasher::new(config.fan_out, config.min_dt, config.max_dt).unwrap();
+      QuerySession::new(hasher, fingerprinter().wavelets_per_second(), TIMEOUT).unwrap()
+   }
+
+   /// The frequencer and the feature finder of a stream, that runs through a session.
+   struct Stream {
+      frequencer: Frequencer<f64>,
+      feature_finder: FeatureFinder<f64>,
+   }
+
+   impl Stream {
+      fn new() -> Self {
+         let config = FingerprintConfig::music();
+         Self {
+            frequencer: Frequencer::new(config.sample_rate, config.block_size, config.step_size)
+               .unwrap(),
+            feature_finder: FeatureFinder::new(config.block_size, config.t_span),
+         }
+      }
+
+      /// Runs the audio through the session and returns its events with the number of
+      /// wavelets processed before each of them.
+      fn run(
+         &mut self,
+         session: &mut QuerySession,
+         index: &Index,
+         audio: &[f64],
+      ) -> Vec<(usize, SessionEvent)> {
+         let mut events = Vec::new();
+         for (i, step) in audio.chunks_exact(256).enumerate() {
+            let features = self
+               .feature_finder
+               .process(self.frequencer.feed_audio(step));
+            if let Some(event) = session.process(index, &features) {
+               events.push((i + 1, event));
+            }
+         }
+         events
+      }
+   }
+
+   #[test]
+   fn finishes_early_with_a_confident_match() {
+      let (index, tracks) = index();
+      let mut session = session();
+
+      // The clip starts on a whole wavelet of the track
+      let start = 300;
+      let clip = &tracks[1][256 * start..256 * start + 10 * 11025];
+      let events = Stream::new().run(&mut session, &index, clip);
+      let timeout = (TIMEOUT * fingerprinter().wavelets_per_second()) as usize;
+      match &events[..] {
+         [(time, SessionEvent::Confident(m))] => {
+            assert!(*time < timeout);
+            assert_eq!(m.track, 1);
+            // The session starts with its first feature, which is less than t_span old
+            let t_span = FingerprintConfig::music().t_span;
+            assert!(
+               (0..t_span as i64).contains(&(m.offset - start as i64)),
+               "{:?}",
+               m
+            );
+         }
+         events => panic!("{:?}", events),
+      }
+      assert!(session.is_finished());
+      assert_eq!(session.candidate().map(|m| m.track), Some(1));
+   }
+
+   #[test]
+   fn reports_a_candidate_once() {
+      let (index, tracks) = index();
+      let mut session = session();
+      session.set_thresholds(CANDIDATE_SCORE, usize::MAX);
+
+      let events = Stream::new().run(&mut session, &index, &tracks[0][..10 * 11025]);
+      let timeout = (TIMEOUT * fingerprinter().wavelets_per_second()) as usize;
+      match &events[..] {
+         [(_, SessionEvent::Candidate(m)), (time, SessionEvent::NoMatch)] => {
+            assert_eq!(m.track, 0);
+            assert_eq!(*time, timeout);
+         }
+         events => panic!("{:?}", events),
+      }
+      assert_eq!(session.candidate().map(|m| m.track), Some(0));
+   }
+
+   #[test]
+   fn gives_up_at_the_timeout() {
+      let (index, _) = index();
+      let mut session = session();
+      let unknown = testing::melody(11025, 20 * 11025, 3);
+      let timeout = (TIMEOUT * fingerprinter().wavelets_per_second()) as usize;
+
+      let mut stream = Stream::new();
+      let events = stream.run(&mut session, &index, &unknown[..2 * timeout * 256]);
+      assert_eq!(events.last(), Some(&(timeout, SessionEvent::NoMatch)));
+      assert!(events[..events.len() - 1]
+         .iter()
+         .all(|(_, event)| matches!(event, SessionEvent::Candidate(_))));
+      assert!(session.is_finished());
+
+      // A reset starts the next attempt on the same stream, that times out again
+      session.reset();
+      let events = stream.run(&mut session, &index, &unknown[2 * timeout * 256..]);
+      assert_eq!(events.last(), Some(&(timeout, SessionEvent::NoMatch)));
+   }
+
+   #[test]
+   fn rejects_invalid_rates() {
+      let config = FingerprintConfig::music();
+      let hasher = || PeakHasher::new(config.fan_out, config.min_dt, config.max_dt).unwrap();
+      assert!(QuerySession::new(hasher(), 0.0, TIMEOUT).is_err());
+      assert!(QuerySession::new(hasher(), 43.0, 0.0).is_err());
+   }
+}
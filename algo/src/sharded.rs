@@ -0,0 +1,294 @@
+use std::{collections::BTreeMap, thread};
+
+use crate::{
+   compact::{CompactIndex, Postings},
+   hash::PeakHash,
+   index::{self, Lookup, Match, ScaledMatch, SegmentMatch, Votes},
+};
+
+/// An index, that is partitioned by hash into shards, which are queried and filled in parallel.
+///
+/// # Algorithm
+/// Every hash belongs to exactly one shard, which is a [`CompactIndex`].
+/// A query is split by the shards of its hashes and every shard builds the offset histogram
+/// of its part on its own thread. The histograms are then merged, which yields the same
+/// histogram as a single index, so the matches are the same as well.
+#[derive(Debug, Clone)]
+pub struct ShardedIndex {
+   shards: Vec<CompactIndex>,
+}
+
+impl ShardedIndex {
+   /// Creates an index with the given number of shards, usually the number of cores.
+   pub fn new(num_shards: usize) -> Result<Self, ()> {
+      if num_shards == 0 {
+         return Err(());
+      }
+
+      Ok(Self {
+         shards: (0..num_shards).map(|_| CompactIndex::new()).collect(),
+      })
+   }
+
+   /// Creates an index with one shard for every core.
+   pub fn with_available_parallelism() -> Self {
+      let num_shards = thread::available_parallelism().map_or(1, |n| n.get());
+      Self {
+         shards: (0..num_shards).map(|_| CompactIndex::new()).collect(),
+      }
+   }
+
+   pub fn num_shards(&self) -> usize {
+      self.shards.len()
+   }
+
+   /// Adds the hashes of a track to the index.
+   pub fn insert(&mut self, track: u32, hashes: &[PeakHash]) {
+      self.insert_batch(&[(track, hashes)]);
+   }
+
+   /// Adds the hashes of several tracks to the index, filling the shards in parallel.
+   pub fn insert_batch(&mut self, tracks: &[(u32, &[PeakHash])]) {
+      let mut parts = (0..self.shards.len())
+         .map(|_| Vec::with_capacity(tracks.len()))
+         .collect::<Vec<_>>();
+      for (track, hashes) in tracks {
+         for (part, hashes) in parts.iter_mut().zip(self.partition(hashes)) {
+            part.push((*track, hashes));
+         }
+      }
+
+      thread::scope(|scope| {
+         for (shard, part) in self.shards.iter_mut().zip(parts) {
+            scope.spawn(move || {
+               for (track, hashes) in part {
+                  shard.insert(track, &hashes);
+               }
+            });
+         }
+      });
+   }
+
+   /// Releases the memory reserved for further postings, after all tracks have been inserted.
+   pub fn shrink_to_fit(&mut self) {
+      for shard in self.shards.iter_mut() {
+         shard.shrink_to_fit();
+      }
+   }
+
+   /// Returns all occurrences of a hash, which are held by the shard of the hash.
+   pub fn lookup(&self, hash: u32) -> Postings<'_> {
+      self.shards[self.shard(hash)].lookup(hash)
+   }
+
+   /// Returns the number of distinct hashes in the index.
+   pub fn num_hashes(&self) -> usize {
+      self.shards.iter().map(CompactIndex::num_hashes).sum()
+   }
+
+   /// Returns the number of postings in the index.
+   pub fn num_postings(&self) -> usize {
+      self.shards.iter().map(CompactIndex::num_postings).sum()
+   }
+
+   /// Returns the number of wavelets covered by the hashes of a track.
+   pub fn track_length(&self, track: u32) -> Option<u32> {
+      self
+         .shards
+         .iter()
+         .filter_map(|shard| shard.track_length(track))
+         .max()
+   }
+
+   /// Returns the approximate number of bytes used by all shards.
+   pub fn memory_usage(&self) -> usize {
+      self.shards.iter().map(CompactIndex::memory_usage).sum()
+   }
+
+   /// Returns the track that matches the query best, if any.
+   pub fn query(&self, hashes: &[PeakHash]) -> Option<Match> {
+      self.candidates(hashes, 1).pop()
+   }
+
+   /// Returns up to `limit` tracks with the highest scores, best first,
+   /// see [`Index::candidates`](crate::index::Index::candidates).
+   pub fn candidates(&self, hashes: &[PeakHash], limit: usize) -> Vec<Match> {
+      index::best_offsets(self.histogram(hashes), limit)
+   }
+
+   /// Returns every part of the query, that matches a track at `min_score` or more times,
+   /// see [`Index::query_all`](crate::index::Index::query_all).
+   pub fn query_all(&self, hashes: &[PeakHash], min_score: usize) -> Vec<SegmentMatch> {
+      let partial_votes = self.fan_out(hashes, |shard, hashes| {
+         index::votes(hashes, |hash| shard.lookup(hash))
+      });
+
+      let mut votes = Votes::new();
+      for partial in partial_votes {
+         for (track, offsets) in partial {
+            let track_votes = votes.entry(track).or_default();
+            for (offset, times) in offsets {
+               track_votes.entry(offset).or_default().extend(times);
+            }
+         }
+      }
+      index::segments(votes, min_score)
+   }
+
+   /// Returns up to `limit` tracks, that may be played at a different speed,
+   /// see [`Index::query_scaled`](crate::index::Index::query_scaled).
+   /// The votes of a track need to be fitted together, so the shards are not queried in parallel.
+   pub fn query_scaled(&self, hashes: &[PeakHash], limit: usize) -> Vec<ScaledMatch> {
+      index::query_scaled(hashes, |hash| self.lookup(hash), limit)
+   }
+
+   /// Counts the votes for every offset of every track, merging the histograms of the shards.
+   fn histogram(&self, hashes: &[PeakHash]) -> BTreeMap<(u32, i64), usize> {
+      let histograms = self.fan_out(hashes, |shard, hashes| {
+         index::histogram(hashes, |hash| shard.lookup(hash))
+      });
+
+      let mut histogram = BTreeMap::new();
+      for partial in histograms {
+         for (key, count) in partial {
+            *histogram.entry(key).or_default() += count;
+         }
+      }
+      histogram
+   }
+
+   /// Runs `f` on every shard with the hashes belonging to it, each on its own thread.
+   fn fan_out<R: Send>(
+      &self,
+      hashes: &[PeakHash],
+      f: impl Fn(&CompactIndex, &[PeakHash]) -> R + Sync,
+   ) -> Vec<R> {
+      let parts = self.partition(hashes);
+      let f = &f;
+
+      thread::scope(|scope| {
+         let handles = self
+            .shards
+            .iter()
+            .zip(parts.iter())
+            .map(|(shard, part)| scope.spawn(move || f(shard, part)))
+            .collect::<Vec<_>>();
+         handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+      })
+   }
+
+   /// Splits the hashes by the shards they belong to.
+   fn partition(&self, hashes: &[PeakHash]) -> Vec<Vec<PeakHash>> {
+      let mut parts = vec![Vec::new(); self.shards.len()];
+      for hash in hashes {
+         parts[self.shard(hash.hash)].push(*hash);
+      }
+      parts
+   }
+
+   /// Returns the shard of a hash. The hash is mixed first,
+   /// since the bits of peak hashes are far from uniformly distributed.
+   fn shard(&self, hash: u32) -> usize {
+      (hash.wrapping_mul(0x9e37_79b1) >> 16) as usize % self.shards.len()
+   }
+}
+
+impl Lookup for ShardedIndex {
+   type Postings<'a> = Postings<'a>;
+
+   fn postings(&self, hash: u32) -> Postings<'_> {
+      self.lookup(hash)
+   }
+
+   fn track_length(&self, track: u32) -> Option<u32> {
+      ShardedIndex::track_length(self, track)
+   }
+
+   fn candidates(&self, hashes: &[PeakHash], limit: usize) -> Vec<Match> {
+      ShardedIndex::candidates(self, hashes, limit)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{config::FingerprintConfig, fingerprint::Fingerprinter, index::Index, testing};
+
+   const SAMPLE_RATE: usize = 11025;
+
+   /// Returns the hashes of tracks and indexes of them with one and with several shards.
+   fn indexes() -> (Vec<Vec<PeakHash>>, Index, ShardedIndex) {
+      let fingerprinter = Fingerprinter::with_config(FingerprintConfig::music()).unwrap();
+      let tracks = (1..=4)
+         .map(|seed| {
+            fingerprinter.fingerprint(&testing::melody(SAMPLE_RATE, 30 * SAMPLE_RATE, seed))
+         })
+         .collect::<Vec<_>>();
+
+      let mut index = Index::new();
+      for (track, hashes) in tracks.iter().enumerate() {
+         index.insert(track as u32, hashes);
+      }
+      let mut sharded = ShardedIndex::new(3).unwrap();
+      sharded.insert_batch(
+         &tracks
+            .iter()
+            .enumerate()
+            .map(|(track, hashes)| (track as u32, &hashes[..]))
+            .collect::<Vec<_>>(),
+      );
+      (tracks, index, sharded)
+   }
+
+   #[test]
+   fn finds_the_same_matches_as_a_single_index() {
+      let (tracks, index, sharded) = indexes();
+      assert_eq!(sharded.num_shards(), 3);
+      assert_eq!(sharded.num_hashes(), index.num_hashes());
+      assert_eq!(
+         sharded.num_postings(),
+         tracks.iter().map(Vec::len).sum::<usize>()
+      );
+      for track in 0..4 {
+         assert_eq!(sharded.track_length(track), index.track_length(track));
+      }
+
+      for clip in [&tracks[1][200..600], &tracks[3][..300]].iter() {
+         assert_eq!(
+            sharded.histogram(clip),
+            index::histogram(clip, |hash| index.lookup(hash).iter().copied())
+         );
+         assert_eq!(sharded.candidates(clip, 4), index.candidates(clip, 4));
+         assert_eq!(sharded.query(clip), index.query(clip));
+         assert_eq!(sharded.query_all(clip, 20), index.query_all(clip, 20));
+      }
+   }
+
+   #[test]
+   fn keeps_every_hash_in_one_shard() {
+      let (tracks, index, sharded) = indexes();
+      for hash in tracks[0].iter() {
+         let mut postings = sharded.lookup(hash.hash).collect::<Vec<_>>();
+         let mut expected = index.lookup(hash.hash).to_vec();
+         postings.sort_unstable_by_key(|p| (p.track, p.time, p.span));
+         expected.sort_unstable_by_key(|p| (p.track, p.time, p.span));
+         assert_eq!(postings, expected);
+
+         let holding = sharded
+            .shards
+            .iter()
+            .filter(|shard| shard.lookup(hash.hash).next().is_some())
+            .count();
+         assert_eq!(holding, 1);
+      }
+   }
+
+   #[test]
+   fn rejects_zero_shards() {
+      assert!(ShardedIndex::new(0).is_err());
+      assert!(ShardedIndex::with_available_parallelism().num_shards() > 0);
+   }
+}
@@ -0,0 +1,278 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use num_traits::Float;
+
+use crate::{Sample, Wavelet};
+
+/// Number of frequency bands, every pair of adjacent bands yields one bit.
+const BANDS: usize = 33;
+/// Lower edge of the lowest band in Hz.
+const MIN_FREQ: f64 = 300.0;
+/// Upper edge of the highest band in Hz.
+const MAX_FREQ: f64 = 2000.0;
+
+/// Number of sub-fingerprints a query block is compared over.
+pub const BLOCK_LEN: usize = 256;
+/// Bit error rate below which a block is considered a match.
+pub const BER_THRESHOLD: f64 = 0.35;
+
+/// The sub-fingerprinter derives a 32 bit sub-fingerprint from every wavelet,
+/// following Haitsma and Kalker.
+///
+/// # Algorithm
+/// The spectrum between 300 Hz and 2000 Hz is divided into 33 logarithmically spaced bands.
+/// Bit `m` of the sub-fingerprint is set, if the energy difference between band `m`
+/// and band `m + 1` increased compared to the previous wavelet.
+/// Since only the signs of differences are kept, the sub-fingerprints are robust
+/// against equalization and lossy compression.
+pub struct SubFingerprinter<T = f64> {
+   band_edges: Vec<usize>,
+   last_energies: Option<Vec<T>>,
+}
+
+impl<T: Sample> SubFingerprinter<T> {
+   pub fn new(sample_rate: usize, block_size: usize) -> Result<Self, ()> {
+      let bins = block_size / 2;
+      let freqs_per_bin = sample_rate as f64 / block_size as f64;
+
+      // Calculate the band edges and make sure, every band contains at least one bin
+      let mut band_edges = Vec::with_capacity(BANDS + 1);
+      for band in 0..=BANDS {
+         let freq = MIN_FREQ * Float::powf(MAX_FREQ / MIN_FREQ, band as f64 / BANDS as f64);
+         let edge = Float::round(freq / freqs_per_bin) as usize;
+         let edge = match band_edges.last() {
+            Some(last) if edge <= *last => last + 1,
+            _ => edge,
+         };
+         band_edges.push(edge);
+      }
+
+      if *band_edges.last().unwrap() > bins {
+         return Err(());
+      }
+
+      Ok(Self {
+         band_edges,
+         last_energies: None,
+      })
+   }
+
+   /// Computes the sub-fingerprint of the next wavelet.
+   /// Returns `None` for the very first wavelet, since there is nothing to compare to.
+   pub fn process(&mut self, wavelet: &Wavelet<T>) -> Option<u32> {
+      let energies = self
+         .band_edges
+         .windows(2)
+         .map(|edges| {
+            wavelet.bins[edges[0]..edges[1]]
+               .iter()
+               .map(|bin| bin.amplitude * bin.amplitude)
+               .sum::<T>()
+         })
+         .collect::<Vec<_>>();
+
+      let sub_fingerprint = self.last_energies.as_ref().map(|last| {
+         (0..BANDS - 1).fold(0u32, |bits, m| {
+            let diff = (energies[m] - energies[m + 1]) - (last[m] - last[m + 1]);
+            (bits << 1) | (diff > T::zero()) as u32
+         })
+      });
+
+      self.last_energies = Some(energies);
+      sub_fingerprint
+   }
+}
+
+/// A track found by a sub-fingerprint query.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SubFingerprintMatch {
+   pub track: u32,
+   /// The time of the query in the track, in sub-fingerprints.
+   pub offset: i64,
+   /// The fraction of differing bits between the query and the track.
+   pub bit_error_rate: f64,
+}
+
+/// The index of sub-fingerprints of known tracks.
+///
+/// # Algorithm
+/// Under the assumption, that at least one sub-fingerprint of the query is free of errors,
+/// all exact matches of query sub-fingerprints are looked up as candidate positions.
+/// For every candidate, the bit error rate between the query and the track is computed over
+/// up to [`BLOCK_LEN`] sub-fingerprints. The best candidate below [`BER_THRESHOLD`] is the match.
+#[derive(Debug, Clone, Default)]
+pub struct SubFingerprintIndex {
+   tracks: BTreeMap<u32, Vec<u32>>,
+   lookup: BTreeMap<u32, Vec<(u32, u32)>>,
+}
+
+impl SubFingerprintIndex {
+   pub fn new() -> Self {
+      Self::default()
+   }
+
+   /// Adds the sub-fingerprints of a track, replacing the track if it already exists.
+   pub fn insert(&mut self, track: u32, sub_fingerprints: &[u32]) {
+      if self.tracks.contains_key(&track) {
+         self.remove(track);
+      }
+
+      for (time, sub_fingerprint) in sub_fingerprints.iter().enumerate() {
+         self
+            .lookup
+            .entry(*sub_fingerprint)
+            .or_default()
+            .push((track, time as u32));
+      }
+      self.tracks.insert(track, sub_fingerprints.to_vec());
+   }
+
+   /// Removes a track from the index.
+   pub fn remove(&mut self, track: u32) {
+      if let Some(sub_fingerprints) = self.tracks.remove(&track) {
+         for sub_fingerprint in sub_fingerprints {
+            if let Some(positions) = self.lookup.get_mut(&sub_fingerprint) {
+               positions.retain(|(t, _)| *t != track);
+               if positions.is_empty() {
+                  self.lookup.remove(&sub_fingerprint);
+               }
+            }
+         }
+      }
+   }
+
+   /// Returns the track that matches the query best, if its bit error rate is below the threshold.
+   pub fn query(&self, sub_fingerprints: &[u32]) -> Option<SubFingerprintMatch> {
+      let query = &sub_fingerprints[..usize::min(sub_fingerprints.len(), BLOCK_LEN)];
+
+      // Collect the candidate positions
+      let mut candidates = Vec::new();
+      for (time, sub_fingerprint) in query.iter().enumerate() {
+         for (track, track_time) in self.lookup.get(sub_fingerprint).into_iter().flatten() {
+            candidates.push((*track, *track_time as i64 - time as i64));
+         }
+      }
+      candidates.sort_unstable();
+      candidates.dedup();
+
+      candidates
+         .into_iter()
+         .filter_map(|(track, offset)| {
+            let bit_error_rate = bit_error_rate(query, &self.tracks[&track], offset)?;
+            Some(SubFingerprintMatch {
+               track,
+               offset,
+               bit_error_rate,
+            })
+         })
+         .filter(|m| m.bit_error_rate < BER_THRESHOLD)
+         .min_by(|a, b| a.bit_error_rate.total_cmp(&b.bit_error_rate))
+   }
+}
+
+/// Computes the bit error rate between a query and a track, where the query starts at `offset`.
+/// Sub-fingerprints outside of the track count as errors of half the bits.
+/// Returns `None`, if the query does not overlap the track at all.
+pub fn bit_error_rate(query: &[u32], track: &[u32], offset: i64) -> Option<f64> {
+   if query.is_empty() || offset >= track.len() as i64 || offset + (query.len() as i64) <= 0 {
+      return None;
+   }
+
+   let errors = query
+      .iter()
+      .enumerate()
+      .map(|(time, sub_fingerprint)| {
+         let track_time = offset + time as i64;
+         if track_time < 0 || track_time >= track.len() as i64 {
+            16
+         } else {
+            (sub_fingerprint ^ track[track_time as usize]).count_ones()
+         }
+      })
+      .sum::<u32>();
+
+   Some(errors as f64 / (32 * query.len()) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{frequencer::Frequencer, testing};
+
+   const SAMPLE_RATE: usize = 8000;
+   const BLOCK_SIZE: usize = 1024;
+   const STEP_SIZE: usize = 256;
+
+   fn sub_fingerprints(audio: &[f64]) -> Vec<u32> {
+      let mut frequencer = Frequencer::new(SAMPLE_RATE, BLOCK_SIZE, STEP_SIZE).unwrap();
+      let mut sub_fingerprinter = SubFingerprinter::new(SAMPLE_RATE, BLOCK_SIZE).unwrap();
+      audio
+         .chunks_exact(STEP_SIZE)
+         .filter_map(|step| sub_fingerprinter.process(&frequencer.feed_audio(step)))
+         .collect()
+   }
+
+   #[test]
+   fn finds_a_clip_at_its_offset() {
+      let tracks = [
+         testing::noise(400 * STEP_SIZE, 1),
+         testing::noise(400 * STEP_SIZE, 2),
+      ];
+      let mut index = SubFingerprintIndex::new();
+      for (track, audio) in tracks.iter().enumerate() {
+         index.insert(track as u32, &sub_fingerprints(audio));
+      }
+
+      // A noisy clip starting 100 wavelets into the second track
+      let clip = tracks[1][100 * STEP_SIZE..300 * STEP_SIZE]
+         .iter()
+         .zip(testing::noise(200 * STEP_SIZE, 3))
+         .map(|(x, noise)| x + 0.1 * noise)
+         .collect::<Vec<_>>();
+      let found = index.query(&sub_fingerprints(&clip)).unwrap();
+      assert_eq!(found.track, 1);
+      assert_eq!(found.offset, 100);
+      assert!(found.bit_error_rate < 0.1, "{}", found.bit_error_rate);
+   }
+
+   #[test]
+   fn rejects_unknown_audio() {
+      let mut index = SubFingerprintIndex::new();
+      index.insert(0, &sub_fingerprints(&testing::noise(400 * STEP_SIZE, 1)));
+      assert_eq!(
+         index.query(&sub_fingerprints(&testing::noise(200 * STEP_SIZE, 4))),
+         None
+      );
+   }
+
+   #[test]
+   fn removes_and_replaces_tracks() {
+      let mut index = SubFingerprintIndex::new();
+      index.insert(0, &[1, 2, 3]);
+      index.insert(0, &[4, 5, 6]);
+      assert_eq!(index.query(&[1, 2, 3]), None);
+      assert_eq!(index.query(&[5, 6]).unwrap().offset, 1);
+
+      index.remove(0);
+      assert_eq!(index.query(&[4, 5, 6]), None);
+      assert!(index.lookup.is_empty());
+   }
+
+   #[test]
+   fn counts_bits_outside_the_track_as_half_wrong() {
+      let track = [0, u32::MAX, 0];
+      assert_eq!(bit_error_rate(&[0, u32::MAX], &track, 0), Some(0.0));
+      assert_eq!(bit_error_rate(&[u32::MAX, 0], &track, 0), Some(1.0));
+      assert_eq!(bit_error_rate(&[0, u32::MAX], &track, 2), Some(0.25));
+      assert_eq!(bit_error_rate(&[0, u32::MAX], &track, -1), Some(0.75));
+      assert_eq!(bit_error_rate(&[0], &track, 3), None);
+      assert_eq!(bit_error_rate(&[0, 0], &track, -2), None);
+      assert_eq!(bit_error_rate(&[], &track, 0), None);
+   }
+
+   #[test]
+   fn rejects_frames_too_short_for_the_bands() {
+      assert!(SubFingerprinter::<f64>::new(SAMPLE_RATE, 64).is_err());
+      assert!(SubFingerprinter::<f64>::new(SAMPLE_RATE, BLOCK_SIZE).is_ok());
+   }
+}
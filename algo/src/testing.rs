@@ -0,0 +1,69 @@
+//! Signals shared by the tests of the modules.
+
+use alloc::{vec, vec::Vec};
+use core::f64::consts::PI;
+
+/// Returns reproducible white noise in `[-1, 1)`, different for every seed.
+pub fn noise(len: usize, seed: u64) -> Vec<f64> {
+   let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+   (0..len)
+      .map(|_| {
+         // xorshift64*
+         state ^= state >> 12;
+         state ^= state << 25;
+         state ^= state >> 27;
+         let value = state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+         value as f64 / (1u64 << 52) as f64 - 1.0
+      })
+      .collect()
+}
+
+/// Returns a sine of the frequency.
+pub fn sine(frequency: f64, sample_rate: usize, len: usize) -> Vec<f64> {
+   (0..len)
+      .map(|i| (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin())
+      .collect()
+}
+
+/// Returns a reproducible melody of notes between 0.15 s and 0.35 s long with random pitches
+/// between 200 Hz and 2 kHz and an overtone each, whose spectral peaks are as stable as in music.
+/// Quiet noise keeps the peaks of the remaining spectrum from following the rhythm of the notes.
+pub fn melody(sample_rate: usize, len: usize, seed: u64) -> Vec<f64> {
+   let random = noise(len / (sample_rate / 16) + 2, seed);
+   let mut audio = noise(len, !seed);
+   audio.iter_mut().for_each(|x| *x *= 0.01);
+
+   let mut start = 0;
+   for note in random.chunks_exact(2) {
+      let frequency = 200.0 * 10f64.powf((note[0] + 1.0) / 2.0);
+      let note_len = ((0.25 + 0.1 * note[1]) * sample_rate as f64) as usize;
+      for i in 0..usize::min(note_len, len.saturating_sub(start)) {
+         let phase = 2.0 * PI * frequency * i as f64 / sample_rate as f64;
+         let envelope = (PI * i as f64 / note_len as f64).sin();
+         audio[start + i] += envelope * (0.6 * phase.sin() + 0.3 * (1.5 * phase).sin());
+      }
+      start += note_len;
+   }
+   audio
+}
+
+/// Returns reproducible chords of three steady tones between 200 Hz and 3 kHz, that change
+/// every 0.1 s to 0.35 s without a fade. Their flat peaks are matched by chance far more
+/// often than the ones of a melody.
+pub fn chords(sample_rate: usize, len: usize, seed: u64) -> Vec<f64> {
+   let random = noise(len / (sample_rate / 10) + 4, seed);
+   let mut audio = vec![0.0; len];
+
+   let mut start = 0;
+   for chord in random.chunks_exact(4) {
+      let chord_len = ((0.225 + 0.125 * chord[0]) * sample_rate as f64) as usize;
+      for tone in &chord[1..] {
+         let frequency = 1600.0 + 1400.0 * tone;
+         for i in start..usize::min(start + chord_len, len) {
+            audio[i] += 0.2 * (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin();
+         }
+      }
+      start += chord_len;
+   }
+   audio
+}
@@ -0,0 +1,447 @@
+use alloc::{format, string::String, vec::Vec};
+use core::convert::TryFrom;
+
+use crate::{
+   compact::write_varint,
+   config::FingerprintConfig,
+   fingerprint::{self, Fingerprinter},
+   hash::PeakHash,
+};
+
+/// Magic bytes at the start of a binary fingerprint.
+const MAGIC: &[u8; 4] = b"FPQH";
+/// Version of the format, written into every form.
+pub const VERSION: u8 = 1;
+
+/// Largest accepted block size. Checking it before creating the fingerprinter keeps
+/// fingerprints received from elsewhere from allocating huge buffers.
+const MAX_BLOCK_SIZE: u64 = 1 << 16;
+
+/// The URL safe base64 alphabet without padding, the same Chromaprint uses.
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// The hashes of a query together with the parameters they were computed with,
+/// such that it can be sent from one component to another, like from a browser to a server.
+///
+/// # Format
+/// The binary form starts with the magic bytes `FPQH`, the version and the algorithm id
+/// as single bytes, followed by the sample rate, block size, step size, t span, fan out,
+/// min dt and max dt and the list of hashes. The list is the number of hashes and every hash, sorted by time,
+/// with the difference of its time to the previous hash, its span and the hash itself
+/// as a 32 bit little endian integer. All other numbers are variable length integers
+/// with 7 bits per byte.
+///
+/// The base64 form encodes the binary form with the alphabet of Chromaprint.
+/// The JSON form holds the header fields as numbers and the base64 encoded hash list:
+/// ```text
+/// {"version": 1, "algorithm": 1, "sample_rate": 11025, "block_size": 1024, "step_size": 256, "t_span": 10,
+///  "fan_out": 5, "min_dt": 1, "max_dt": 60, "hashes": "..."}
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+   pub fingerprinter: Fingerprinter,
+   /// The hashes, sorted by time when the fingerprint is decoded.
+   pub hashes: Vec<PeakHash>,
+}
+
+impl Fingerprint {
+   pub fn new(fingerprinter: &Fingerprinter, hashes: Vec<PeakHash>) -> Self {
+      Self {
+         fingerprinter: fingerprinter.clone(),
+         hashes,
+      }
+   }
+
+   /// Encodes the fingerprint into its binary form.
+   pub fn to_bytes(&self) -> Vec<u8> {
+      let mut data = MAGIC.to_vec();
+      data.push(VERSION);
+      data.push(fingerprint::ALGORITHM_ID);
+      for value in parameters(self.fingerprinter.config()).iter() {
+         write_varint(&mut data, *value as u64);
+      }
+      data.extend(encode_hashes(&self.hashes));
+      data
+   }
+
+   /// Decodes the binary form of a fingerprint.
+   /// Fails, if the version or the algorithm isn't supported.
+   pub fn from_bytes(data: &[u8]) -> Result<Self, ()> {
+      if data.len() < MAGIC.len() + 2 || &data[..MAGIC.len()] != MAGIC {
+         return Err(());
+      }
+      check_header(data[MAGIC.len()] as u64, data[MAGIC.len() + 1] as u64)?;
+
+      let mut reader = Reader {
+         data: &data[MAGIC.len() + 2..],
+      };
+      let mut values = [None; 7];
+      for value in values.iter_mut() {
+         *value = Some(reader.varint()?);
+      }
+      Ok(Self {
+         fingerprinter: fingerprinter(values)?,
+         hashes: decode_hashes(reader)?,
+      })
+   }
+
+   /// Encodes the fingerprint into its base64 form.
+   pub fn to_base64(&self) -> String {
+      encode_base64(&self.to_bytes())
+   }
+
+   pub fn from_base64(text: &str) -> Result<Self, ()> {
+      Self::from_bytes(&decode_base64(text.trim())?)
+   }
+
+   /// Encodes the fingerprint into its JSON form.
+   pub fn to_json(&self) -> String {
+      let mut text = format!(
+         "{{\"version\": {}, \"algorithm\": {}",
+         VERSION,
+         fingerprint::ALGORITHM_ID
+      );
+      for (name, value) in PARAMETERS
+         .iter()
+         .zip(parameters(self.fingerprinter.config()).iter())
+      {
+         text.push_str(&format!(", \"{}\": {}", name, value));
+      }
+      text.push_str(&format!(
+         ", \"hashes\": \"{}\"}}",
+         encode_base64(&encode_hashes(&self.hashes))
+      ));
+      text
+   }
+
+   /// Decodes the JSON form of a fingerprint. Only flat objects, as written by
+   /// [`to_json`](Self::to_json), are accepted, the order of the fields doesn't matter.
+   pub fn from_json(text: &str) -> Result<Self, ()> {
+      let text = text.trim();
+      let fields = text
+         .strip_prefix('{')
+         .and_then(|text| text.strip_suffix('}'))
+         .ok_or(())?;
+
+      // Neither the numbers nor base64 contain commas or quotes
+      let (mut version, mut algorithm, mut hashes) = (None, None, None);
+      let mut values = [None; 7];
+      for field in fields.split(',') {
+         let (name, value) = field.split_once(':').ok_or(())?;
+         let (name, value) = (unquote(name).ok_or(())?, value.trim());
+         let number = || value.parse::<u64>().map_err(|_| ());
+         match name {
+            "version" => version = Some(number()?),
+            "algorithm" => algorithm = Some(number()?),
+            "hashes" => hashes = Some(value),
+            _ => {
+               if let Some(position) = PARAMETERS.iter().position(|known| *known == name) {
+                  values[position] = Some(number()?);
+               }
+            }
+         }
+      }
+
+      check_header(version.ok_or(())?, algorithm.ok_or(())?)?;
+      let hashes = decode_base64(unquote(hashes.ok_or(())?).ok_or(())?)?;
+      Ok(Self {
+         fingerprinter: fingerprinter(values)?,
+         hashes: decode_hashes(Reader { data: &hashes })?,
+      })
+   }
+
+   /// Decodes a fingerprint in any of its forms, telling them apart by their first bytes.
+   pub fn decode(data: &[u8]) -> Result<Self, ()> {
+      if data.starts_with(MAGIC) {
+         return Self::from_bytes(data);
+      }
+      let text = core::str::from_utf8(data).map_err(|_| ())?;
+      if text.trim_start().starts_with('{') {
+         Self::from_json(text)
+      } else {
+         Self::from_base64(text)
+      }
+   }
+}
+
+/// Names of the parameters in the JSON form, in the order of the binary form.
+const PARAMETERS: [&str; 7] = [
+   "sample_rate",
+   "block_size",
+   "step_size",
+   "t_span",
+   "fan_out",
+   "min_dt",
+   "max_dt",
+];
+
+fn parameters(config: &FingerprintConfig) -> [usize; 7] {
+   [
+      config.sample_rate,
+      config.block_size,
+      config.step_size,
+      config.t_span,
+      config.fan_out,
+      config.min_dt,
+      config.max_dt,
+   ]
+}
+
+/// Creates the fingerprinter from the parameters in the order of [`PARAMETERS`],
+/// which are all required.
+fn fingerprinter(values: [Option<u64>; 7]) -> Result<Fingerprinter, ()> {
+   let mut parameters = [0; 7];
+   for (parameter, value) in parameters.iter_mut().zip(values.iter()) {
+      *parameter = usize::try_from(value.ok_or(())?).map_err(|_| ())?;
+   }
+   if parameters[1] as u64 > MAX_BLOCK_SIZE {
+      return Err(());
+   }
+
+   let [sample_rate, block_size, step_size, t_span, fan_out, min_dt, max_dt] = parameters;
+   Fingerprinter::with_config(FingerprintConfig {
+      sample_rate,
+      block_size,
+      step_size,
+      t_span,
+      fan_out,
+      min_dt,
+      max_dt,
+   })
+}
+
+/// Makes sure, the fingerprint was written by a supported version and with the same hashes.
+fn check_header(version: u64, algorithm: u64) -> Result<(), ()> {
+   if version != VERSION as u64 || algorithm != fingerprint::ALGORITHM_ID as u64 {
+      return Err(());
+   }
+   Ok(())
+}
+
+fn encode_hashes(hashes: &[PeakHash]) -> Vec<u8> {
+   let mut hashes = hashes.to_vec();
+   hashes.sort_unstable_by_key(|hash| (hash.time, hash.hash, hash.span));
+
+   let mut data = Vec::with_capacity(6 * hashes.len() + 4);
+   write_varint(&mut data, hashes.len() as u64);
+   let mut last_time = 0;
+   for hash in hashes {
+      write_varint(&mut data, (hash.time - last_time) as u64);
+      write_varint(&mut data, hash.span as u64);
+      data.extend_from_slice(&hash.hash.to_le_bytes());
+      last_time = hash.time;
+   }
+   data
+}
+
+fn decode_hashes(mut reader: Reader<'_>) -> Result<Vec<PeakHash>, ()> {
+   let num_hashes = usize::try_from(reader.varint()?).map_err(|_| ())?;
+   let mut hashes = Vec::with_capacity(usize::min(num_hashes, reader.data.len()));
+   let mut time = 0u32;
+   for _ in 0..num_hashes {
+      let delta = u32::try_from(reader.varint()?).map_err(|_| ())?;
+      time = time.checked_add(delta).ok_or(())?;
+      let span = u32::try_from(reader.varint()?).map_err(|_| ())?;
+      let hash = reader.u32()?;
+      hashes.push(PeakHash { hash, time, span });
+   }
+
+   if !reader.data.is_empty() {
+      return Err(());
+   }
+   Ok(hashes)
+}
+
+/// Returns the text between the quotes of a JSON string without escapes.
+fn unquote(text: &str) -> Option<&str> {
+   text
+      .trim()
+      .strip_prefix('"')?
+      .strip_suffix('"')
+      .filter(|text| !text.contains(&['"', '\\'][..]))
+}
+
+/// Encodes data with the URL safe base64 alphabet without padding.
+pub(crate) fn encode_base64(data: &[u8]) -> String {
+   let mut output = String::with_capacity((data.len() * 4).div_ceil(3));
+   for chunk in data.chunks(3) {
+      let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+         bits | (*byte as u32) << (16 - 8 * i)
+      });
+      for i in 0..=chunk.len() {
+         output.push(BASE64[(bits >> (18 - 6 * i) & 63) as usize] as char);
+      }
+   }
+   output
+}
+
+/// Decodes data encoded by [`encode_base64`]. Fails on characters outside of the alphabet
+/// and on a single character left over, which can't hold a byte.
+pub(crate) fn decode_base64(encoded: &str) -> Result<Vec<u8>, ()> {
+   if encoded.len() % 4 == 1 {
+      return Err(());
+   }
+   let mut data = Vec::with_capacity(encoded.len() * 3 / 4);
+   let (mut bits, mut count) = (0u32, 0);
+   for c in encoded.bytes() {
+      let value = BASE64.iter().position(|b| *b == c).ok_or(())? as u32;
+      bits = bits << 6 | value;
+      count += 6;
+      if count >= 8 {
+         count -= 8;
+         data.push((bits >> count) as u8);
+      }
+   }
+   Ok(data)
+}
+
+/// Reads the values of a binary fingerprint, failing if the data ends early.
+struct Reader<'a> {
+   data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+   fn u32(&mut self) -> Result<u32, ()> {
+      if self.data.len() < 4 {
+         return Err(());
+      }
+      let (bytes, rest) = self.data.split_at(4);
+      self.data = rest;
+      Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+   }
+
+   fn varint(&mut self) -> Result<u64, ()> {
+      let mut value = 0;
+      for shift in (0..64).step_by(7) {
+         let (byte, rest) = self.data.split_first().ok_or(())?;
+         self.data = rest;
+         value |= ((byte & 0x7f) as u64) << shift;
+         if byte & 0x80 == 0 {
+            return Ok(value);
+         }
+      }
+      Err(())
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::config::FingerprintConfig;
+
+   fn fingerprint() -> Fingerprint {
+      let fingerprinter = Fingerprinter::with_config(FingerprintConfig::speech()).unwrap();
+      let hashes = (0..50)
+         .map(|i| PeakHash {
+            hash: i * 0x0101_0101,
+            time: i * i,
+            span: i % 9,
+         })
+         .collect();
+      Fingerprint::new(&fingerprinter, hashes)
+   }
+
+   #[test]
+   fn round_trips_every_form() {
+      let fingerprint = fingerprint();
+      assert_eq!(
+         Fingerprint::from_bytes(&fingerprint.to_bytes()),
+         Ok(fingerprint.clone())
+      );
+      assert_eq!(
+         Fingerprint::from_base64(&fingerprint.to_base64()),
+         Ok(fingerprint.clone())
+      );
+      assert_eq!(
+         Fingerprint::from_json(&fingerprint.to_json()),
+         Ok(fingerprint.clone())
+      );
+
+      // The hashes come back sorted by time
+      let mut reversed = fingerprint.clone();
+      reversed.hashes.reverse();
+      assert_eq!(
+         Fingerprint::from_bytes(&reversed.to_bytes()),
+         Ok(fingerprint)
+      );
+   }
+
+   #[test]
+   fn tells_the_forms_apart() {
+      let fingerprint = fingerprint();
+      for data in [
+         fingerprint.to_bytes(),
+         fingerprint.to_base64().into_bytes(),
+         format!("{}\n", fingerprint.to_base64()).into_bytes(),
+         fingerprint.to_json().into_bytes(),
+         format!("  {}\n", fingerprint.to_json()).into_bytes(),
+      ]
+      .iter()
+      {
+         assert_eq!(Fingerprint::decode(data), Ok(fingerprint.clone()));
+      }
+   }
+
+   #[test]
+   fn rejects_truncated_and_invalid_input() {
+      let fingerprint = fingerprint();
+      let bytes = fingerprint.to_bytes();
+      for length in [0, 3, 4, 6, bytes.len() / 2, bytes.len() - 1].iter() {
+         assert!(
+            Fingerprint::from_bytes(&bytes[..*length]).is_err(),
+            "{}",
+            length
+         );
+      }
+      let mut trailing = bytes.clone();
+      trailing.push(0);
+      assert!(Fingerprint::from_bytes(&trailing).is_err());
+
+      // Another version or algorithm
+      let mut other = bytes.clone();
+      other[MAGIC.len()] = VERSION + 1;
+      assert!(Fingerprint::from_bytes(&other).is_err());
+      let mut other = bytes;
+      other[MAGIC.len() + 1] ^= 0xff;
+      assert!(Fingerprint::from_bytes(&other).is_err());
+
+      // A time difference, that doesn't fit into 32 bits
+      let mut data = MAGIC.to_vec();
+      data.extend_from_slice(&[VERSION, fingerprint::ALGORITHM_ID]);
+      for value in parameters(fingerprint.fingerprinter.config()).iter() {
+         write_varint(&mut data, *value as u64);
+      }
+      write_varint(&mut data, 1);
+      write_varint(&mut data, 1 << 32);
+      write_varint(&mut data, 0);
+      data.extend_from_slice(&[0; 4]);
+      assert!(Fingerprint::from_bytes(&data).is_err());
+
+      let base64 = fingerprint.to_base64();
+      assert!(Fingerprint::from_base64(&base64[..base64.len() - 1]).is_err());
+      assert!(Fingerprint::from_base64("not base64!").is_err());
+      let json = fingerprint.to_json();
+      assert!(Fingerprint::from_json(&json[..json.len() / 2]).is_err());
+      assert!(Fingerprint::from_json(&json.replace("\"fan_out\"", "\"fan_in\"")).is_err());
+      assert!(Fingerprint::decode(b"\xff\xfe").is_err());
+      assert!(Fingerprint::decode(b"").is_err());
+   }
+
+   #[test]
+   fn encodes_base64_like_chromaprint() {
+      for (data, encoded) in [
+         (&b""[..], ""),
+         (b"x", "eA"),
+         (b"xx", "eHg"),
+         (b"xxx", "eHh4"),
+         (&[0xfb, 0xff, 0xbf], "-_-_"),
+      ]
+      .iter()
+      {
+         assert_eq!(encode_base64(data), *encoded);
+         assert_eq!(decode_base64(encoded).as_deref(), Ok(*data));
+      }
+      assert!(decode_base64("eHh4e").is_err());
+      assert!(decode_base64("eH+4").is_err());
+   }
+}
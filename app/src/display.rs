@@ -1,4 +1,4 @@
-use algo::{FrequencyFeature, Wavelet};
+use algo::{config::FingerprintConfig, FrequencyFeature, Wavelet};
 use alloc::collections::VecDeque;
 use std::{
    collections::HashMap,
@@ -11,8 +11,8 @@ use crate::AppResult;
 
 #[derive(Debug, Clone)]
 pub enum DisplayMessage {
-   Wavelet(Wavelet),
-   Feature(Vec<FrequencyFeature>),
+   Wavelet(Wavelet<f32>),
+   Feature(Vec<FrequencyFeature<f32>>),
 }
 
 #[derive(Debug, Clone)]
@@ -20,6 +20,8 @@ pub struct DisplayConfig {
    pub canvas_name: String,
    pub display_size: usize,
    pub display_height: usize,
+   /// The configuration of the analyzed wavelets and features.
+   pub fingerprint: FingerprintConfig,
 }
 
 #[derive(Debug)]
@@ -28,8 +30,8 @@ pub struct DisplayState {
    tx: Sender<DisplayMessage>,
    rx: Receiver<DisplayMessage>,
    time: usize,
-   wavelets: VecDeque<Wavelet>,
-   features: HashMap<usize, FrequencyFeature>,
+   wavelets: VecDeque<Wavelet<f32>>,
+   features: HashMap<usize, FrequencyFeature<f32>>,
 }
 
 impl DisplayState {
@@ -63,7 +65,7 @@ impl DisplayState {
             let amplitude = wavelet
                .bins
                .get(index)
-               .map(|bin| bin.amplitude)
+               .map(|bin| bin.amplitude as f64)
                .unwrap_or(0.0);
 
             // Clamp down the red value
@@ -89,11 +91,12 @@ impl DisplayState {
             true => self.config.display_size - (self.time - time),
             false => *time,
          };
-         let y = feature.bin_index * self.config.display_height / (crate::BLOCK_SIZE / 2);
+         let y = feature.bin_index * self.config.display_height
+            / (self.config.fingerprint.block_size / 2);
          let img_index = 4 * (x + y * self.config.display_size);
 
          // Paint the pixel under the feature green
-         let amplitude = feature.amplitude;
+         let amplitude = feature.amplitude as f64;
          let green = 255f64 * (2f64.powf(-amplitude)) * (2f64.powf(amplitude) - 1f64);
          let green = if green < 255f64 { green as u8 } else { 255 };
 
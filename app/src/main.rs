@@ -1,26 +1,37 @@
-#![recursion_limit = "256"]
+#![recursion_limit = "512"]
 #![allow(dead_code)]
 
 extern crate alloc;
 
 mod display;
+mod matcher;
 mod pipeline;
+mod storage;
 
 use core::time::Duration;
 use js_sys::Error as JsError;
+use std::{
+    rc::Rc,
+    sync::mpsc::{channel, Receiver, Sender},
+};
 use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{AudioContext, File};
 use yew::prelude::*;
 use yew::services::interval::{IntervalService, IntervalTask};
 use yewtil::future::LinkFuture;
 
+use algo::{channel::Downmix, config::FingerprintConfig, database::DatabaseSnapshot};
+
 use crate::{
     display::{DisplayConfig, DisplayState},
+    matcher::{LocalMatcher, Matcher, RemoteMatcher},
     pipeline::Pipeline,
 };
 
-pub const BLOCK_SIZE: usize = 4096;
-pub const STEP_SIZE: usize = 1024;
-pub const T_SPAN: usize = 50;
+pub const NOISE_LEARN_FRAMES: usize = 20;
+pub const INPUT_CHANNELS: usize = 2;
+pub const DOWNMIX: Downmix = Downmix::Mid;
+pub const DEFAULT_ENDPOINT: &str = "http://127.0.0.1:8080";
 
 type AppResult<T> = Result<T, Box<dyn std::error::Error>>;
 
@@ -35,6 +46,15 @@ fn js_err(value: JsValue, alt: &str) -> String {
 
 enum Msg {
     PlayButtonPress,
+    DenoiseButtonPress,
+    MatchButtonPress,
+    EndpointInput(String),
+    MatcherConnected(Result<Box<RemoteMatcher>, String>),
+    OfflineButtonPress,
+    DatabaseFile(File),
+    DatabaseUrlInput(String),
+    DatabaseUrlLoad,
+    DatabaseLoaded(Result<Option<DatabaseSnapshot>, String>),
     PipelineStarted,
     DisplayUpdate,
 }
@@ -44,7 +64,21 @@ struct Model {
     interval: Option<IntervalTask>,
     pipeline: Pipeline,
     started: bool,
+    denoise: bool,
     display: DisplayState,
+    /// The server, that the hashes are matched on.
+    endpoint: String,
+    matching: bool,
+    /// The latest answer of the server.
+    matched: Option<String>,
+    match_tx: Sender<String>,
+    match_rx: Receiver<String>,
+    /// Match against the database in the browser instead of the server.
+    offline: bool,
+    database: Option<Rc<DatabaseSnapshot>>,
+    database_url: String,
+    /// The state of the database in the browser.
+    database_status: String,
 }
 
 impl Component for Model {
@@ -52,20 +86,44 @@ impl Component for Model {
     type Properties = ();
 
     fn create(_props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let audio_context = AudioContext::new()
+            .map_err(|val| js_err(val, &"failed to establish audio context"))
+            .unwrap();
+        // Analyze the audio at the rate of the browser with wavelets of the usual length
+        let fingerprint =
+            FingerprintConfig::music().scaled_to(audio_context.sample_rate() as u32 as usize);
+
         let display = DisplayState::new(DisplayConfig {
             canvas_name: "display".to_string(),
             display_size: 600,
             display_height: 400,
+            fingerprint: fingerprint.clone(),
         });
 
-        let pipeline = Pipeline::new(display.sender()).unwrap();
+        let pipeline = Pipeline::new(audio_context, fingerprint, display.sender()).unwrap();
+        let (match_tx, match_rx) = channel();
+
+        // Load the database kept from the last visit
+        link.send_future(async {
+            Msg::DatabaseLoaded(storage::restore().await.map_err(|err| err.to_string()))
+        });
 
         Self {
             link,
             interval: None,
             pipeline,
             started: false,
+            denoise: false,
             display,
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            matching: false,
+            matched: None,
+            match_tx,
+            match_rx,
+            offline: false,
+            database: None,
+            database_url: String::new(),
+            database_status: "no database".to_string(),
         }
     }
 
@@ -84,6 +142,119 @@ impl Component for Model {
                 self.started = true;
                 false
             }
+            Msg::DenoiseButtonPress => {
+                self.denoise = !self.denoise;
+                self.pipeline.set_denoise(self.denoise);
+                true
+            }
+            Msg::MatchButtonPress => {
+                self.matching = !self.matching;
+                if !self.matching {
+                    self.pipeline.set_matcher(None);
+                    self.matched = None;
+                    return true;
+                }
+                if self.offline {
+                    let database = match &self.database {
+                        Some(database) => database.clone(),
+                        None => {
+                            self.matching = false;
+                            self.matched = Some("load a database first".to_string());
+                            return true;
+                        }
+                    };
+                    let sample_rate = self.pipeline.sample_rate();
+                    match LocalMatcher::new(database, sample_rate, self.match_tx.clone()) {
+                        Ok(matcher) => {
+                            self.pipeline.set_matcher(Some(Matcher::Local(matcher)));
+                            self.matched = Some("listening".to_string());
+                        }
+                        Err(err) => {
+                            self.matching = false;
+                            self.matched = Some(err.to_string());
+                        }
+                    }
+                    return true;
+                }
+                // Ask the server for its parameters before sending any hashes
+                let endpoint = self.endpoint.clone();
+                let sample_rate = self.pipeline.sample_rate();
+                let results = self.match_tx.clone();
+                self.link.send_future(async move {
+                    let matcher = RemoteMatcher::connect(endpoint, sample_rate, results).await;
+                    Msg::MatcherConnected(matcher.map(Box::new).map_err(|err| err.to_string()))
+                });
+                self.matched = Some("connecting".to_string());
+                true
+            }
+            Msg::EndpointInput(endpoint) => {
+                self.endpoint = endpoint;
+                false
+            }
+            Msg::MatcherConnected(matcher) => {
+                // Matching may have been switched off in the meantime
+                if !self.matching {
+                    return false;
+                }
+                match matcher {
+                    Ok(matcher) => {
+                        self.pipeline.set_matcher(Some(Matcher::Remote(*matcher)));
+                        self.matched = Some("listening".to_string());
+                    }
+                    Err(err) => {
+                        self.matching = false;
+                        self.matched = Some(err);
+                    }
+                }
+                true
+            }
+            Msg::OfflineButtonPress => {
+                // Switching the source stops the matching
+                self.offline = !self.offline;
+                self.matching = false;
+                self.matched = None;
+                self.pipeline.set_matcher(None);
+                true
+            }
+            Msg::DatabaseFile(file) => {
+                self.link.send_future(async move {
+                    let result = match storage::read_file(&file).await {
+                        Ok(data) => storage::install(data).await.map(Some),
+                        Err(err) => Err(err),
+                    };
+                    Msg::DatabaseLoaded(result.map_err(|err| err.to_string()))
+                });
+                self.database_status = "loading database".to_string();
+                true
+            }
+            Msg::DatabaseUrlInput(url) => {
+                self.database_url = url;
+                false
+            }
+            Msg::DatabaseUrlLoad => {
+                let url = self.database_url.clone();
+                self.link.send_future(async move {
+                    let result = match storage::download(&url).await {
+                        Ok(data) => storage::install(data).await.map(Some),
+                        Err(err) => Err(err),
+                    };
+                    Msg::DatabaseLoaded(result.map_err(|err| err.to_string()))
+                });
+                self.database_status = "downloading database".to_string();
+                true
+            }
+            Msg::DatabaseLoaded(database) => {
+                match database {
+                    Ok(Some(database)) => {
+                        self.database_status = format!("{} tracks offline", database.num_tracks());
+                        self.database = Some(Rc::new(database));
+                    }
+                    // Nothing was stored yet
+                    Ok(None) => return false,
+                    Err(err) => self.database_status = err,
+                }
+                true
+            }
             Msg::PipelineStarted => {
                 // Start the display update interval
                 self.interval = Some(IntervalService::spawn(
@@ -95,7 +266,15 @@ impl Component for Model {
             // update the display
             Msg::DisplayUpdate => {
                 self.display.update();
-                false
+
+                // Show the latest answer of the server
+                match self.match_rx.try_iter().last() {
+                    Some(matched) if self.matching => {
+                        self.matched = Some(matched);
+                        true
+                    }
+                    _ => false,
+                }
             }
             #[allow(unreachable_patterns)]
             _ => false,
@@ -112,12 +291,49 @@ impl Component for Model {
                 <button onclick = self.link.callback(|_|Msg::PlayButtonPress)>
                     {"Start"}
                 </button>
+                <button onclick = self.link.callback(|_|Msg::DenoiseButtonPress)>
+                    {if self.denoise { "Denoise: on" } else { "Denoise: off" }}
+                </button>
+                <input
+                    value = self.endpoint.clone()
+                    oninput = self.link.callback(|e: InputData| Msg::EndpointInput(e.value))
+                />
+                <button onclick = self.link.callback(|_|Msg::MatchButtonPress)>
+                    {if self.matching { "Match: on" } else { "Match: off" }}
+                </button>
+                <button onclick = self.link.callback(|_|Msg::OfflineButtonPress)>
+                    {if self.offline { "Offline: on" } else { "Offline: off" }}
+                </button>
+                {self.matched.clone().unwrap_or_default()}
+                <div>
+                    <input
+                        type = "file"
+                        onchange = self.link.batch_callback(database_file)
+                    />
+                    <input
+                        value = self.database_url.clone()
+                        placeholder = "database URL"
+                        oninput = self.link.callback(|e: InputData| Msg::DatabaseUrlInput(e.value))
+                    />
+                    <button onclick = self.link.callback(|_|Msg::DatabaseUrlLoad)>
+                        {"Load"}
+                    </button>
+                    {&self.database_status}
+                </div>
                 <canvas id="display" style="width:1200;height:800;"/>
             </div>
         }
     }
 }
 
+/// Turns the file chosen by the user into a message.
+fn database_file(change: ChangeData) -> Vec<Msg> {
+    match change {
+        ChangeData::Files(files) => files.get(0).map(Msg::DatabaseFile).into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
 fn main() {
     yew::start_app::<Model>();
 }
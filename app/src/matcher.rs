@@ -0,0 +1,339 @@
+use algo::{
+   config::FingerprintConfig,
+   database::DatabaseSnapshot,
+   feature::FeatureFinder,
+   fingerprint::{FingerprintStream, Fingerprinter},
+   frequencer::Frequencer,
+   hash::{PeakHash, PeakHasher},
+   index::Match,
+   resample::Resampler,
+   session::{QuerySession, SessionEvent},
+   transport::Fingerprint,
+   Algorithm,
+};
+use alloc::collections::VecDeque;
+use core::cell::Cell;
+use js_sys::{Reflect, Uint8Array};
+use std::{rc::Rc, sync::mpsc::Sender};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{Request, RequestInit, Response};
+
+use crate::{js_err, AppResult};
+
+/// Seconds of hashes sent with every request.
+const WINDOW_SECONDS: f64 = 8.0;
+/// Seconds of audio between two requests.
+const SEND_INTERVAL_SECONDS: f64 = 2.0;
+/// Seconds, after which a local identification attempt gives up and starts over.
+const SESSION_TIMEOUT_SECONDS: f64 = 15.0;
+
+/// Identifies the recording, either on a server or against a database in the browser.
+pub enum Matcher {
+   Remote(RemoteMatcher),
+   Local(LocalMatcher),
+}
+
+impl Matcher {
+   /// Fingerprints the next samples of the recording and reports what is identified.
+   pub fn feed(&mut self, audio: &[f32]) {
+      match self {
+         Matcher::Remote(matcher) => matcher.feed(audio),
+         Matcher::Local(matcher) => matcher.feed(audio),
+      }
+   }
+}
+
+/// Fingerprints the microphone in the browser and lets a server find the track.
+/// Only the hashes of the last seconds are sent, never the audio itself.
+pub struct RemoteMatcher {
+   endpoint: String,
+   fingerprinter: Fingerprinter,
+   window: HashWindow,
+   /// Set while a request is running, such that slow servers don't pile up requests.
+   in_flight: Rc<Cell<bool>>,
+   results: Sender<String>,
+}
+
+/// Fingerprints the microphone and finds the track in a database held by the browser,
+/// such that nothing leaves the device.
+///
+/// The recording runs through a [`QuerySession`], which reports candidates as they come up
+/// and starts over, once it is confident or gives up.
+pub struct LocalMatcher {
+   database: Rc<DatabaseSnapshot>,
+   resampler: Resampler,
+   frequencer: Frequencer<f64>,
+   feature_finder: FeatureFinder<f64>,
+   /// Audio, that doesn't fill a whole step yet.
+   pending: Vec<f64>,
+   session: QuerySession,
+   results: Sender<String>,
+}
+
+/// Computes the hashes of the recording and keeps those of the last `WINDOW_SECONDS`,
+/// which are sent to the server. The server scores every window on its own.
+struct HashWindow {
+   resampler: Resampler,
+   stream: FingerprintStream<f64>,
+   hashes: VecDeque<PeakHash>,
+   window_length: u32,
+   send_interval: u32,
+   /// The time of the newest hash of the last query.
+   last_sent: u32,
+}
+
+impl RemoteMatcher {
+   /// Asks the server at `endpoint` for its parameters and creates a matcher computing the
+   /// same hashes from audio at `sample_rate`.
+   pub async fn connect(
+      endpoint: String,
+      sample_rate: usize,
+      results: Sender<String>,
+   ) -> AppResult<Self> {
+      let endpoint = String::from(endpoint.trim_end_matches('/'));
+      let stats = fetch_json(&format!("{}/stats", endpoint), None).await?;
+      let parameters = get(&stats, "parameters");
+      let optional = |name: &str| get(&parameters, name).as_f64().map(|value| value as usize);
+      let parameter =
+         |name: &str| optional(name).ok_or_else(|| format!("the server reports no {}", name));
+
+      // Servers, that predate the hashing parameters, use the default ones
+      let default = FingerprintConfig::default();
+      let fingerprinter = Fingerprinter::with_config(FingerprintConfig {
+         sample_rate: parameter("sample_rate")?,
+         block_size: parameter("block_size")?,
+         step_size: parameter("step_size")?,
+         t_span: parameter("t_span")?,
+         fan_out: optional("fan_out").unwrap_or(default.fan_out),
+         min_dt: optional("min_dt").unwrap_or(default.min_dt),
+         max_dt: optional("max_dt").unwrap_or(default.max_dt),
+      })
+      .map_err(|_| "the server uses unsupported parameters")?;
+
+      Ok(Self {
+         endpoint,
+         window: HashWindow::new(&fingerprinter, sample_rate)?,
+         fingerprinter,
+         in_flight: Rc::new(Cell::new(false)),
+         results,
+      })
+   }
+
+   fn feed(&mut self, audio: &[f32]) {
+      if let Some(hashes) = self.window.feed(audio, !self.in_flight.get()) {
+         self.send(hashes);
+      }
+   }
+
+   /// Posts the hashes to the server and reports the best match, if any.
+   fn send(&self, hashes: Vec<PeakHash>) {
+      let body = Fingerprint::new(&self.fingerprinter, hashes).to_bytes();
+      let url = format!("{}/identify/fingerprint", self.endpoint);
+      let in_flight = self.in_flight.clone();
+      let results = self.results.clone();
+      in_flight.set(true);
+      spawn_local(async move {
+         let result = match fetch_json(&url, Some(&body)).await {
+            Ok(response) => describe_response(&response),
+            Err(err) => format!("matching failed: {}", err),
+         };
+         in_flight.set(false);
+         // The receiver is gone, if the app shuts down
+         let _ = results.send(result);
+      });
+   }
+}
+
+impl LocalMatcher {
+   /// Creates a matcher computing the hashes of the database from audio at `sample_rate`.
+   /// Fails, if the database holds other than constellation hashes, which the session computes.
+   pub fn new(
+      database: Rc<DatabaseSnapshot>,
+      sample_rate: usize,
+      results: Sender<String>,
+   ) -> AppResult<Self> {
+      if database.algorithm() != Algorithm::Constellation {
+         return Err("the database holds hashes of another algorithm".into());
+      }
+      let fingerprinter = database.fingerprinter();
+      let config = fingerprinter.config();
+      let resampler = Resampler::new(sample_rate, fingerprinter.sample_rate())
+         .map_err(|_| "unsupported sample rate")?;
+      let frequencer = Frequencer::new(config.sample_rate, config.block_size, config.step_size)
+         .map_err(|_| "the database uses unsupported parameters")?;
+      let hasher = PeakHasher::new(config.fan_out, config.min_dt, config.max_dt)
+         .map_err(|_| "the database uses unsupported parameters")?;
+      let session = QuerySession::new(
+         hasher,
+         fingerprinter.wavelets_per_second(),
+         SESSION_TIMEOUT_SECONDS,
+      )
+      .map_err(|_| "the database uses unsupported parameters")?;
+
+      Ok(Self {
+         resampler,
+         frequencer,
+         feature_finder: FeatureFinder::new(config.block_size, config.t_span),
+         pending: Vec::with_capacity(config.step_size),
+         session,
+         database,
+         results,
+      })
+   }
+
+   fn feed(&mut self, audio: &[f32]) {
+      let audio = audio
+         .iter()
+         .map(|&sample| sample as f64)
+         .collect::<Vec<_>>();
+      let step_size = self.frequencer.step_size();
+
+      for sample in self.resampler.process(&audio) {
+         self.pending.push(sample);
+         if self.pending.len() < step_size {
+            continue;
+         }
+
+         let wavelet = self.frequencer.feed_audio(&self.pending);
+         self.pending.clear();
+         let features = self.feature_finder.process(wavelet);
+         let result = match self.session.process(self.database.index(), &features) {
+            Some(SessionEvent::Candidate(found)) => self.describe(found, "candidate"),
+            Some(SessionEvent::Confident(found)) => self.describe(found, "confident"),
+            Some(SessionEvent::NoMatch) => String::from("no match"),
+            None => continue,
+         };
+
+         // Start over, such that the next track is identified as well
+         if self.session.is_finished() {
+            self.session.reset();
+         }
+         let _ = self.results.send(result);
+      }
+   }
+
+   fn describe(&self, found: Match, confidence: &str) -> String {
+      match self.database.resolve(found.track, ()) {
+         Some(found) => describe(
+            found.metadata.title.clone(),
+            found.metadata.artist.clone(),
+            found.key,
+            confidence,
+         ),
+         None => String::from("no match"),
+      }
+   }
+}
+
+impl HashWindow {
+   fn new(fingerprinter: &Fingerprinter, sample_rate: usize) -> AppResult<Self> {
+      let resampler = Resampler::new(sample_rate, fingerprinter.sample_rate())
+         .map_err(|_| "unsupported sample rate")?;
+
+      let wavelets_per_second = fingerprinter.wavelets_per_second();
+      Ok(Self {
+         resampler,
+         stream: fingerprinter.stream(),
+         hashes: VecDeque::new(),
+         window_length: (WINDOW_SECONDS * wavelets_per_second) as u32,
+         send_interval: (SEND_INTERVAL_SECONDS * wavelets_per_second) as u32,
+         last_sent: 0,
+      })
+   }
+
+   /// Feeds the next samples and returns the hashes of the window, if a query is due
+   /// and the matcher is `ready` for it.
+   fn feed(&mut self, audio: &[f32], ready: bool) -> Option<Vec<PeakHash>> {
+      let audio = audio
+         .iter()
+         .map(|&sample| sample as f64)
+         .collect::<Vec<_>>();
+      let audio = self.resampler.process(&audio);
+      self.hashes.extend(self.stream.feed(&audio));
+
+      let newest = self.hashes.back()?.time;
+      while self
+         .hashes
+         .front()
+         .is_some_and(|hash| hash.time + self.window_length < newest)
+      {
+         self.hashes.pop_front();
+      }
+
+      if newest < self.last_sent + self.send_interval || !ready {
+         return None;
+      }
+      self.last_sent = newest;
+      Some(self.hashes.iter().copied().collect())
+   }
+}
+
+/// Formats the best match of an identification response.
+fn describe_response(response: &JsValue) -> String {
+   let best =
+      Reflect::get(&get(response, "matches"), &JsValue::from(0)).unwrap_or(JsValue::UNDEFINED);
+   if best.is_undefined() {
+      return String::from("no match");
+   }
+
+   let text = |name: &str| get(&best, name).as_string();
+   describe(
+      text("title"),
+      text("artist"),
+      &text("key").unwrap_or_default(),
+      &format!(
+         "{:.0}%",
+         100.0 * get(&best, "probability").as_f64().unwrap_or(0.0)
+      ),
+   )
+}
+
+/// Names a track by its artist and title, or by its key if there is no title,
+/// followed by how confident the match is.
+fn describe(title: Option<String>, artist: Option<String>, key: &str, confidence: &str) -> String {
+   let name = match (title, artist) {
+      (Some(title), Some(artist)) => format!("{} - {}", artist, title),
+      (Some(title), None) => title,
+      _ => String::from(key),
+   };
+   format!("{} ({})", name, confidence)
+}
+
+/// Fetches a JSON document, with a POST request if there is a body.
+async fn fetch_json(url: &str, body: Option<&[u8]>) -> AppResult<JsValue> {
+   let response = fetch(url, body).await?;
+   let json = response
+      .json()
+      .map_err(|val| js_err(val, &"failed to read response"))?;
+   Ok(JsFuture::from(json)
+      .await
+      .map_err(|val| js_err(val, &"invalid JSON"))?)
+}
+
+/// Sends a request, a POST request if there is a body, and fails unless it succeeds.
+pub async fn fetch(url: &str, body: Option<&[u8]>) -> AppResult<Response> {
+   let init = RequestInit::new();
+   if let Some(body) = body {
+      init.set_method("POST");
+      init.set_body(&Uint8Array::from(body));
+   }
+   let request = Request::new_with_str_and_init(url, &init)
+      .map_err(|val| js_err(val, &"failed to create request"))?;
+
+   let response = JsFuture::from(web_sys::window().unwrap().fetch_with_request(&request))
+      .await
+      .map_err(|val| js_err(val, &"failed to reach the server"))?;
+   let response = response
+      .dyn_into::<Response>()
+      .map_err(|val| js_err(val, &"invalid response"))?;
+   if !response.ok() {
+      return Err(format!("the server answered with status {}", response.status()).into());
+   }
+   Ok(response)
+}
+
+/// Returns a property of a JSON object, or `undefined`.
+fn get(object: &JsValue, name: &str) -> JsValue {
+   Reflect::get(object, &JsValue::from_str(name)).unwrap_or(JsValue::UNDEFINED)
+}
@@ -1,4 +1,7 @@
-use algo::{feature::FeatureFinder, frequencer::Frequencer};
+use algo::{
+   channel::ChannelMixer, config::FingerprintConfig, denoise::Denoiser, feature::FeatureFinder,
+   frequencer::Frequencer,
+};
 use core::cell::RefCell;
 use std::{rc::Rc, sync::mpsc::Sender};
 use wasm_bindgen::{closure::Closure, JsCast, JsValue};
@@ -9,6 +12,7 @@ use web_sys::{
 };
 
 use crate::display::DisplayMessage;
+use crate::matcher::Matcher;
 use crate::{js_err, AppResult};
 
 #[derive(Clone)]
@@ -16,19 +20,41 @@ pub struct Pipeline(Rc<RefCell<PipelineInner>>);
 
 pub struct PipelineInner {
    audio_context: AudioContext,
+   /// The parameters of the frequencer and the feature finder of every stream.
+   config: FingerprintConfig,
    script_processor: ScriptProcessorNode,
    proc_pipeline: Option<AudioNode>,
-   frequencer: Frequencer,
-   feature_finder: FeatureFinder,
+   mixer: ChannelMixer,
+   channels: Vec<ChannelPipeline>,
+   denoise: bool,
    display: Sender<DisplayMessage>,
+   /// Identifies the first stream, if set.
+   matcher: Option<Matcher>,
+}
+
+/// The processing state of a single mono stream coming out of the channel mixer.
+struct ChannelPipeline {
+   frequencer: Frequencer<f32>,
+   denoiser: Denoiser<f32>,
+   feature_finder: FeatureFinder<f32>,
 }
 
 impl Pipeline {
-   pub fn new(display: Sender<DisplayMessage>) -> AppResult<Self> {
-      let audio_context =
-         AudioContext::new().map_err(|val| js_err(val, &"failed to establish audio context"))?;
+   /// Creates the pipeline, that analyzes audio of the `audio_context` as configured by `config`,
+   /// whose sample rate has to be the one of the context.
+   pub fn new(
+      audio_context: AudioContext,
+      config: FingerprintConfig,
+      display: Sender<DisplayMessage>,
+   ) -> AppResult<Self> {
+      config
+         .validate()
+         .map_err(|_| "invalid fingerprint configuration")?;
       let script_processor = audio_context
-         .create_script_processor_with_buffer_size(crate::STEP_SIZE as u32)
+         .create_script_processor_with_buffer_size_and_number_of_input_channels(
+            config.step_size as u32,
+            crate::INPUT_CHANNELS as u32,
+         )
          .map_err(|val| js_err(val, &"failed to set up processing nodes"))?;
 
       let sample_rate = audio_context.sample_rate() as u32 as usize;
@@ -37,16 +63,45 @@ impl Pipeline {
          sample_rate
       )));
 
+      let mixer = ChannelMixer::new(crate::INPUT_CHANNELS, crate::DOWNMIX)
+         .map_err(|_| "invalid channel configuration")?;
+      let channels = (0..mixer.outputs())
+         .map(|_| ChannelPipeline {
+            frequencer: Frequencer::new(sample_rate, config.block_size, config.step_size).unwrap(),
+            denoiser: Denoiser::new(config.block_size, crate::NOISE_LEARN_FRAMES),
+            feature_finder: FeatureFinder::new(config.block_size, config.t_span),
+         })
+         .collect();
+
       Ok(Self(Rc::new(RefCell::new(PipelineInner {
          audio_context,
+         config,
          script_processor,
          proc_pipeline: None,
-         frequencer: Frequencer::new(sample_rate, crate::BLOCK_SIZE, crate::STEP_SIZE).unwrap(),
-         feature_finder: FeatureFinder::new(crate::BLOCK_SIZE, crate::T_SPAN),
+         mixer,
+         channels,
+         denoise: false,
          display,
+         matcher: None,
       }))))
    }
 
+   /// Enables or disables the noise reduction.
+   /// The noise profile is learned in either case, such that it can be toggled at any time.
+   pub fn set_denoise(&self, denoise: bool) {
+      self.0.borrow_mut().denoise = denoise;
+   }
+
+   /// Returns the sample rate of the recorded audio.
+   pub fn sample_rate(&self) -> usize {
+      self.0.borrow().audio_context.sample_rate() as u32 as usize
+   }
+
+   /// Starts or stops identifying the recording.
+   pub fn set_matcher(&self, matcher: Option<Matcher>) {
+      self.0.borrow_mut().matcher = matcher;
+   }
+
    pub async fn start(&self) -> AppResult<()> {
       let mut pipeline = self.0.borrow_mut();
 
@@ -95,31 +150,55 @@ impl Pipeline {
 
    fn process_audio_event(&mut self, event: AudioProcessingEvent) {
       let mut pipeline = self.0.borrow_mut();
-      // Pull the audio out of the audio event
-      let audio = event
-         .input_buffer()
-         .unwrap()
-         .get_channel_data(0)
-         .unwrap()
-         .iter()
-         .map(|x| *x as f64)
-         .collect::<Vec<f64>>();
-      assert_eq!(audio.len(), crate::STEP_SIZE);
-
-      // Feed the data into the frequencer
-      let wavelet = pipeline.frequencer.feed_audio(&audio);
-      // Send the wavelet to the display
-      pipeline
-         .display
-         .send(DisplayMessage::Wavelet(wavelet.clone()))
-         .unwrap();
-
-      let features = pipeline.feature_finder.process(wavelet);
-
-      pipeline
-         .display
-         .send(DisplayMessage::Feature(features))
-         .unwrap();
+      let pipeline = &mut *pipeline;
+
+      // Pull the audio of every channel out of the audio event
+      let input_buffer = event.input_buffer().unwrap();
+      let planes = (0..pipeline.mixer.channels())
+         .map(|channel| input_buffer.get_channel_data(channel as u32).unwrap())
+         .collect::<Vec<_>>();
+      let planes = planes.iter().map(|plane| &plane[..]).collect::<Vec<_>>();
+      let streams = match pipeline.mixer.mix_planes(&planes) {
+         Ok(streams) => streams,
+         Err(()) => return,
+      };
+
+      for (index, (channel, audio)) in pipeline.channels.iter_mut().zip(streams).enumerate() {
+         assert_eq!(audio.len(), pipeline.config.step_size);
+
+         // Only the first stream is matched
+         if index == 0 {
+            if let Some(matcher) = pipeline.matcher.as_mut() {
+               matcher.feed(&audio);
+            }
+         }
+
+         // Feed the data into the frequencer
+         let mut wavelet = channel.frequencer.feed_audio(&audio);
+
+         // Learn the noise and remove it if requested
+         channel.denoiser.update(&wavelet);
+         if pipeline.denoise {
+            channel.denoiser.apply(&mut wavelet);
+         }
+
+         // Only the first stream is displayed
+         if index == 0 {
+            pipeline
+               .display
+               .send(DisplayMessage::Wavelet(wavelet.clone()))
+               .unwrap();
+         }
+
+         let features = channel.feature_finder.process(wavelet);
+
+         if index == 0 {
+            pipeline
+               .display
+               .send(DisplayMessage::Feature(features))
+               .unwrap();
+         }
+      }
 
       // TODO: Further processing of the data
    }
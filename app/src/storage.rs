@@ -0,0 +1,115 @@
+use algo::database::DatabaseSnapshot;
+use js_sys::{Promise, Uint8Array};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{File, IdbDatabase, IdbObjectStore, IdbRequest, IdbTransactionMode};
+
+use crate::{js_err, matcher, AppResult};
+
+/// Name of the IndexedDB database of the app.
+const DATABASE_NAME: &str = "audio-fp";
+const DATABASE_VERSION: u32 = 1;
+/// Name of the object store holding the serialized fingerprint database.
+const STORE_NAME: &str = "fingerprints";
+/// Key of the serialized fingerprint database in the store.
+const SNAPSHOT_KEY: &str = "snapshot";
+
+/// Loads a serialized fingerprint database and keeps it in IndexedDB,
+/// replacing the previous one, such that it is still there after a reload.
+/// Data, that isn't a valid database, isn't stored.
+pub async fn install(data: Vec<u8>) -> AppResult<DatabaseSnapshot> {
+   let snapshot = DatabaseSnapshot::decode(&data)?;
+
+   let store = open_store(IdbTransactionMode::Readwrite).await?;
+   let request = store
+      .put_with_key(
+         &Uint8Array::from(&data[..]),
+         &JsValue::from_str(SNAPSHOT_KEY),
+      )
+      .map_err(|val| js_err(val, &"failed to store the database"))?;
+   wait(&request).await?;
+   Ok(snapshot)
+}
+
+/// Loads the fingerprint database kept in IndexedDB, if there is one.
+pub async fn restore() -> AppResult<Option<DatabaseSnapshot>> {
+   let store = open_store(IdbTransactionMode::Readonly).await?;
+   let request = store
+      .get(&JsValue::from_str(SNAPSHOT_KEY))
+      .map_err(|val| js_err(val, &"failed to read the database"))?;
+   let value = wait(&request).await?;
+   if value.is_undefined() {
+      return Ok(None);
+   }
+
+   let data = Uint8Array::new(&value).to_vec();
+   Ok(Some(DatabaseSnapshot::decode(&data)?))
+}
+
+/// Reads a file chosen by the user.
+pub async fn read_file(file: &File) -> AppResult<Vec<u8>> {
+   let buffer = JsFuture::from(file.array_buffer())
+      .await
+      .map_err(|val| js_err(val, &"failed to read the file"))?;
+   Ok(Uint8Array::new(&buffer).to_vec())
+}
+
+/// Downloads a file.
+pub async fn download(url: &str) -> AppResult<Vec<u8>> {
+   let response = matcher::fetch(url, None).await?;
+   let buffer = response
+      .array_buffer()
+      .map_err(|val| js_err(val, &"failed to read the response"))?;
+   let buffer = JsFuture::from(buffer)
+      .await
+      .map_err(|val| js_err(val, &"failed to download the file"))?;
+   Ok(Uint8Array::new(&buffer).to_vec())
+}
+
+/// Opens the object store of the app, creating it on the first use.
+async fn open_store(mode: IdbTransactionMode) -> AppResult<IdbObjectStore> {
+   let factory = web_sys::window()
+      .unwrap()
+      .indexed_db()
+      .map_err(|val| js_err(val, &"failed to access IndexedDB"))?
+      .ok_or("IndexedDB is not available")?;
+   let request = factory
+      .open_with_u32(DATABASE_NAME, DATABASE_VERSION)
+      .map_err(|val| js_err(val, &"failed to open IndexedDB"))?;
+
+   // The store is created, when the database is created or its version changes
+   let upgrade_request = request.clone();
+   let on_upgrade = Closure::wrap(Box::new(move || {
+      if let Ok(database) = upgrade_request.result() {
+         let database = database.unchecked_into::<IdbDatabase>();
+         if !database.object_store_names().contains(STORE_NAME) {
+            let _ = database.create_object_store(STORE_NAME);
+         }
+      }
+   }) as Box<dyn FnMut()>);
+   request.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+
+   let database = wait(&request).await?.unchecked_into::<IdbDatabase>();
+   drop(on_upgrade);
+
+   let transaction = database
+      .transaction_with_str_and_mode(STORE_NAME, mode)
+      .map_err(|val| js_err(val, &"failed to start a transaction"))?;
+   Ok(transaction
+      .object_store(STORE_NAME)
+      .map_err(|val| js_err(val, &"failed to open the store"))?)
+}
+
+/// Waits for a request to finish and returns its result.
+async fn wait(request: &IdbRequest) -> AppResult<JsValue> {
+   let promise = Promise::new(&mut |resolve, reject| {
+      request.set_onsuccess(Some(&resolve));
+      request.set_onerror(Some(&reject));
+   });
+   JsFuture::from(promise)
+      .await
+      .map_err(|val| js_err(val, &"IndexedDB request failed"))?;
+   Ok(request
+      .result()
+      .map_err(|val| js_err(val, &"IndexedDB request failed"))?)
+}
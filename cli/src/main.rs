@@ -0,0 +1,571 @@
+mod wav;
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use algo::{
+    align::Aligner,
+    channel::Downmix,
+    config::FingerprintConfig,
+    database::Database,
+    dedup::{self, Deduplicator},
+    fingerprint::Fingerprinter,
+    hash::{PeakHash, PeakHasher},
+    index::Index,
+    metadata::{self, TrackMetadata},
+    monitor::{self, Monitor},
+    score::{self, Calibration},
+    transport::Fingerprint,
+    Algorithm,
+};
+
+/// Number of tracks scored when identifying a clip.
+const MAX_CANDIDATES: usize = 5;
+/// Number of triplet hashes, that need to line up to report a track played at another speed.
+const MIN_SCALED_SCORE: usize = 10;
+/// Number of query times, that need to line up to report a part of a clip, that matches a track.
+const MIN_SEGMENT_SCORE: usize = 20;
+
+const USAGE: &str = "usage:
+    cli align <reference.wav> <other.wav>    print the offset of other relative to reference
+    cli dedup <path>...                      print the clusters of duplicate wav files as JSON
+        [--min-similarity <fraction>]        fraction of the hashes, that need to line up (default 0.015),
+                                             0.3 only keeps re-uploads
+    cli index <database> <path>...           add the wav files to the database, creating it if needed
+        [--algorithm <name>]                 hashing with constellation (default), subfingerprint
+                                             or triplet, which matches clips played faster or slower
+    cli delete <database> <path>...          delete the wav files from the database
+    cli compact <database>                   merge the segments of the database, dropping deleted tracks
+    cli merge <database> <source>...         add the tracks of the source databases to the database
+    cli export <database> <file>             write the database into a single file, that the web app loads
+    cli fingerprint <clip.wav> [format]      print the fingerprint of the clip as base64, json or binary
+    cli identify <database> <clip>           print the tracks of the database, that match the clip,
+                                             given as wav file or fingerprint
+        [--all]                              print every part of the clip, that matches a track,
+                                             like the tracks of a mix
+    cli monitor <database> <stream.wav>      print when the tracks of the database play in a long recording
+        [--csv]                              as CSV instead of JSON
+
+options:
+    --downmix <name>                         how wav files with several channels are mixed down:
+                                             mid (default), left, right or side";
+
+type CliResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+fn main() {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    let downmix = match take_option(&mut args, "--downmix").and_then(parse_downmix) {
+        Ok(downmix) => downmix,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let result = match args.first().map(String::as_str) {
+        Some("align") => align(&args[1..], downmix),
+        Some("dedup") => dedup(&args[1..], downmix),
+        Some("index") => index(&args[1..], downmix),
+        Some("delete") => delete(&args[1..]),
+        Some("compact") => compact(&args[1..]),
+        Some("merge") => merge(&args[1..]),
+        Some("export") => export(&args[1..]),
+        Some("fingerprint") => fingerprint(&args[1..], downmix),
+        Some("identify") => identify(&args[1..], downmix),
+        Some("monitor") => monitor(&args[1..], downmix),
+        _ => Err(USAGE.into()),
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn align(args: &[String], downmix: Downmix) -> CliResult<()> {
+    let (reference, other) = match args {
+        [reference, other] => (reference, other),
+        _ => return Err(USAGE.into()),
+    };
+
+    let fingerprinter = fingerprinter()?;
+    let sample_rate = fingerprinter.sample_rate();
+    let aligner = Aligner::new(fingerprinter);
+    let alignment = aligner
+        .align(
+            &wav::read(reference, sample_rate, downmix)?,
+            &wav::read(other, sample_rate, downmix)?,
+        )
+        .ok_or("the recordings have nothing in common")?;
+
+    println!("offset: {:.4} s", alignment.offset);
+    println!("drift:  {:.1} ppm", alignment.drift * 1e6);
+    println!("score:  {}", alignment.score);
+    Ok(())
+}
+
+fn dedup(args: &[String], downmix: Downmix) -> CliResult<()> {
+    let mut args = args.to_vec();
+    let min_similarity = match take_option(&mut args, "--min-similarity")? {
+        Some(value) => match value.parse::<f64>() {
+            Ok(fraction) if (0.0..=1.0).contains(&fraction) => fraction,
+            _ => return Err(format!("invalid similarity {}, expected a fraction", value).into()),
+        },
+        None => dedup::MIN_SIMILARITY,
+    };
+    if args.is_empty() {
+        return Err(USAGE.into());
+    }
+
+    let mut files = Vec::new();
+    for arg in &args {
+        collect_wav_files(PathBuf::from(arg), &mut files)?;
+    }
+    files.sort();
+
+    let fingerprinter = fingerprinter()?;
+    let mut deduplicator = Deduplicator::new(fingerprinter.wavelets_per_second());
+    deduplicator.set_min_similarity(min_similarity);
+    for (track, file) in files.iter().enumerate() {
+        let audio = wav::read(file, fingerprinter.sample_rate(), downmix)?;
+        deduplicator.insert(track as u32, fingerprinter.fingerprint(&audio));
+    }
+
+    let clusters = deduplicator.clusters();
+    print!(
+        "{}",
+        dedup::to_json(&clusters, |track| files[track as usize]
+            .to_string_lossy()
+            .into_owned())
+    );
+    Ok(())
+}
+
+fn index(args: &[String], downmix: Downmix) -> CliResult<()> {
+    let mut args = args.to_vec();
+    let algorithm = match take_option(&mut args, "--algorithm")? {
+        Some(name) => Some(
+            Algorithm::from_name(&name).ok_or_else(|| format!("unknown algorithm {}", name))?,
+        ),
+        None => None,
+    };
+    let (path, inputs) = match &args[..] {
+        [path, inputs @ ..] if !inputs.is_empty() => (Path::new(path), inputs),
+        _ => return Err(USAGE.into()),
+    };
+
+    let mut database = if path.exists() {
+        Database::open(path)?
+    } else {
+        Database::create_with_algorithm(path, &fingerprinter()?, algorithm.unwrap_or_default())?
+    };
+    if algorithm.is_some_and(|algorithm| algorithm != database.algorithm()) {
+        return Err(format!(
+            "{}: holds {} hashes",
+            path.display(),
+            database.algorithm().name()
+        )
+        .into());
+    }
+
+    let mut files = Vec::new();
+    for input in inputs {
+        collect_wav_files(PathBuf::from(input), &mut files)?;
+    }
+    files.sort();
+
+    // Tracks are keyed by their path, skip those already in the database
+    let fingerprinter = database.fingerprinter().clone();
+    let mut tracks = Vec::new();
+    for file in files {
+        let key = file.to_string_lossy().into_owned();
+        if database.track_id(&key).is_none() {
+            let audio = wav::read(&file, fingerprinter.sample_rate(), downmix)?;
+            let metadata = TrackMetadata {
+                title: file
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned()),
+                duration: Some(audio.len() as f64 / fingerprinter.sample_rate() as f64),
+                source_path: Some(key.clone()),
+                checksum: Some(metadata::checksum(&fs::read(&file)?)),
+                ..TrackMetadata::default()
+            };
+            let hashes = match database.algorithm() {
+                Algorithm::Constellation => fingerprinter.fingerprint(&audio),
+                Algorithm::SubFingerprint => sub_fingerprint_hashes(
+                    &fingerprinter
+                        .sub_fingerprints(&audio)
+                        .map_err(|_| "invalid sub-fingerprint parameters")?,
+                ),
+                Algorithm::Triplet => fingerprinter
+                    .fingerprint_triplets(&audio)
+                    .map_err(|_| "the fan out is too small for triplets")?,
+            };
+            tracks.push((key, hashes, metadata));
+        }
+    }
+
+    let tracks = tracks
+        .iter()
+        .map(|(key, hashes, metadata)| (key.as_str(), &hashes[..], metadata))
+        .collect::<Vec<_>>();
+    database.append(&tracks)?;
+    println!(
+        "added {} tracks, {} tracks in {} segments",
+        tracks.len(),
+        database.num_tracks(),
+        database.num_segments()
+    );
+    Ok(())
+}
+
+fn delete(args: &[String]) -> CliResult<()> {
+    let (path, keys) = match args {
+        [path, keys @ ..] if !keys.is_empty() => (path, keys),
+        _ => return Err(USAGE.into()),
+    };
+
+    let mut database = Database::open(path)?;
+    for key in keys {
+        let id = database
+            .track_id(key)
+            .ok_or_else(|| format!("{}: not in the database", key))?;
+        database.delete(id)?;
+    }
+    Ok(())
+}
+
+fn compact(args: &[String]) -> CliResult<()> {
+    let path = match args {
+        [path] => path,
+        _ => return Err(USAGE.into()),
+    };
+
+    let mut database = Database::open(path)?;
+    database.compact()?;
+    println!("{} tracks", database.num_tracks());
+    Ok(())
+}
+
+fn merge(args: &[String]) -> CliResult<()> {
+    let (path, sources) = match args {
+        [path, sources @ ..] if !sources.is_empty() => (Path::new(path), sources),
+        _ => return Err(USAGE.into()),
+    };
+
+    let sources = sources
+        .iter()
+        .map(Database::open)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut database = if path.exists() {
+        Database::open(path)?
+    } else {
+        Database::create_with_algorithm(path, sources[0].fingerprinter(), sources[0].algorithm())?
+    };
+
+    database.merge(&sources.iter().collect::<Vec<_>>())?;
+    println!(
+        "{} tracks in {} segments",
+        database.num_tracks(),
+        database.num_segments()
+    );
+    Ok(())
+}
+
+fn export(args: &[String]) -> CliResult<()> {
+    let (path, file) = match args {
+        [path, file] => (path, file),
+        _ => return Err(USAGE.into()),
+    };
+
+    let database = Database::open(path)?;
+    let data = database.export()?;
+    fs::write(file, &data)?;
+    println!("{} tracks in {} bytes", database.num_tracks(), data.len());
+    Ok(())
+}
+
+fn fingerprint(args: &[String], downmix: Downmix) -> CliResult<()> {
+    let (clip, format) = match args {
+        [clip] => (clip, "base64"),
+        [clip, format] => (clip, format.as_str()),
+        _ => return Err(USAGE.into()),
+    };
+
+    let fingerprinter = fingerprinter()?;
+    let hashes = fingerprinter.fingerprint(&wav::read(clip, fingerprinter.sample_rate(), downmix)?);
+    let fingerprint = Fingerprint::new(&fingerprinter, hashes);
+    match format {
+        "base64" => println!("{}", fingerprint.to_base64()),
+        "json" => println!("{}", fingerprint.to_json()),
+        "binary" => io::stdout().write_all(&fingerprint.to_bytes())?,
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+fn identify(args: &[String], downmix: Downmix) -> CliResult<()> {
+    let mut args = args.to_vec();
+    let all = take_flag(&mut args, "--all");
+    let (path, clip) = match &args[..] {
+        [path, clip] => (path, clip),
+        _ => return Err(USAGE.into()),
+    };
+
+    let database = Database::open(path)?;
+    match database.algorithm() {
+        Algorithm::Constellation => {}
+        Algorithm::SubFingerprint => return identify_sub_fingerprints(&database, clip, downmix),
+        Algorithm::Triplet => return identify_triplets(&database, clip, downmix),
+    }
+
+    let index = database.load_index()?;
+    let fingerprinter = database.fingerprinter();
+    let hashes = if is_wav_file(Path::new(clip)) {
+        fingerprinter.fingerprint(&wav::read(clip, fingerprinter.sample_rate(), downmix)?)
+    } else {
+        let fingerprint = Fingerprint::decode(&fs::read(clip)?)
+            .map_err(|_| format!("{}: not a wav file or fingerprint", clip))?;
+        if &fingerprint.fingerprinter != fingerprinter {
+            return Err(format!("{}: computed with different parameters", clip).into());
+        }
+        fingerprint.hashes
+    };
+    if all {
+        return identify_segments(&database, &index, &hashes);
+    }
+
+    let calibration = Calibration::default();
+    let candidates = index.candidates(&hashes, MAX_CANDIDATES);
+    for m in score::score(&index, &hashes, &candidates) {
+        if !calibration.accept(&m) {
+            continue;
+        }
+        let found = match database.resolve(m.track, m) {
+            Some(found) => found,
+            None => continue,
+        };
+
+        print_track(found.key, found.metadata);
+        println!(
+            "    offset: {:.2} s",
+            found.found.offset as f64 / fingerprinter.wavelets_per_second()
+        );
+        println!("    probability: {:.3}", calibration.probability(&found.found));
+    }
+    Ok(())
+}
+
+/// Prints every part of the query, that matches a track, in the order they start.
+fn identify_segments(database: &Database, index: &Index, hashes: &[PeakHash]) -> CliResult<()> {
+    let seconds = |wavelets: f64| wavelets / database.fingerprinter().wavelets_per_second();
+    for segment in index.query_all(hashes, MIN_SEGMENT_SCORE) {
+        let found = match database.resolve(segment.track, segment) {
+            Some(found) => found,
+            None => continue,
+        };
+
+        print_track(found.key, found.metadata);
+        println!(
+            "    query: {:.2} s - {:.2} s",
+            seconds(found.found.start as f64),
+            seconds(found.found.end as f64)
+        );
+        println!("    offset: {:.2} s", seconds(found.found.offset as f64));
+        println!("    score: {}", found.found.score);
+    }
+    Ok(())
+}
+
+fn monitor(args: &[String], downmix: Downmix) -> CliResult<()> {
+    let mut args = args.to_vec();
+    let csv = take_flag(&mut args, "--csv");
+    let (path, stream) = match &args[..] {
+        [path, stream] => (path, stream),
+        _ => return Err(USAGE.into()),
+    };
+
+    let database = Database::open(path)?;
+    if database.algorithm() != Algorithm::Constellation {
+        return Err(format!("{}: only constellation databases can be monitored", path).into());
+    }
+    let index = database.load_index()?;
+    let config = database.fingerprinter().config();
+    let hasher = PeakHasher::new(config.fan_out, config.min_dt, config.max_dt)
+        .map_err(|_| "invalid fingerprint parameters")?;
+    let mut monitor = Monitor::new(
+        config.sample_rate,
+        config.block_size,
+        config.step_size,
+        config.t_span,
+        hasher,
+    )
+    .map_err(|_| "invalid fingerprint parameters")?;
+
+    let audio = wav::read(stream, config.sample_rate, downmix)?;
+    let mut detections = monitor.feed_audio(&index, &audio);
+    detections.extend(monitor.finish(&index));
+
+    let key = |track: u32| {
+        database
+            .resolve(track, ())
+            .map_or_else(|| track.to_string(), |found| found.key.to_string())
+    };
+    if csv {
+        print!("{}", monitor::to_csv(&detections, key));
+    } else {
+        print!("{}", monitor::to_json(&detections, key));
+    }
+    Ok(())
+}
+
+/// Identifies a wav file in a database of sub-fingerprints, see [`Algorithm::SubFingerprint`].
+fn identify_sub_fingerprints(database: &Database, clip: &str, downmix: Downmix) -> CliResult<()> {
+    if !is_wav_file(Path::new(clip)) {
+        return Err(format!("{}: sub-fingerprint databases only identify wav files", clip).into());
+    }
+
+    let index = database.load_sub_fingerprint_index()?;
+    let fingerprinter = database.fingerprinter();
+    let sub_fingerprints = fingerprinter
+        .sub_fingerprints(&wav::read(clip, fingerprinter.sample_rate(), downmix)?)
+        .map_err(|_| "invalid sub-fingerprint parameters")?;
+
+    if let Some(found) = index
+        .query(&sub_fingerprints)
+        .and_then(|m| database.resolve(m.track, m))
+    {
+        print_track(found.key, found.metadata);
+        println!(
+            "    offset: {:.2} s",
+            found.found.offset as f64 / fingerprinter.wavelets_per_second()
+        );
+        println!("    bit error rate: {:.3}", found.found.bit_error_rate);
+    }
+    Ok(())
+}
+
+/// Identifies a wav file, that may be played at another speed, in a database of triplet hashes,
+/// see [`Algorithm::Triplet`].
+fn identify_triplets(database: &Database, clip: &str, downmix: Downmix) -> CliResult<()> {
+    if !is_wav_file(Path::new(clip)) {
+        return Err(format!("{}: triplet databases only identify wav files", clip).into());
+    }
+
+    let index = database.load_index()?;
+    let fingerprinter = database.fingerprinter();
+    let hashes = fingerprinter
+        .fingerprint_triplets(&wav::read(clip, fingerprinter.sample_rate(), downmix)?)
+        .map_err(|_| "the fan out is too small for triplets")?;
+
+    for m in index.query_scaled(&hashes, MAX_CANDIDATES) {
+        if m.score < MIN_SCALED_SCORE {
+            continue;
+        }
+        let found = match database.resolve(m.track, m) {
+            Some(found) => found,
+            None => continue,
+        };
+
+        print_track(found.key, found.metadata);
+        println!(
+            "    offset: {:.2} s",
+            found.found.offset / fingerprinter.wavelets_per_second()
+        );
+        println!("    speed: {:.3}", found.found.speed);
+        println!("    score: {}", found.found.score);
+    }
+    Ok(())
+}
+
+/// Prints the key of a track and its metadata.
+fn print_track(key: &str, metadata: &TrackMetadata) {
+    println!("{}", key);
+    let fields = [
+        ("title", &metadata.title),
+        ("artist", &metadata.artist),
+        ("album", &metadata.album),
+        ("isrc", &metadata.isrc),
+    ];
+    for (name, value) in fields.iter() {
+        if let Some(value) = value {
+            println!("    {}: {}", name, value);
+        }
+    }
+    for (name, value) in metadata.tags.iter() {
+        println!("    {}: {}", name, value);
+    }
+}
+
+/// Stores sub-fingerprints as hashes, whose time is their position, like databases expect them.
+fn sub_fingerprint_hashes(sub_fingerprints: &[u32]) -> Vec<PeakHash> {
+    sub_fingerprints
+        .iter()
+        .enumerate()
+        .map(|(time, sub_fingerprint)| PeakHash {
+            hash: *sub_fingerprint,
+            time: time as u32,
+            span: 0,
+        })
+        .collect()
+}
+
+/// Removes an option and its value from the arguments and returns the value, if it was given.
+fn take_option(args: &mut Vec<String>, name: &str) -> CliResult<Option<String>> {
+    let position = match args.iter().position(|arg| arg == name) {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+    if position + 1 == args.len() {
+        return Err(format!("{} needs a value", name).into());
+    }
+    let value = args.remove(position + 1);
+    args.remove(position);
+    Ok(Some(value))
+}
+
+/// Parses the value of `--downmix`, which has to mix down to a single stream.
+fn parse_downmix(name: Option<String>) -> CliResult<Downmix> {
+    match name {
+        None => Ok(Downmix::Mid),
+        Some(name) => match Downmix::from_name(&name) {
+            Some(downmix) if downmix != Downmix::PerChannel => Ok(downmix),
+            _ => Err(format!("unknown downmix {}, expected mid, left, right or side", name).into()),
+        },
+    }
+}
+
+/// Removes a flag from the arguments and returns whether it was given.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let position = args.iter().position(|arg| arg == name);
+    if let Some(position) = position {
+        args.remove(position);
+    }
+    position.is_some()
+}
+
+/// Adds the path, if it is a wav file, or all wav files below it, if it is a directory.
+fn collect_wav_files(path: PathBuf, files: &mut Vec<PathBuf>) -> CliResult<()> {
+    if path.is_dir() {
+        for entry in fs::read_dir(&path)? {
+            collect_wav_files(entry?.path(), files)?;
+        }
+    } else if is_wav_file(&path) {
+        files.push(path);
+    }
+    Ok(())
+}
+
+fn is_wav_file(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("wav"))
+}
+
+/// Returns the fingerprinter of new databases and of comparisons, configured for music.
+fn fingerprinter() -> CliResult<Fingerprinter> {
+    Ok(Fingerprinter::with_config(FingerprintConfig::music())
+        .map_err(|_| "invalid fingerprint parameters")?)
+}
@@ -0,0 +1,53 @@
+use algo::{
+   channel::{ChannelMixer, Downmix, Layout},
+   resample::Resampler,
+};
+use hound::{SampleFormat, WavReader};
+use std::path::Path;
+
+use crate::CliResult;
+
+/// Reads a wav file, mixes it down to mono with `downmix` and resamples it to `sample_rate`.
+/// The downmix has to output a single stream.
+pub fn read<P: AsRef<Path>>(path: P, sample_rate: usize, downmix: Downmix) -> CliResult<Vec<f64>> {
+   let path = path.as_ref();
+   let mut reader = WavReader::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+   let spec = reader.spec();
+
+   // Convert the samples to floats in [-1, 1]
+   let samples = match spec.sample_format {
+      SampleFormat::Float => reader
+         .samples::<f32>()
+         .map(|sample| sample.map(|sample| sample as f64))
+         .collect::<Result<Vec<_>, _>>()?,
+      SampleFormat::Int => {
+         let scale = (1u64 << (spec.bits_per_sample - 1)) as f64;
+         reader
+            .samples::<i32>()
+            .map(|sample| sample.map(|sample| sample as f64 / scale))
+            .collect::<Result<Vec<_>, _>>()?
+      }
+   };
+
+   let mixer = ChannelMixer::new(spec.channels as usize, downmix).map_err(|_| {
+      format!(
+         "{}: {} channels can't be mixed down with {}",
+         path.display(),
+         spec.channels,
+         downmix.name()
+      )
+   })?;
+   let audio = mixer
+      .mix_buffer(&samples, Layout::Interleaved)
+      .map_err(|_| format!("{}: incomplete frame", path.display()))?
+      .remove(0);
+
+   if spec.sample_rate as usize == sample_rate {
+      return Ok(audio);
+   }
+   let mut resampler = Resampler::new(spec.sample_rate as usize, sample_rate)
+      .map_err(|_| format!("{}: unsupported sample rate", path.display()))?;
+   let mut resampled = resampler.process(&audio);
+   resampled.extend(resampler.flush());
+   Ok(resampled)
+}
@@ -0,0 +1,85 @@
+use algo::{
+   channel::{ChannelMixer, Downmix, Layout},
+   resample::Resampler,
+};
+use hound::{SampleFormat, WavReader};
+use std::io::Cursor;
+
+/// Decodes a wav file, mixes it down to mono with `downmix` and resamples it to `sample_rate`.
+pub fn decode_wav(data: &[u8], downmix: Downmix, sample_rate: usize) -> Result<Vec<f64>, String> {
+   let mut reader = WavReader::new(Cursor::new(data)).map_err(|err| err.to_string())?;
+   let spec = reader.spec();
+
+   // Convert the samples to floats in [-1, 1]
+   let samples = match spec.sample_format {
+      SampleFormat::Float => reader
+         .samples::<f32>()
+         .map(|sample| sample.map(|sample| sample as f64))
+         .collect::<Result<Vec<_>, _>>(),
+      SampleFormat::Int => {
+         let scale = (1u64 << (spec.bits_per_sample - 1)) as f64;
+         reader
+            .samples::<i32>()
+            .map(|sample| sample.map(|sample| sample as f64 / scale))
+            .collect::<Result<Vec<_>, _>>()
+      }
+   }
+   .map_err(|err| err.to_string())?;
+
+   mix_and_resample(
+      &samples,
+      spec.channels as usize,
+      downmix,
+      spec.sample_rate as usize,
+      sample_rate,
+   )
+}
+
+/// Decodes interleaved 16 bit little endian PCM, mixes it down to mono with `downmix` and
+/// resamples it to `sample_rate`.
+pub fn decode_pcm(
+   data: &[u8],
+   channels: usize,
+   downmix: Downmix,
+   source_rate: usize,
+   sample_rate: usize,
+) -> Result<Vec<f64>, String> {
+   // Every frame holds a 16 bit sample per channel
+   if channels > 0 && !data.len().is_multiple_of(2 * channels) {
+      return Err(String::from("incomplete frame"));
+   }
+   let samples = data
+      .chunks_exact(2)
+      .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f64 / 32768.0)
+      .collect::<Vec<_>>();
+   mix_and_resample(&samples, channels, downmix, source_rate, sample_rate)
+}
+
+fn mix_and_resample(
+   samples: &[f64],
+   channels: usize,
+   downmix: Downmix,
+   source_rate: usize,
+   sample_rate: usize,
+) -> Result<Vec<f64>, String> {
+   let mixer = ChannelMixer::new(channels, downmix).map_err(|_| {
+      format!(
+         "{} channels can't be mixed down with {}",
+         channels,
+         downmix.name()
+      )
+   })?;
+   let audio = mixer
+      .mix_buffer(samples, Layout::Interleaved)
+      .map_err(|_| String::from("incomplete frame"))?
+      .remove(0);
+
+   if source_rate == sample_rate {
+      return Ok(audio);
+   }
+   let mut resampler = Resampler::new(source_rate, sample_rate)
+      .map_err(|_| String::from("unsupported sample rate"))?;
+   let mut resampled = resampler.process(&audio);
+   resampled.extend(resampler.flush());
+   Ok(resampled)
+}
@@ -0,0 +1,217 @@
+use std::{
+   collections::BTreeMap,
+   io::{self, BufRead, BufReader, Read, Write},
+   net::TcpStream,
+};
+
+/// Largest accepted request body, in bytes.
+const MAX_BODY_SIZE: usize = 64 << 20;
+/// Largest accepted request line or header, in bytes.
+const MAX_LINE_LENGTH: usize = 8 << 10;
+/// Headers allowing cross origin requests from browsers.
+const CORS_HEADERS: &str = "Access-Control-Allow-Origin: *\r\n\
+   Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
+   Access-Control-Allow-Headers: Content-Type\r\n";
+
+/// A parsed HTTP request.
+pub struct Request {
+   pub method: String,
+   pub path: String,
+   /// The parameters of the query string, without percent decoding.
+   pub query: BTreeMap<String, String>,
+   pub body: Vec<u8>,
+}
+
+/// A response with a JSON body.
+pub struct Response {
+   pub status: u16,
+   pub body: String,
+}
+
+impl Response {
+   pub fn ok(body: String) -> Self {
+      Self { status: 200, body }
+   }
+
+   /// Creates a response with a JSON object holding the error message.
+   pub fn error(status: u16, message: &str) -> Self {
+      Self {
+         status,
+         body: format!("{{\"error\": {}}}\n", escape(message)),
+      }
+   }
+}
+
+/// Reads a request from the stream. Only requests with a `Content-Length` may have a body.
+pub fn read_request(stream: &TcpStream) -> io::Result<Request> {
+   let mut reader = BufReader::new(stream);
+
+   let request_line = read_line(&mut reader)?;
+   let mut words = request_line.split(' ');
+   let (method, target) = match (words.next(), words.next()) {
+      (Some(method), Some(target)) => (String::from(method), target),
+      _ => return Err(invalid("invalid request line")),
+   };
+   let (path, query) = match target.find('?') {
+      Some(i) => (&target[..i], &target[i + 1..]),
+      None => (target, ""),
+   };
+   let query = query
+      .split('&')
+      .filter(|pair| !pair.is_empty())
+      .map(|pair| match pair.find('=') {
+         Some(i) => (String::from(&pair[..i]), String::from(&pair[i + 1..])),
+         None => (String::from(pair), String::new()),
+      })
+      .collect();
+
+   let mut content_length = 0;
+   loop {
+      let line = read_line(&mut reader)?;
+      if line.is_empty() {
+         break;
+      }
+      if let Some(i) = line.find(':') {
+         if line[..i].eq_ignore_ascii_case("content-length") {
+            content_length = line[i + 1..]
+               .trim()
+               .parse()
+               .map_err(|_| invalid("invalid content length"))?;
+         }
+      }
+   }
+   if content_length > MAX_BODY_SIZE {
+      return Err(invalid("request body too large"));
+   }
+
+   let mut body = vec![0; content_length];
+   reader.read_exact(&mut body)?;
+
+   Ok(Request {
+      method,
+      path: String::from(path),
+      query,
+      body,
+   })
+}
+
+/// Writes the response and closes the connection.
+/// Every origin may call the server, such that web apps can send their hashes.
+pub fn write_response(mut stream: &TcpStream, response: &Response) -> io::Result<()> {
+   let reason = match response.status {
+      200 => "OK",
+      400 => "Bad Request",
+      404 => "Not Found",
+      405 => "Method Not Allowed",
+      _ => "Internal Server Error",
+   };
+   write!(
+      stream,
+      "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+      response.status,
+      reason,
+      response.body.len(),
+      CORS_HEADERS,
+      response.body
+   )?;
+   stream.flush()
+}
+
+/// Quotes a string for JSON.
+pub fn escape(value: &str) -> String {
+   let mut escaped = String::from("\"");
+   for c in value.chars() {
+      match c {
+         '"' => escaped.push_str("\\\""),
+         '\\' => escaped.push_str("\\\\"),
+         '\n' => escaped.push_str("\\n"),
+         c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+         c => escaped.push(c),
+      }
+   }
+   escaped.push('"');
+   escaped
+}
+
+/// Reads a line without its line break.
+fn read_line(reader: &mut BufReader<&TcpStream>) -> io::Result<String> {
+   let mut line = Vec::new();
+   reader
+      .by_ref()
+      .take(MAX_LINE_LENGTH as u64)
+      .read_until(b'\n', &mut line)?;
+   if line.last() != Some(&b'\n') {
+      return Err(invalid("line too long or connection closed"));
+   }
+
+   let line = String::from_utf8(line).map_err(|_| invalid("invalid header"))?;
+   Ok(String::from(line.trim_end_matches(&['\r', '\n'][..])))
+}
+
+fn invalid(message: &str) -> io::Error {
+   io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::{net::TcpListener, thread};
+
+   /// Sends the raw bytes to a new connection and reads a request from it.
+   fn read(raw: &[u8]) -> io::Result<Request> {
+      let raw = raw.to_vec();
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      let address = listener.local_addr().unwrap();
+      let client = thread::spawn(move || {
+         let mut stream = TcpStream::connect(address).unwrap();
+         stream.write_all(&raw).unwrap();
+      });
+      let (stream, _) = listener.accept().unwrap();
+      client.join().unwrap();
+      read_request(&stream)
+   }
+
+   #[test]
+   fn reads_requests() {
+      let request =
+         read(b"POST /identify/pcm?sample_rate=8000&all&x=1=2 HTTP/1.1\r\nHost: a\r\ncontent-LENGTH: 3\r\n\r\nabcdef")
+            .unwrap();
+      assert_eq!(request.method, "POST");
+      assert_eq!(request.path, "/identify/pcm");
+      assert_eq!(request.query.len(), 3);
+      assert_eq!(request.query["sample_rate"], "8000");
+      assert_eq!(request.query["all"], "");
+      assert_eq!(request.query["x"], "1=2");
+      assert_eq!(request.body, b"abc");
+
+      let request = read(b"GET /health HTTP/1.1\n\n").unwrap();
+      assert_eq!(
+         (request.method.as_str(), request.path.as_str()),
+         ("GET", "/health")
+      );
+      assert!(request.query.is_empty() && request.body.is_empty());
+   }
+
+   #[test]
+   fn rejects_malformed_requests() {
+      assert!(read(b"GET\r\n\r\n").is_err());
+      assert!(read(b"GET /health HTTP/1.1\r\n").is_err());
+      assert!(read(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").is_err());
+      assert!(read(b"POST / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n").is_err());
+      assert!(read(b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nab").is_err());
+      assert!(read(b"GET / HTTP/1.1\r\nX: \xff\r\n\r\n").is_err());
+
+      assert!(read(&[b'a'; MAX_LINE_LENGTH + 1]).is_err());
+   }
+
+   #[test]
+   fn escapes_json_strings() {
+      assert_eq!(escape("plain"), "\"plain\"");
+      assert_eq!(escape("a \"b\" \\ c"), "\"a \\\"b\\\" \\\\ c\"");
+      assert_eq!(
+         escape("line\nbreak\ttab\u{1}"),
+         "\"line\\nbreak\\u0009tab\\u0001\""
+      );
+      assert_eq!(escape("ünïcode"), "\"ünïcode\"");
+   }
+}
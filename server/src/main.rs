@@ -0,0 +1,818 @@
+mod audio;
+mod http;
+
+use std::{
+    env,
+    net::{TcpListener, TcpStream},
+    ops::RangeInclusive,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+use algo::{
+    channel::Downmix,
+    config::FingerprintConfig,
+    database::Database,
+    fingerprint::Fingerprinter,
+    hash::PeakHash,
+    metadata::TrackMetadata,
+    score::{self, Calibration},
+    sharded::ShardedIndex,
+    transport::Fingerprint,
+    Algorithm,
+};
+
+use http::{escape, Request, Response};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+/// Number of tracks scored for every query.
+const MAX_CANDIDATES: usize = 5;
+/// Number of triplet hashes, that need to line up to report a track played at another speed.
+const MIN_SCALED_SCORE: usize = 10;
+/// Number of query times, that need to line up to report a part of a query, that matches a track.
+const MIN_SEGMENT_SCORE: usize = 20;
+/// Sample rates of PCM bodies, that are resampled.
+const PCM_SAMPLE_RATES: RangeInclusive<usize> = 8000..=192000;
+/// Largest number of channels of PCM bodies.
+const MAX_PCM_CHANNELS: usize = 8;
+
+const USAGE: &str = "usage: server <database> [address] [--shards <n>]
+       server --mock [address]
+
+the mock server needs no database and answers every query with a fingerprint with the same track,
+for testing clients. databases of triplet hashes also find clips played faster or slower,
+but only identify audio. --shards splits the index into n shards, usually one per core,
+that answer every query in parallel. the identify endpoints take ?all=1 to report every part of the query,
+that matches a track, like the tracks of a mix, with its start and end in the query.
+pcm bodies may have sample rates from 8000 to 192000 hz and up to 8 channels.
+the audio endpoints take ?downmix=<name> to mix several channels down with mid (default),
+left, right or side.
+
+endpoints:
+    GET  /health                                    report that the server is running
+    GET  /stats                                     report the size of the database and the number of queries
+    POST /identify                                  identify the wav file in the body
+    POST /identify/pcm?sample_rate=<hz>&channels=<n>  identify 16 bit little endian PCM in the body
+    POST /identify/fingerprint                      identify the fingerprint in the body, in binary,
+                                                    base64 or json form, see `cli fingerprint`
+
+example:
+    curl --data-binary @clip.wav http://127.0.0.1:8080/identify";
+
+/// Serves the identification of a database.
+struct Server {
+    database: Database,
+    index: ShardedIndex,
+    calibration: Calibration,
+    queries: AtomicUsize,
+    started: Instant,
+}
+
+/// Answers like a server with a single track, without a database.
+struct Mock {
+    fingerprinter: Fingerprinter,
+    queries: AtomicUsize,
+    started: Instant,
+}
+
+fn main() {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    let num_shards = match args.iter().position(|arg| arg == "--shards") {
+        Some(position) if position + 1 < args.len() => {
+            let value = args.remove(position + 1);
+            args.remove(position);
+            value
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .unwrap_or_else(|| exit(&format!("invalid number of shards {}", value)))
+        }
+        Some(_) => exit(USAGE),
+        None => 1,
+    };
+
+    match &args[..] {
+        [flag] if flag == "--mock" => serve_mock(DEFAULT_ADDRESS),
+        [flag, address] if flag == "--mock" => serve_mock(address),
+        [path] => serve_database(path, DEFAULT_ADDRESS, num_shards),
+        [path, address] => serve_database(path, address, num_shards),
+        _ => exit(USAGE),
+    }
+}
+
+fn serve_database(path: &str, address: &str, num_shards: usize) {
+    let database = Database::open(path).unwrap_or_else(|err| exit(&format!("{}: {}", path, err)));
+    if database.algorithm() == Algorithm::SubFingerprint {
+        exit(&format!("{}: sub-fingerprint databases can't be served", path));
+    }
+    let index = database
+        .load_sharded_index(num_shards)
+        .unwrap_or_else(|err| exit(&format!("{}: {}", path, err)));
+    let description = format!(
+        "{} tracks in {} shards",
+        database.num_tracks(),
+        index.num_shards()
+    );
+    let server = Server {
+        database,
+        index,
+        calibration: Calibration::default(),
+        queries: AtomicUsize::new(0),
+        started: Instant::now(),
+    };
+    serve(address, &description, server, route);
+}
+
+fn serve_mock(address: &str) {
+    // The defaults of the command line tool
+    let mock = Mock {
+        fingerprinter: Fingerprinter::with_config(FingerprintConfig::default()).unwrap(),
+        queries: AtomicUsize::new(0),
+        started: Instant::now(),
+    };
+    serve(address, "a mock track", mock, route_mock);
+}
+
+/// Answers the requests on the address with `route`.
+fn serve<S: Send + Sync + 'static>(
+    address: &str,
+    description: &str,
+    state: S,
+    route: fn(&S, &Request) -> Response,
+) {
+    let listener = TcpListener::bind(address).unwrap_or_else(|err| exit(&err.to_string()));
+    eprintln!("serving {} on http://{}", description, address);
+    accept(listener, Arc::new(state), route);
+}
+
+/// Answers the connections of the listener with `route`, every connection on its own thread.
+fn accept<S: Send + Sync + 'static>(
+    listener: TcpListener,
+    state: Arc<S>,
+    route: fn(&S, &Request) -> Response,
+) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let state = state.clone();
+                thread::spawn(move || handle_connection(&*state, stream, route));
+            }
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn handle_connection<S>(state: &S, stream: TcpStream, route: fn(&S, &Request) -> Response) {
+    let response = match http::read_request(&stream) {
+        // Browsers ask before sending hashes from another origin
+        Ok(request) if request.method == "OPTIONS" => Response::ok(String::new()),
+        Ok(request) => route(state, &request),
+        Err(err) => Response::error(400, &err.to_string()),
+    };
+    if let Err(err) = http::write_response(&stream, &response) {
+        eprintln!("{}", err);
+    }
+}
+
+fn route(server: &Server, request: &Request) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/health") => Response::ok(String::from("{\"status\": \"ok\"}\n")),
+        ("GET", "/stats") => stats(server),
+        ("POST", "/identify") => {
+            let all = match match_all(server, request) {
+                Ok(all) => all,
+                Err(response) => return response,
+            };
+            let downmix = match downmix(request) {
+                Ok(downmix) => downmix,
+                Err(response) => return response,
+            };
+            let sample_rate = server.database.fingerprinter().sample_rate();
+            match audio::decode_wav(&request.body, downmix, sample_rate) {
+                Ok(audio) => identify_audio(server, &audio, all),
+                Err(err) => Response::error(400, &err),
+            }
+        }
+        ("POST", "/identify/pcm") => {
+            let all = match match_all(server, request) {
+                Ok(all) => all,
+                Err(response) => return response,
+            };
+            let parameter = |name: &str| {
+                request
+                    .query
+                    .get(name)
+                    .and_then(|value| value.parse::<usize>().ok())
+            };
+            let (source_rate, channels) = match (parameter("sample_rate"), parameter("channels")) {
+                (Some(source_rate), Some(channels)) => (source_rate, channels),
+                _ => return Response::error(400, "missing sample_rate or channels"),
+            };
+            if !PCM_SAMPLE_RATES.contains(&source_rate) {
+                return Response::error(400, "sample_rate out of range");
+            }
+            if channels == 0 || channels > MAX_PCM_CHANNELS {
+                return Response::error(400, "channels out of range");
+            }
+            let downmix = match downmix(request) {
+                Ok(downmix) => downmix,
+                Err(response) => return response,
+            };
+
+            let sample_rate = server.database.fingerprinter().sample_rate();
+            match audio::decode_pcm(&request.body, channels, downmix, source_rate, sample_rate) {
+                Ok(audio) => identify_audio(server, &audio, all),
+                Err(err) => Response::error(400, &err),
+            }
+        }
+        ("POST", "/identify/fingerprint") => {
+            if server.database.algorithm() == Algorithm::Triplet {
+                return Response::error(400, "the database holds triplet hashes, send audio");
+            }
+            let all = match match_all(server, request) {
+                Ok(all) => all,
+                Err(response) => return response,
+            };
+            match parse_fingerprint(&request.body, server.database.fingerprinter()) {
+                Ok(hashes) if all => identify_segments(server, &hashes),
+                Ok(hashes) => identify(server, &hashes),
+                Err(response) => response,
+            }
+        }
+        (_, path) => unknown_route(path),
+    }
+}
+
+/// Returns whether the query asks for every part, that matches a track,
+/// which databases of triplet hashes can't answer.
+fn match_all(server: &Server, request: &Request) -> Result<bool, Response> {
+    let all = matches!(request.query.get("all").map(String::as_str), Some("1") | Some("true"));
+    if all && server.database.algorithm() == Algorithm::Triplet {
+        return Err(Response::error(
+            400,
+            "the database holds triplet hashes, that can't match all parts",
+        ));
+    }
+    Ok(all)
+}
+
+/// Returns the downmix of the query, which has to mix down to a single stream.
+fn downmix(request: &Request) -> Result<Downmix, Response> {
+    match request.query.get("downmix") {
+        None => Ok(Downmix::Mid),
+        Some(name) => match Downmix::from_name(name) {
+            Some(downmix) if downmix != Downmix::PerChannel => Ok(downmix),
+            _ => Err(Response::error(400, "unknown downmix, expected mid, left, right or side")),
+        },
+    }
+}
+
+/// Answers fingerprints, but no audio, with a match of the mock track.
+fn route_mock(mock: &Mock, request: &Request) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/health") => Response::ok(String::from("{\"status\": \"ok\"}\n")),
+        ("GET", "/stats") => Response::ok(format!(
+            "{{\"tracks\": 1, \"segments\": 0, \"hashes\": 0, \"memory_bytes\": 0, \"queries\": {}, \"uptime_seconds\": {}, \
+             \"parameters\": {}}}\n",
+            mock.queries.load(Ordering::Relaxed),
+            mock.started.elapsed().as_secs(),
+            parameters(&mock.fingerprinter)
+        )),
+        ("POST", "/identify/fingerprint") => {
+            let hashes = match parse_fingerprint(&request.body, &mock.fingerprinter) {
+                Ok(hashes) => hashes,
+                Err(response) => return response,
+            };
+            mock.queries.fetch_add(1, Ordering::Relaxed);
+            if hashes.is_empty() {
+                return Response::ok(String::from("{\"matches\": [\n]}\n"));
+            }
+
+            let metadata = TrackMetadata {
+                title: Some(String::from("Mock Track")),
+                artist: Some(String::from("Mock Artist")),
+                ..TrackMetadata::default()
+            };
+            let found = format_match(0, "mock", &metadata, 0.0, 1.0, hashes.len(), Some(1.0));
+            Response::ok(format!("{{\"matches\": [{}\n]}}\n", found))
+        }
+        ("POST", "/identify") | ("POST", "/identify/pcm") => {
+            Response::error(400, "the mock server only identifies fingerprints")
+        }
+        (_, path) => unknown_route(path),
+    }
+}
+
+fn unknown_route(path: &str) -> Response {
+    match path {
+        "/health" | "/stats" | "/identify" | "/identify/pcm" | "/identify/fingerprint" => {
+            Response::error(405, "method not allowed")
+        }
+        _ => Response::error(404, "not found"),
+    }
+}
+
+/// Decodes a fingerprint in any form and returns its hashes,
+/// if they were computed with the parameters of the fingerprinter.
+fn parse_fingerprint(
+    body: &[u8],
+    fingerprinter: &Fingerprinter,
+) -> Result<Vec<PeakHash>, Response> {
+    let fingerprint = Fingerprint::decode(body).map_err(|_| {
+        Response::error(400, "the body is not a fingerprint of a supported version")
+    })?;
+    if &fingerprint.fingerprinter != fingerprinter {
+        return Err(Response::error(
+            400,
+            "the fingerprint was computed with different parameters, see /stats",
+        ));
+    }
+    Ok(fingerprint.hashes)
+}
+
+fn stats(server: &Server) -> Response {
+    Response::ok(format!(
+        "{{\"tracks\": {}, \"segments\": {}, \"hashes\": {}, \"memory_bytes\": {}, \"queries\": {}, \"uptime_seconds\": {}, \
+         \"parameters\": {}}}\n",
+        server.database.num_tracks(),
+        server.database.num_segments(),
+        server.index.num_hashes(),
+        server.index.memory_usage(),
+        server.queries.load(Ordering::Relaxed),
+        server.started.elapsed().as_secs(),
+        parameters(server.database.fingerprinter())
+    ))
+}
+
+/// Returns the parameters, that clients need to compute matching hashes, as a JSON object.
+/// The id is a hex string,
+/// because JavaScript numbers can't hold 64 bits.
+fn parameters(fingerprinter: &Fingerprinter) -> String {
+    let config = fingerprinter.config();
+    format!(
+        "{{\"id\": \"{:016x}\", \"sample_rate\": {}, \"block_size\": {}, \"step_size\": {}, \
+         \"t_span\": {}, \"fan_out\": {}, \"min_dt\": {}, \"max_dt\": {}}}",
+        config.id(),
+        config.sample_rate,
+        config.block_size,
+        config.step_size,
+        config.t_span,
+        config.fan_out,
+        config.min_dt,
+        config.max_dt
+    )
+}
+
+fn identify_audio(server: &Server, audio: &[f64], all: bool) -> Response {
+    let fingerprinter = server.database.fingerprinter();
+    if server.database.algorithm() == Algorithm::Triplet {
+        return match fingerprinter.fingerprint_triplets(audio) {
+            Ok(hashes) => identify_scaled(server, &hashes),
+            Err(()) => Response::error(500, "the fan out is too small for triplets"),
+        };
+    }
+    if all {
+        return identify_segments(server, &fingerprinter.fingerprint(audio));
+    }
+    identify(server, &fingerprinter.fingerprint(audio))
+}
+
+/// Returns the accepted matches of the hashes with the metadata of their tracks.
+fn identify(server: &Server, hashes: &[PeakHash]) -> Response {
+    server.queries.fetch_add(1, Ordering::Relaxed);
+    let wavelets_per_second = server.database.fingerprinter().wavelets_per_second();
+
+    let candidates = server.index.candidates(hashes, MAX_CANDIDATES);
+    let matches = score::score(&server.index, hashes, &candidates)
+        .into_iter()
+        .filter(|m| server.calibration.accept(m))
+        .filter_map(|m| server.database.resolve(m.track, m))
+        .map(|found| {
+            format_match(
+                found.found.track,
+                found.key,
+                found.metadata,
+                found.found.offset as f64 / wavelets_per_second,
+                1.0,
+                found.found.score,
+                Some(server.calibration.probability(&found.found)),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    Response::ok(format!("{{\"matches\": [{}\n]}}\n", matches.join(",")))
+}
+
+/// Returns the matches of triplet hashes, that may be played at another speed.
+/// The calibration only applies to constellation hashes, so there is no probability.
+fn identify_scaled(server: &Server, hashes: &[PeakHash]) -> Response {
+    server.queries.fetch_add(1, Ordering::Relaxed);
+    let wavelets_per_second = server.database.fingerprinter().wavelets_per_second();
+
+    let matches = server
+        .index
+        .query_scaled(hashes, MAX_CANDIDATES)
+        .into_iter()
+        .filter(|m| m.score >= MIN_SCALED_SCORE)
+        .filter_map(|m| server.database.resolve(m.track, m))
+        .map(|found| {
+            format_match(
+                found.found.track,
+                found.key,
+                found.metadata,
+                found.found.offset / wavelets_per_second,
+                found.found.speed,
+                found.found.score,
+                None,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    Response::ok(format!("{{\"matches\": [{}\n]}}\n", matches.join(",")))
+}
+
+/// Returns every part of the query, that matches a track, in the order they start.
+/// The segments aren't calibrated, so there is no probability.
+fn identify_segments(server: &Server, hashes: &[PeakHash]) -> Response {
+    server.queries.fetch_add(1, Ordering::Relaxed);
+    let seconds = |wavelets: f64| wavelets / server.database.fingerprinter().wavelets_per_second();
+
+    let segments = server
+        .index
+        .query_all(hashes, MIN_SEGMENT_SCORE)
+        .into_iter()
+        .filter_map(|segment| server.database.resolve(segment.track, segment))
+        .map(|found| {
+            format!(
+                "\n    {{{}, \"start\": {:.3}, \"end\": {:.3}, \"offset\": {:.3}, \"score\": {}}}",
+                track_fields(found.found.track, found.key, found.metadata),
+                seconds(found.found.start as f64),
+                seconds(found.found.end as f64),
+                seconds(found.found.offset as f64),
+                found.found.score
+            )
+        })
+        .collect::<Vec<_>>();
+
+    Response::ok(format!("{{\"matches\": [{}\n]}}\n", segments.join(",")))
+}
+
+/// Formats a match as an element of the `matches` array. The offset is given in seconds,
+/// the speed relative to the track.
+fn format_match(
+    track: u32,
+    key: &str,
+    metadata: &TrackMetadata,
+    offset: f64,
+    speed: f64,
+    score: usize,
+    probability: Option<f64>,
+) -> String {
+    format!(
+        "\n    {{{}, \"offset\": {:.3}, \"speed\": {:.3}, \"score\": {}, \"probability\": {}}}",
+        track_fields(track, key, metadata),
+        offset,
+        speed,
+        score,
+        probability.map_or(String::from("null"), |probability| format!("{:.3}", probability))
+    )
+}
+
+/// Formats the track and its metadata as the first fields of a JSON object.
+fn track_fields(track: u32, key: &str, metadata: &TrackMetadata) -> String {
+    let text = |value: &Option<String>| value.as_deref().map_or(String::from("null"), escape);
+    format!(
+        "\"track\": {}, \"key\": {}, \"title\": {}, \"artist\": {}, \"album\": {}, \"isrc\": {}, \
+         \"duration\": {}",
+        track,
+        escape(key),
+        text(&metadata.title),
+        text(&metadata.artist),
+        text(&metadata.album),
+        text(&metadata.isrc),
+        metadata
+            .duration
+            .map_or(String::from("null"), |duration| format!("{:.3}", duration))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        f64::consts::PI,
+        fs,
+        io::{Read, Write},
+        net::SocketAddr,
+        path::Path,
+    };
+
+    /// Seconds of the track in the test databases.
+    const TRACK_SECONDS: usize = 30;
+
+    /// Returns a reproducible melody of quarter second notes between 200 Hz and 2 kHz.
+    fn melody(sample_rate: usize, len: usize) -> Vec<f64> {
+        let mut state = 1u64;
+        let note_len = sample_rate / 4;
+        (0..len)
+            .map(|i| {
+                if i.is_multiple_of(note_len) {
+                    state = state
+                        .wrapping_mul(6_364_136_223_846_793_005)
+                        .wrapping_add(1_442_695_040_888_963_407);
+                }
+                let frequency = 200.0 * 10f64.powf((state >> 40) as f64 / (1u64 << 24) as f64);
+                let t = (i % note_len) as f64;
+                let envelope = (PI * t / note_len as f64).sin();
+                envelope * (2.0 * PI * frequency * t / sample_rate as f64).sin()
+            })
+            .collect()
+    }
+
+    fn fingerprinter() -> Fingerprinter {
+        Fingerprinter::with_config(FingerprintConfig::music()).unwrap()
+    }
+
+    /// Stores the melody in a new database of the algorithm and serves it on a free port.
+    fn start(name: &str, algorithm: Algorithm) -> SocketAddr {
+        let path = std::env::temp_dir().join(format!("server-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let server = create_server(&path, algorithm);
+        let _ = fs::remove_dir_all(&path);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || accept(listener, Arc::new(server), route));
+        address
+    }
+
+    fn create_server(path: &Path, algorithm: Algorithm) -> Server {
+        let fingerprinter = fingerprinter();
+        let audio = melody(
+            fingerprinter.sample_rate(),
+            TRACK_SECONDS * fingerprinter.sample_rate(),
+        );
+        let hashes = match algorithm {
+            Algorithm::Triplet => fingerprinter.fingerprint_triplets(&audio).unwrap(),
+            _ => fingerprinter.fingerprint(&audio),
+        };
+        let metadata = TrackMetadata {
+            title: Some(String::from("Melody \"1\"")),
+            ..TrackMetadata::default()
+        };
+
+        let mut database =
+            Database::create_with_algorithm(path, &fingerprinter, algorithm).unwrap();
+        database.append(&[("melody", &hashes, &metadata)]).unwrap();
+        let database = Database::open(path).unwrap();
+        let index = database.load_sharded_index(2).unwrap();
+        Server {
+            database,
+            index,
+            calibration: Calibration::default(),
+            queries: AtomicUsize::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Sends the raw request and returns the status and the body of the response.
+    fn send(address: SocketAddr, request: &[u8]) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        (status, String::from(body))
+    }
+
+    fn get(address: SocketAddr, target: &str) -> (u16, String) {
+        send(
+            address,
+            format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes(),
+        )
+    }
+
+    fn post(address: SocketAddr, target: &str, body: &[u8]) -> (u16, String) {
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            target,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        send(address, &request)
+    }
+
+    /// Returns ten seconds of the melody, starting on a step after five seconds.
+    fn clip() -> Vec<f64> {
+        let fingerprinter = fingerprinter();
+        let sample_rate = fingerprinter.sample_rate();
+        let start = 5 * sample_rate / fingerprinter.step_size() * fingerprinter.step_size();
+        melody(sample_rate, TRACK_SECONDS * sample_rate)[start..start + 10 * sample_rate].to_vec()
+    }
+
+    fn pcm(audio: &[f64], channels: usize) -> Vec<u8> {
+        audio
+            .iter()
+            .flat_map(|sample| {
+                let bytes = ((sample * 16384.0) as i16).to_le_bytes();
+                (0..channels).flat_map(move |_| bytes.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_health_and_stats() {
+        let address = start("stats", Algorithm::Constellation);
+        assert_eq!(
+            get(address, "/health"),
+            (200, String::from("{\"status\": \"ok\"}\n"))
+        );
+
+        let (status, body) = get(address, "/stats");
+        assert_eq!(status, 200);
+        assert!(
+            body.starts_with("{\"tracks\": 1, \"segments\": 1, "),
+            "{}",
+            body
+        );
+        let id = format!("\"id\": \"{:016x}\"", FingerprintConfig::music().id());
+        assert!(body.contains(&id), "{}", body);
+    }
+
+    #[test]
+    fn identifies_fingerprints_and_pcm() {
+        let address = start("identify", Algorithm::Constellation);
+        let fingerprinter = fingerprinter();
+        let clip = clip();
+
+        let fingerprint = Fingerprint::new(&fingerprinter, fingerprinter.fingerprint(&clip));
+        for body in [fingerprint.to_bytes(), fingerprint.to_base64().into_bytes()] {
+            let (status, found) = post(address, "/identify/fingerprint", &body);
+            assert_eq!(status, 200);
+            assert!(
+                found.contains("\"key\": \"melody\", \"title\": \"Melody \\\"1\\\"\""),
+                "{}",
+                found
+            );
+            assert!(found.contains("\"offset\": 4.9"), "{}", found);
+        }
+
+        let (status, found) = post(
+            address,
+            "/identify/fingerprint?all=1",
+            &fingerprint.to_bytes(),
+        );
+        assert_eq!(status, 200);
+        assert!(found.contains("\"key\": \"melody\""), "{}", found);
+
+        let target = format!(
+            "/identify/pcm?sample_rate={}&channels=2",
+            fingerprinter.sample_rate()
+        );
+        let (status, found) = post(address, &target, &pcm(&clip, 2));
+        assert_eq!(status, 200);
+        assert!(found.contains("\"key\": \"melody\""), "{}", found);
+
+        let (status, found) = post(address, "/identify/fingerprint", &[]);
+        assert_eq!(status, 400, "{}", found);
+    }
+
+    #[test]
+    fn mixes_down_as_requested() {
+        let address = start("downmix", Algorithm::Constellation);
+        let target = format!(
+            "/identify/pcm?sample_rate={}&channels=2",
+            fingerprinter().sample_rate()
+        );
+
+        // The clip plays on the left channel only
+        let body = pcm(&clip(), 1)
+            .chunks_exact(2)
+            .flat_map(|sample| vec![sample[0], sample[1], 0, 0])
+            .collect::<Vec<_>>();
+        for (downmix, found) in [
+            ("mid", true),
+            ("left", true),
+            ("side", true),
+            ("right", false),
+        ] {
+            let (status, matches) =
+                post(address, &format!("{}&downmix={}", target, downmix), &body);
+            assert_eq!(status, 200);
+            assert_eq!(
+                matches.contains("\"key\": \"melody\""),
+                found,
+                "{}: {}",
+                downmix,
+                matches
+            );
+        }
+
+        assert_eq!(
+            post(address, &format!("{}&downmix=per-channel", target), &body).0,
+            400
+        );
+        assert_eq!(
+            post(address, &format!("{}&downmix=both", target), &body).0,
+            400
+        );
+        let mono = format!(
+            "/identify/pcm?sample_rate={}&channels=1",
+            fingerprinter().sample_rate()
+        );
+        assert_eq!(
+            post(address, &format!("{}&downmix=side", mono), &pcm(&clip(), 1)).0,
+            400
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let address = start("malformed", Algorithm::Constellation);
+        let rate = fingerprinter().sample_rate();
+
+        assert_eq!(send(address, b"GARBAGE\r\n\r\n").0, 400);
+        assert_eq!(
+            send(
+                address,
+                b"POST /identify HTTP/1.1\r\nContent-Length: x\r\n\r\n"
+            )
+            .0,
+            400
+        );
+        assert_eq!(
+            send(
+                address,
+                b"POST /identify HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n"
+            )
+            .0,
+            400
+        );
+        assert_eq!(get(address, "/missing").0, 404);
+        assert_eq!(get(address, "/identify").0, 405);
+        assert_eq!(post(address, "/stats", b"").0, 405);
+
+        assert_eq!(post(address, "/identify", b"not a wav file").0, 400);
+        assert_eq!(
+            post(address, "/identify/fingerprint", b"not a fingerprint").0,
+            400
+        );
+        let pcm_status =
+            |query: String, body: &[u8]| post(address, &format!("/identify/pcm{}", query), body).0;
+        assert_eq!(pcm_status(String::new(), &[0; 4]), 400);
+        assert_eq!(
+            pcm_status(format!("?sample_rate={}&channels=2", rate), &[0; 3]),
+            400
+        );
+        assert_eq!(
+            pcm_status(format!("?sample_rate={}&channels=0", rate), &[0; 4]),
+            400
+        );
+        assert_eq!(
+            pcm_status(format!("?sample_rate={}&channels=1000", rate), &[0; 4]),
+            400
+        );
+        assert_eq!(
+            pcm_status(String::from("?sample_rate=100&channels=1"), &[0; 4]),
+            400
+        );
+        assert_eq!(
+            pcm_status(String::from("?sample_rate=1000000&channels=1"), &[0; 4]),
+            400
+        );
+
+        // The server keeps answering
+        assert_eq!(get(address, "/health").0, 200);
+    }
+
+    #[test]
+    fn rejects_all_parts_only_when_identifying_triplets() {
+        let address = start("triplets", Algorithm::Triplet);
+        let rate = fingerprinter().sample_rate();
+
+        assert_eq!(get(address, "/health?all=1").0, 200);
+        assert_eq!(get(address, "/stats?all=1").0, 200);
+        let target = format!("/identify/pcm?sample_rate={}&channels=1", rate);
+        assert_eq!(
+            post(address, &format!("{}&all=1", target), &pcm(&clip(), 1)).0,
+            400
+        );
+
+        let (status, found) = post(address, &target, &pcm(&clip(), 1));
+        assert_eq!(status, 200);
+        assert!(found.contains("\"key\": \"melody\""), "{}", found);
+    }
+}